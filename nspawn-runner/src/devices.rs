@@ -0,0 +1,331 @@
+//! Resolution of the device nodes passed through to job containers.
+//!
+//! Devices are either configured by a fixed device node, or selected by their
+//! USB identity (vendor and product ID, serial number or port path). USB
+//! devices are looked up in sysfs when a job starts, and expand into all of
+//! their device nodes, such as `/dev/bus/usb/<bus>/<device>` and the nodes of
+//! their interfaces (e.g., `/dev/ttyACM0` or `/dev/hidraw0`).
+
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{DeviceConfigAddMount, NspawnRunnerEnvironmentDeviceConfig};
+
+const SYSFS_USB_DEVICES: &str = "/sys/bus/usb/devices";
+
+/// Nesting depth up to which to search a USB device's sysfs directory for
+/// device nodes of its interfaces.
+const MAX_SYSFS_DEPTH: usize = 6;
+
+/// Criteria selecting USB devices. All given criteria must match.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UsbDeviceMatch {
+    /// Vendor ID as four hexadecimal digits, e.g. "0483".
+    #[serde(default)]
+    vendor_id: Option<String>,
+    /// Product ID as four hexadecimal digits, e.g. "374b".
+    #[serde(default)]
+    product_id: Option<String>,
+    #[serde(default)]
+    serial: Option<String>,
+    /// Physical port path, as named in sysfs (e.g., "1-2.3").
+    #[serde(default)]
+    port_path: Option<String>,
+}
+
+impl std::fmt::Display for UsbDeviceMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let criteria: Vec<String> = [
+            ("vendor_id", &self.vendor_id),
+            ("product_id", &self.product_id),
+            ("serial", &self.serial),
+            ("port_path", &self.port_path),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.as_ref().map(|value| format!("{}={}", name, value)))
+        .collect();
+        write!(f, "{}", criteria.join(", "))
+    }
+}
+
+/// A device node to pass through to a job's container.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceNode {
+    pub node: PathBuf,
+    /// Access to grant through `DeviceAllow=`, a combination of "r", "w" and
+    /// "m".
+    pub access: String,
+    pub add_mount: DeviceConfigAddMount,
+}
+
+fn read_attribute(device_dir: &Path, attribute: &str) -> Option<String> {
+    std::fs::read_to_string(device_dir.join(attribute))
+        .ok()
+        .map(|value| value.trim().to_string())
+}
+
+impl UsbDeviceMatch {
+    fn is_empty(&self) -> bool {
+        self.vendor_id.is_none()
+            && self.product_id.is_none()
+            && self.serial.is_none()
+            && self.port_path.is_none()
+    }
+
+    /// Whether the USB device at the given port path and sysfs directory
+    /// matches all criteria.
+    pub fn matches(&self, port_path: &str, device_dir: &Path) -> bool {
+        let matches_attribute = |expected: &Option<String>, attribute: &str| match expected {
+            Some(expected) => read_attribute(device_dir, attribute)
+                .is_some_and(|value| value.eq_ignore_ascii_case(expected)),
+            None => true,
+        };
+
+        self.port_path
+            .as_ref()
+            .is_none_or(|expected| expected == port_path)
+            && matches_attribute(&self.vendor_id, "idVendor")
+            && matches_attribute(&self.product_id, "idProduct")
+            && matches_attribute(&self.serial, "serial")
+    }
+}
+
+/// Whether a sysfs entry names a USB device (e.g., "1-2.3"), rather than a
+/// root hub ("usb1") or an interface ("1-2.3:1.0").
+pub fn is_usb_device_name(name: &str) -> bool {
+    !name.contains(':') && name.starts_with(|c: char| c.is_ascii_digit()) && name.contains('-')
+}
+
+/// Collect the device nodes announced in the `uevent` files of a USB device
+/// and its interfaces, without descending into devices attached to it.
+fn collect_device_nodes(dir: &Path, depth: usize, nodes: &mut Vec<PathBuf>) {
+    if let Ok(uevent) = std::fs::read_to_string(dir.join("uevent")) {
+        if let Some(devname) = uevent
+            .lines()
+            .find_map(|line| line.strip_prefix("DEVNAME="))
+        {
+            nodes.push(Path::new("/dev").join(devname));
+        }
+    }
+
+    if depth == MAX_SYSFS_DEPTH {
+        return;
+    }
+    let Ok(dir_entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for dir_entry in dir_entries.flatten() {
+        // sysfs is full of symlinks to other devices, which are not followed:
+        let is_dir = dir_entry
+            .file_type()
+            .map(|file_type| file_type.is_dir())
+            .unwrap_or(false);
+        let name = dir_entry.file_name();
+        if is_dir && !is_usb_device_name(&name.to_string_lossy()) {
+            collect_device_nodes(&dir_entry.path(), depth + 1, nodes);
+        }
+    }
+}
+
+/// Device nodes of all USB devices matching the criteria.
+fn find_usb_device_nodes(usb_match: &UsbDeviceMatch) -> Result<Vec<PathBuf>, String> {
+    let dir_entries = std::fs::read_dir(SYSFS_USB_DEVICES)
+        .map_err(|e| format!("Failed to read {:?}: {:?}", SYSFS_USB_DEVICES, e))?;
+
+    let mut nodes = vec![];
+    let mut matched = false;
+    for dir_entry in dir_entries.flatten() {
+        let port_path = dir_entry.file_name().to_string_lossy().to_string();
+        if !is_usb_device_name(&port_path) {
+            continue;
+        }
+
+        // Entries are symlinks into the device hierarchy:
+        let Ok(device_dir) = std::fs::canonicalize(dir_entry.path()) else {
+            continue;
+        };
+        if usb_match.matches(&port_path, &device_dir) {
+            matched = true;
+            collect_device_nodes(&device_dir, 0, &mut nodes);
+        }
+    }
+
+    if !matched {
+        return Err(format!("USB device ({}) is not present", usb_match));
+    }
+    Ok(nodes)
+}
+
+impl NspawnRunnerEnvironmentDeviceConfig {
+    fn access(&self) -> String {
+        format!(
+            "{}{}{}",
+            if self.read { "r" } else { "" },
+            if self.write { "w" } else { "" },
+            if self.create { "m" } else { "" },
+        )
+    }
+
+    /// Pass through a device node selected by this configuration.
+    pub fn device_node(&self, node: PathBuf) -> DeviceNode {
+        DeviceNode {
+            node,
+            access: self.access(),
+            add_mount: self.add_mount.clone(),
+        }
+    }
+
+    /// Device nodes selected by this configuration.
+    fn resolve(&self) -> Result<Vec<DeviceNode>, String> {
+        let nodes = match (&self.device_node, &self.usb) {
+            (Some(device_node), None) => {
+                let mut device_node = device_node.clone();
+                if self.resolve_symlink {
+                    match std::fs::canonicalize(&device_node) {
+                        Ok(canon) => device_node = canon,
+                        Err(e) => warn!(
+                            "Failed to get canonical path to device node {:?}: {:?}",
+                            device_node, e
+                        ),
+                    }
+                }
+                vec![device_node]
+            }
+            (None, Some(usb_match)) if !usb_match.is_empty() => find_usb_device_nodes(usb_match)?,
+            (None, Some(_)) => return Err("USB device without any criteria".to_string()),
+            _ => {
+                return Err(
+                    "Devices must specify exactly one of `device_node` and `usb`".to_string(),
+                )
+            }
+        };
+
+        Ok(nodes
+            .into_iter()
+            .map(|node| self.device_node(node))
+            .collect())
+    }
+}
+
+/// Resolve the device nodes of an environment's devices, failing if any of
+/// them are absent.
+pub async fn resolve_device_nodes(
+    device_cfgs: &[NspawnRunnerEnvironmentDeviceConfig],
+) -> Result<Vec<DeviceNode>, String> {
+    let device_cfgs = device_cfgs.to_vec();
+    tokio::task::spawn_blocking(move || {
+        let mut nodes: Vec<DeviceNode> = vec![];
+        for device_cfg in device_cfgs.iter() {
+            if !device_cfg.read && !device_cfg.write && !device_cfg.create {
+                // Don't add devices with no permissions:
+                continue;
+            }
+            for node in device_cfg.resolve()? {
+                if !nodes.iter().any(|existing| existing.node == node.node) {
+                    nodes.push(node);
+                }
+            }
+        }
+        Ok(nodes)
+    })
+    .await
+    .map_err(|e| format!("Failed to resolve device nodes: {:?}", e))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fake sysfs directory of a USB device with the given attributes, and
+    /// an interface with a device node.
+    fn fake_usb_device(name: &str, attributes: &[(&str, &str)]) -> PathBuf {
+        let device_dir = std::env::temp_dir().join(format!(
+            "treadmill-usb-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&device_dir);
+        let interface_dir = device_dir.join(format!("{}:1.0", name)).join("tty/ttyACM0");
+        std::fs::create_dir_all(&interface_dir).unwrap();
+        std::fs::write(device_dir.join("uevent"), "DEVNAME=bus/usb/001/004\n").unwrap();
+        std::fs::write(interface_dir.join("uevent"), "DEVNAME=ttyACM0\n").unwrap();
+        for (attribute, value) in attributes {
+            std::fs::write(device_dir.join(attribute), format!("{}\n", value)).unwrap();
+        }
+        device_dir
+    }
+
+    #[test]
+    fn usb_device_names() {
+        assert!(is_usb_device_name("1-2"));
+        assert!(is_usb_device_name("1-2.3.4"));
+        assert!(!is_usb_device_name("usb1"));
+        assert!(!is_usb_device_name("1-2.3:1.0"));
+        assert!(!is_usb_device_name("1"));
+    }
+
+    #[test]
+    fn matches_usb_identity() {
+        let device_dir = fake_usb_device(
+            "1-2.3",
+            &[
+                ("idVendor", "0483"),
+                ("idProduct", "374B"),
+                ("serial", "0672FF"),
+            ],
+        );
+
+        let usb_match = |vendor_id: Option<&str>,
+                         product_id: Option<&str>,
+                         serial: Option<&str>,
+                         port_path: Option<&str>| UsbDeviceMatch {
+            vendor_id: vendor_id.map(str::to_string),
+            product_id: product_id.map(str::to_string),
+            serial: serial.map(str::to_string),
+            port_path: port_path.map(str::to_string),
+        };
+
+        // Empty criteria match any device:
+        assert!(UsbDeviceMatch::default().matches("1-2.3", &device_dir));
+
+        // IDs are compared case-insensitively:
+        assert!(usb_match(Some("0483"), Some("374b"), None, None).matches("1-2.3", &device_dir));
+        assert!(usb_match(None, None, Some("0672ff"), Some("1-2.3")).matches("1-2.3", &device_dir));
+
+        // All given criteria must match:
+        assert!(!usb_match(Some("0483"), Some("374e"), None, None).matches("1-2.3", &device_dir));
+        assert!(!usb_match(Some("0483"), None, None, Some("1-2.4")).matches("1-2.3", &device_dir));
+
+        // Missing attributes don't match:
+        let bare_dir = fake_usb_device("1-3", &[]);
+        assert!(!usb_match(None, None, Some("0672FF"), None).matches("1-3", &bare_dir));
+        assert!(usb_match(None, None, None, Some("1-3")).matches("1-3", &bare_dir));
+
+        std::fs::remove_dir_all(&device_dir).unwrap();
+        std::fs::remove_dir_all(&bare_dir).unwrap();
+    }
+
+    #[test]
+    fn collects_interface_nodes() {
+        let device_dir = fake_usb_device("2-1", &[]);
+        // Devices attached to a hub are not part of the hub:
+        let child_dir = device_dir.join("2-1.1");
+        std::fs::create_dir(&child_dir).unwrap();
+        std::fs::write(child_dir.join("uevent"), "DEVNAME=bus/usb/001/005\n").unwrap();
+
+        let mut nodes = vec![];
+        collect_device_nodes(&device_dir, 0, &mut nodes);
+        nodes.sort();
+        assert_eq!(
+            nodes,
+            vec![
+                PathBuf::from("/dev/bus/usb/001/004"),
+                PathBuf::from("/dev/ttyACM0"),
+            ]
+        );
+
+        std::fs::remove_dir_all(&device_dir).unwrap();
+    }
+}
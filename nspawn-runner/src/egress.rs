@@ -0,0 +1,352 @@
+//! Network egress policies of job containers.
+//!
+//! An environment with runner-managed host networking may restrict the
+//! destinations its containers can reach. The policy is an ordered list of
+//! rules matching destination networks (or DNS names, which are resolved when
+//! the job starts), protocols and ports, followed by a default action. It is
+//! installed as nftables rules on the host side of the container's veth pair,
+//! in the job's table. Packets dropped by the policy are counted and reported
+//! as part of the job's resource usage.
+//!
+//! The policy applies to traffic routed by, or destined to, the host. It is
+//! therefore only supported for point-to-point networking, as traffic of a
+//! container attached to a bridge could bypass it.
+
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use uuid::Uuid;
+
+use crate::network::{nft_table_name, HostNetworkAllocation};
+
+/// Name of the nftables counter of packets denied by the policy.
+const DENIED_COUNTER: &str = "egress_denied";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EgressAction {
+    #[default]
+    Allow,
+    Deny,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EgressProtocol {
+    Tcp,
+    Udp,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NspawnRunnerEgressRuleConfig {
+    action: EgressAction,
+    /// Network in CIDR notation (e.g., "10.0.0.0/8"), address, or DNS name
+    /// resolved when the job starts.
+    destination: String,
+    #[serde(default)]
+    protocol: Option<EgressProtocol>,
+    /// Destination ports. Requires a `protocol`.
+    #[serde(default)]
+    ports: Vec<u16>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NspawnRunnerEnvironmentEgressConfig {
+    /// Rules, of which the first matching one applies.
+    #[serde(default)]
+    rule: Vec<NspawnRunnerEgressRuleConfig>,
+    /// Action for traffic not matching any rule.
+    #[serde(default)]
+    default_action: EgressAction,
+}
+
+/// Parse a network in CIDR notation, or a single address.
+fn parse_network(destination: &str) -> Option<(IpAddr, Option<u8>)> {
+    match destination.split_once('/') {
+        Some((address, prefix_length)) => {
+            let address: IpAddr = address.parse().ok()?;
+            let prefix_length: u8 = prefix_length.parse().ok()?;
+            let max_prefix_length = if address.is_ipv4() { 32 } else { 128 };
+            (prefix_length <= max_prefix_length).then_some((address, Some(prefix_length)))
+        }
+        None => destination.parse().ok().map(|address| (address, None)),
+    }
+}
+
+/// Resolve a rule's destination into networks.
+async fn resolve_destination(destination: &str) -> Result<Vec<(IpAddr, Option<u8>)>, String> {
+    if let Some(network) = parse_network(destination) {
+        return Ok(vec![network]);
+    }
+
+    let mut addresses: Vec<(IpAddr, Option<u8>)> = tokio::net::lookup_host((destination, 0))
+        .await
+        .map_err(|e| format!("Failed to resolve {:?}: {:?}", destination, e))?
+        .map(|socket_addr| (socket_addr.ip(), None))
+        .collect();
+    addresses.sort();
+    addresses.dedup();
+    if addresses.is_empty() {
+        return Err(format!("{:?} did not resolve to any address", destination));
+    }
+    Ok(addresses)
+}
+
+fn verdict(action: EgressAction) -> String {
+    match action {
+        EgressAction::Allow => "accept".to_string(),
+        EgressAction::Deny => format!("counter name \"{}\" drop", DENIED_COUNTER),
+    }
+}
+
+impl NspawnRunnerEnvironmentEgressConfig {
+    /// nftables ruleset enforcing the policy on the container's traffic,
+    /// both routed through and destined to the host.
+    pub async fn ruleset(
+        &self,
+        job_id: Uuid,
+        allocation: &HostNetworkAllocation,
+    ) -> Result<String, String> {
+        // Replies of connections initiated towards the container are always
+        // permitted:
+        let mut rules = vec![
+            "ct state established,related accept".to_string(),
+            // The container's IPv6 connectivity relies on neighbor and router
+            // discovery with the host, regardless of the policy:
+            "icmpv6 type { nd-neighbor-solicit, nd-neighbor-advert, nd-router-solicit } accept"
+                .to_string(),
+        ];
+
+        for rule_cfg in self.rule.iter() {
+            if !rule_cfg.ports.is_empty() && rule_cfg.protocol.is_none() {
+                return Err(format!(
+                    "Egress rule for {:?} specifies ports without a protocol",
+                    rule_cfg.destination
+                ));
+            }
+            let ports = match rule_cfg.protocol {
+                Some(protocol) => {
+                    let protocol = match protocol {
+                        EgressProtocol::Tcp => "tcp",
+                        EgressProtocol::Udp => "udp",
+                    };
+                    if rule_cfg.ports.is_empty() {
+                        format!(" meta l4proto {}", protocol)
+                    } else {
+                        let ports: Vec<String> =
+                            rule_cfg.ports.iter().map(|port| port.to_string()).collect();
+                        format!(" {} dport {{ {} }}", protocol, ports.join(", "))
+                    }
+                }
+                None => String::new(),
+            };
+
+            for (address, prefix_length) in resolve_destination(&rule_cfg.destination).await? {
+                let family = if address.is_ipv4() { "ip" } else { "ip6" };
+                let network = match prefix_length {
+                    Some(prefix_length) => format!("{}/{}", address, prefix_length),
+                    None => address.to_string(),
+                };
+                rules.push(format!(
+                    "{} daddr {}{} {}",
+                    family,
+                    network,
+                    ports,
+                    verdict(rule_cfg.action)
+                ));
+            }
+        }
+        rules.push(verdict(self.default_action));
+
+        let mut ruleset = format!(
+            "table inet {} {{\n  counter {} {{ }}\n  chain egress {{\n",
+            nft_table_name(job_id),
+            DENIED_COUNTER
+        );
+        for rule in rules {
+            ruleset.push_str(&format!("    {}\n", rule));
+        }
+        ruleset.push_str("  }\n");
+        for hook in ["forward", "input"] {
+            ruleset.push_str(&format!(
+                "  chain egress_{hook} {{\n    \
+                     type filter hook {hook} priority filter; policy accept;\n    \
+                     iifname \"{ifname}\" jump egress\n  \
+                   }}\n",
+                hook = hook,
+                ifname = allocation.ifname_host
+            ));
+        }
+        ruleset.push_str("}\n");
+
+        Ok(ruleset)
+    }
+}
+
+/// Number of packets of a job denied by its egress policy so far.
+pub async fn denied_packets(job_id: Uuid) -> Result<u64, String> {
+    let output = Command::new("nft")
+        .args([
+            "list",
+            "counter",
+            "inet",
+            &nft_table_name(job_id),
+            DENIED_COUNTER,
+        ])
+        .output()
+        .await
+        .map_err(|e| format!("Running \"nft\" failed: {:?}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Reading the egress counter of job {:?} failed: {}",
+            job_id,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    // The counter is listed as `packets <n> bytes <n>`:
+    let listing = String::from_utf8_lossy(&output.stdout);
+    let mut tokens = listing.split_whitespace();
+    tokens
+        .find(|token| *token == "packets")
+        .and_then(|_| tokens.next())
+        .and_then(|packets| packets.parse().ok())
+        .ok_or_else(|| format!("Failed to parse egress counter of job {:?}", job_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(
+        action: EgressAction,
+        destination: &str,
+        protocol: Option<EgressProtocol>,
+        ports: &[u16],
+    ) -> NspawnRunnerEgressRuleConfig {
+        NspawnRunnerEgressRuleConfig {
+            action,
+            destination: destination.to_string(),
+            protocol,
+            ports: ports.to_vec(),
+        }
+    }
+
+    fn allocation() -> HostNetworkAllocation {
+        HostNetworkAllocation {
+            ifname_host: "tm-test".to_string(),
+            ifname_container: "host0".to_string(),
+            ipv4: None,
+            ipv6: None,
+        }
+    }
+
+    #[test]
+    fn parses_networks() {
+        assert_eq!(
+            parse_network("10.0.0.0/8"),
+            Some(("10.0.0.0".parse().unwrap(), Some(8)))
+        );
+        assert_eq!(
+            parse_network("192.0.2.1"),
+            Some(("192.0.2.1".parse().unwrap(), None))
+        );
+        assert_eq!(
+            parse_network("0.0.0.0/0"),
+            Some(("0.0.0.0".parse().unwrap(), Some(0)))
+        );
+        assert_eq!(
+            parse_network("10.0.0.1/32"),
+            Some(("10.0.0.1".parse().unwrap(), Some(32)))
+        );
+        assert_eq!(
+            parse_network("2001:db8::/32"),
+            Some(("2001:db8::".parse().unwrap(), Some(32)))
+        );
+        assert_eq!(
+            parse_network("::1/128"),
+            Some(("::1".parse().unwrap(), Some(128)))
+        );
+        assert_eq!(
+            parse_network("fd00::1"),
+            Some(("fd00::1".parse().unwrap(), None))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_networks() {
+        for destination in [
+            "10.0.0.0/33",
+            "2001:db8::/129",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "10.0.0/8",
+            "example.com",
+            "",
+        ] {
+            assert_eq!(parse_network(destination), None, "{:?}", destination);
+        }
+    }
+
+    #[tokio::test]
+    async fn generates_ruleset() {
+        let egress_cfg = NspawnRunnerEnvironmentEgressConfig {
+            rule: vec![
+                rule(
+                    EgressAction::Allow,
+                    "192.0.2.0/24",
+                    Some(EgressProtocol::Tcp),
+                    &[22, 443],
+                ),
+                rule(
+                    EgressAction::Allow,
+                    "2001:db8::53",
+                    Some(EgressProtocol::Udp),
+                    &[],
+                ),
+                rule(EgressAction::Deny, "10.0.0.0/8", None, &[]),
+            ],
+            default_action: EgressAction::Allow,
+        };
+
+        let ruleset = egress_cfg
+            .ruleset(Uuid::nil(), &allocation())
+            .await
+            .unwrap();
+        assert_eq!(
+            ruleset,
+            "table inet treadmill_00000000000000000000000000000000 {\n  \
+               counter egress_denied { }\n  \
+               chain egress {\n    \
+                 ct state established,related accept\n    \
+                 icmpv6 type { nd-neighbor-solicit, nd-neighbor-advert, nd-router-solicit } accept\n    \
+                 ip daddr 192.0.2.0/24 tcp dport { 22, 443 } accept\n    \
+                 ip6 daddr 2001:db8::53 meta l4proto udp accept\n    \
+                 ip daddr 10.0.0.0/8 counter name \"egress_denied\" drop\n    \
+                 accept\n  \
+               }\n  \
+               chain egress_forward {\n    \
+                 type filter hook forward priority filter; policy accept;\n    \
+                 iifname \"tm-test\" jump egress\n  \
+               }\n  \
+               chain egress_input {\n    \
+                 type filter hook input priority filter; policy accept;\n    \
+                 iifname \"tm-test\" jump egress\n  \
+               }\n\
+             }\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn requires_protocol_for_ports() {
+        let egress_cfg = NspawnRunnerEnvironmentEgressConfig {
+            rule: vec![rule(EgressAction::Allow, "192.0.2.1", None, &[80])],
+            default_action: EgressAction::Deny,
+        };
+        assert!(egress_cfg
+            .ruleset(Uuid::nil(), &allocation())
+            .await
+            .is_err());
+    }
+}
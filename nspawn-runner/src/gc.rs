@@ -0,0 +1,382 @@
+//! Garbage collection of resources left behind by jobs.
+//!
+//! Jobs with metadata in the runner's state directory are recovered (and, if
+//! no longer running, cleaned up) through [`JobDriver::recover`]. However, a
+//! runner may also die before it has persisted a job's metadata, and cleaning
+//! up a job may fail. This module finds root file system mounts and volumes,
+//! control sockets, job state directories and address leases that do not
+//! belong to any known job and releases them. It also destroys image versions
+//! that are no longer in use.
+//!
+//! Resources of a job being started are not yet recorded in its metadata,
+//! and would be mistaken for garbage. The runner thus holds an exclusive lock
+//! on its state directory for its entire lifetime, which `--gc` must acquire
+//! as well.
+//!
+//! [`JobDriver::recover`]: treadmill_rs::executor::JobDriver::recover
+
+use std::collections::HashSet;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::process::Command;
+use uuid::Uuid;
+
+use crate::rootfs::RootFsProvider;
+use crate::{is_mountpoint, NspawnRunner, NspawnRunnerJobMetadata};
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct NspawnRunnerGcConfig {
+    /// Whether to destroy orphaned root file system volumes (e.g., ZFS
+    /// datasets), or only unmount them.
+    #[serde(default, alias = "destroy_zfs_datasets")]
+    destroy_volumes: bool,
+}
+
+/// Actions taken by a garbage collection pass.
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub actions: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+impl GcReport {
+    pub fn action(&mut self, action: String) {
+        info!("GC: {}", action);
+        self.actions.push(action);
+    }
+
+    pub fn error(&mut self, error: String) {
+        warn!("GC: {}", error);
+        self.errors.push(error);
+    }
+}
+
+/// Holds an exclusive lock on the runner's state directory until dropped.
+pub struct StateDirLock(#[allow(dead_code)] std::fs::File);
+
+/// Lock the runner's state directory, such that no other runner or garbage
+/// collection pass operates on it concurrently. Fails if it is already
+/// locked.
+pub fn lock_state_dir(state_dir: &Path) -> Result<StateDirLock, String> {
+    let res: std::io::Result<StateDirLock> = (|| {
+        std::fs::create_dir_all(state_dir)?;
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(state_dir.join("runner.lock"))?;
+        nix::fcntl::flock(
+            file.as_raw_fd(),
+            nix::fcntl::FlockArg::LockExclusiveNonblock,
+        )?;
+        Ok(StateDirLock(file))
+    })();
+
+    res.map_err(|e| match e.kind() {
+        std::io::ErrorKind::WouldBlock => format!(
+            "State directory {:?} is in use by another runner or garbage collection pass",
+            state_dir
+        ),
+        _ => format!("Failed to lock state directory {:?}: {:?}", state_dir, e),
+    })
+}
+
+/// Job IDs of all entries in `dir` named after a UUID. Other entries are
+/// never touched by the garbage collector.
+pub async fn job_dir_entries(dir: &Path) -> std::io::Result<Vec<(Uuid, PathBuf)>> {
+    let mut dir_entries = match tokio::fs::read_dir(dir).await {
+        Ok(dir_entries) => dir_entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+
+    let mut entries = vec![];
+    while let Some(dir_entry) = dir_entries.next_entry().await? {
+        if let Some(job_id) = dir_entry
+            .file_name()
+            .to_str()
+            .and_then(|name| Uuid::parse_str(name).ok())
+        {
+            entries.push((job_id, dir_entry.path()));
+        }
+    }
+
+    Ok(entries)
+}
+
+impl NspawnRunner {
+    /// Jobs with readable metadata. These are owned by the recovery process
+    /// and are never garbage collected. This includes the IDs that
+    /// pre-warmed root file systems were allocated under.
+    async fn known_job_ids(&self, report: &mut GcReport) -> HashSet<Uuid> {
+        let jobs_dir = self.config.state_dir.join("jobs");
+        let job_dirs = match job_dir_entries(&jobs_dir).await {
+            Ok(job_dirs) => job_dirs,
+            Err(e) => {
+                report.error(format!(
+                    "Failed to read jobs directory {:?}: {:?}",
+                    jobs_dir, e
+                ));
+                return HashSet::new();
+            }
+        };
+
+        let mut known_job_ids = HashSet::new();
+        for (job_id, job_dir) in job_dirs {
+            let metadata_path = job_dir.join("metadata.json");
+            if tokio::fs::metadata(&metadata_path).await.is_ok() {
+                known_job_ids.insert(job_id);
+
+                // The job may have claimed a pre-warmed root file system:
+                if let Some(root_fs_id) = tokio::fs::read(&metadata_path)
+                    .await
+                    .ok()
+                    .and_then(|serialized| {
+                        serde_json::from_slice::<NspawnRunnerJobMetadata>(&serialized).ok()
+                    })
+                    .and_then(|metadata| metadata.root_fs_id)
+                {
+                    known_job_ids.insert(root_fs_id);
+                }
+            } else {
+                // The runner died before persisting the job's metadata:
+                match tokio::fs::remove_dir_all(&job_dir).await {
+                    Ok(()) => report.action(format!(
+                        "Removed state directory {:?} of unknown job {:?}",
+                        job_dir, job_id
+                    )),
+                    Err(e) => report.error(format!(
+                        "Failed to remove state directory {:?}: {:?}",
+                        job_dir, e
+                    )),
+                }
+            }
+        }
+
+        // Warm root file systems are discarded by the warmer instead:
+        let prewarm_dir = self.config.state_dir.join("prewarm");
+        match job_dir_entries(&prewarm_dir).await {
+            Ok(records) => known_job_ids.extend(records.into_iter().map(|(id, _)| id)),
+            Err(e) => report.error(format!(
+                "Failed to read warm root filesystem records {:?}: {:?}",
+                prewarm_dir, e
+            )),
+        }
+
+        known_job_ids
+    }
+
+    /// Remove control sockets which any environment may have created in a
+    /// root file system.
+    async fn remove_stale_control_sockets(&self, root_fs: &Path, report: &mut GcReport) {
+        let mut control_socket_paths: Vec<&PathBuf> = self
+            .config
+            .environments
+            .values()
+            .map(|env_cfg| &env_cfg.control_socket_path)
+            .collect();
+        control_socket_paths.sort();
+        control_socket_paths.dedup();
+
+        for control_socket_path in control_socket_paths {
+            let path = root_fs.join(
+                control_socket_path
+                    .strip_prefix("/")
+                    .unwrap_or(control_socket_path),
+            );
+            if !path.starts_with(root_fs) {
+                continue;
+            }
+
+            match tokio::fs::symlink_metadata(&path).await {
+                Ok(metadata) if metadata.file_type().is_socket() => {
+                    match tokio::fs::remove_file(&path).await {
+                        Ok(()) => report.action(format!("Removed stale control socket {:?}", path)),
+                        Err(e) => report.error(format!(
+                            "Failed to remove stale control socket {:?}: {:?}",
+                            path, e
+                        )),
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Unmount root file systems of unknown jobs, and remove their
+    /// mountpoints.
+    async fn collect_mounts(
+        &self,
+        mount_base: &Path,
+        known_job_ids: &HashSet<Uuid>,
+        report: &mut GcReport,
+    ) {
+        let mountpoints = match job_dir_entries(mount_base).await {
+            Ok(mountpoints) => mountpoints,
+            Err(e) => {
+                report.error(format!(
+                    "Failed to read mount base {:?}: {:?}",
+                    mount_base, e
+                ));
+                return;
+            }
+        };
+
+        for (job_id, mountpoint) in mountpoints {
+            if known_job_ids.contains(&job_id) {
+                continue;
+            }
+
+            match is_mountpoint(&mountpoint) {
+                Ok(true) => {
+                    self.remove_stale_control_sockets(&mountpoint, report).await;
+
+                    match Command::new("umount").arg(&mountpoint).output().await {
+                        Ok(output) if output.status.success() => report.action(format!(
+                            "Unmounted root file system {:?} of unknown job {:?}",
+                            mountpoint, job_id
+                        )),
+                        Ok(output) => {
+                            report.error(format!(
+                                "Unmounting {:?} failed with exit-status {:?}. Stderr: {}",
+                                mountpoint,
+                                output.status.code(),
+                                String::from_utf8_lossy(&output.stderr)
+                            ));
+                            continue;
+                        }
+                        Err(e) => {
+                            report.error(format!("Unmounting {:?} failed: {:?}", mountpoint, e));
+                            continue;
+                        }
+                    }
+                }
+                Ok(false) => (),
+                Err(e) => {
+                    report.error(format!("Failed to read mount table: {:?}", e));
+                    continue;
+                }
+            }
+
+            // Only remove empty mountpoints, to never delete any data:
+            match tokio::fs::remove_dir(&mountpoint).await {
+                Ok(()) => report.action(format!("Removed mountpoint {:?}", mountpoint)),
+                Err(e) => report.error(format!(
+                    "Failed to remove mountpoint {:?}: {:?}",
+                    mountpoint, e
+                )),
+            }
+        }
+    }
+
+    /// Destroy root file system volumes of unknown jobs, if enabled by the
+    /// policy. Volumes already visited through another environment's provider
+    /// are skipped.
+    async fn collect_volumes(
+        &self,
+        root_fs_provider: &dyn RootFsProvider,
+        known_job_ids: &HashSet<Uuid>,
+        visited_volumes: &mut HashSet<String>,
+        report: &mut GcReport,
+    ) {
+        let volumes = match root_fs_provider.volumes().await {
+            Ok(volumes) => volumes,
+            Err(e) => {
+                report.error(format!("Failed to list root file system volumes: {}", e));
+                return;
+            }
+        };
+
+        for volume in volumes {
+            if !visited_volumes.insert(volume.volume.clone()) {
+                continue;
+            }
+
+            // Volumes retained after their job stopped are owned by the
+            // retention reaper:
+            if known_job_ids.contains(&volume.job_id) || volume.retained.is_some() {
+                continue;
+            }
+
+            if !self.config.gc.destroy_volumes {
+                match root_fs_provider.unmount(&volume.volume).await {
+                    Ok(true) => report.action(format!(
+                        "Unmounted volume {:?} of unknown job {:?}",
+                        volume.volume, volume.job_id
+                    )),
+                    Ok(false) => (),
+                    Err(e) => report.error(e),
+                }
+                report.action(format!(
+                    "Keeping volume {:?} of unknown job {:?}",
+                    volume.volume, volume.job_id
+                ));
+                continue;
+            }
+
+            match root_fs_provider.destroy(&volume.volume).await {
+                Ok(()) => report.action(format!(
+                    "Destroyed volume {:?} of unknown job {:?}",
+                    volume.volume, volume.job_id
+                )),
+                Err(e) => report.error(e),
+            }
+        }
+    }
+
+    /// Release all resources of jobs without metadata in the state directory.
+    /// Must not run concurrently to a job being started, i.e., only while
+    /// holding the [`StateDirLock`] and before the runner accepts jobs.
+    pub async fn collect_garbage(&self) -> GcReport {
+        let mut report = GcReport::default();
+
+        let known_job_ids = self.known_job_ids(&mut report).await;
+
+        let mut root_fs_providers = vec![];
+        for (environment_id, environment_cfg) in self.config.environments.iter() {
+            match Self::root_fs_provider(*environment_id, environment_cfg) {
+                Ok(root_fs_provider) => root_fs_providers.push(root_fs_provider),
+                Err(e) => report.error(e),
+            }
+        }
+
+        let mut mount_bases: Vec<&Path> = root_fs_providers
+            .iter()
+            .filter_map(|root_fs_provider| root_fs_provider.mount_base())
+            .collect();
+        mount_bases.sort();
+        mount_bases.dedup();
+
+        // Volumes can only be destroyed once they are unmounted:
+        for mount_base in mount_bases {
+            self.collect_mounts(mount_base, &known_job_ids, &mut report)
+                .await;
+        }
+
+        let mut visited_volumes = HashSet::new();
+        for root_fs_provider in root_fs_providers.iter() {
+            self.collect_volumes(
+                &**root_fs_provider,
+                &known_job_ids,
+                &mut visited_volumes,
+                &mut report,
+            )
+            .await;
+        }
+
+        self.collect_leases(&known_job_ids, &mut report).await;
+        self.collect_images(&mut report).await;
+
+        info!(
+            "Garbage collection finished, {} actions, {} errors.",
+            report.actions.len(),
+            report.errors.len()
+        );
+
+        report
+    }
+}
@@ -0,0 +1,301 @@
+//! Hot-plugging of devices into running job containers.
+//!
+//! Devices re-enumerate when, for instance, their firmware is flashed: they
+//! disappear and reappear, possibly under a new device node. While a job
+//! runs, its device watcher follows udev events through `udevadm monitor`.
+//! Device nodes appearing for one of the environment's device rules are
+//! granted to the container's scope unit and bind-mounted into the container,
+//! and the nodes of removed devices are revoked again. The resulting device
+//! nodes are persisted to the job's metadata, to resume from after
+//! reattaching to the job.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::Arc;
+
+use log::{debug, info, warn};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use uuid::Uuid;
+
+use treadmill_rs::executor::JobDriver;
+
+use crate::devices::{self, DeviceNode};
+use crate::{
+    DeviceConfigAddMount, NspawnRunner, NspawnRunnerEnvironmentDeviceConfig,
+    NspawnRunnerJobMetadata,
+};
+
+/// Subsystems of the device nodes that may belong to a device rule.
+const WATCHED_SUBSYSTEMS: &[&str] = &["usb", "tty", "hidraw", "usbmisc"];
+
+/// A udev event, as its properties.
+type UdevEvent = HashMap<String, String>;
+
+/// Device nodes currently passed through to a running container.
+struct DeviceWatcher {
+    driver: Arc<JobDriver<NspawnRunner>>,
+    job_id: Uuid,
+    device_cfgs: Vec<NspawnRunnerEnvironmentDeviceConfig>,
+    unit_name: String,
+    machine_name: String,
+    nodes: Vec<DeviceNode>,
+}
+
+async fn run_command(program: &str, args: &[String]) -> Result<String, String> {
+    debug!("Executing {:?} with arguments {:?}", program, args);
+    match Command::new(program).args(args).output().await {
+        Ok(output) if output.status.success() => {
+            Ok(String::from_utf8_lossy(&output.stdout).to_string())
+        }
+        Ok(output) => Err(format!(
+            "{} {} failed with exit-status {:?}. Stderr: {}",
+            program,
+            args.join(" "),
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr)
+        )),
+        Err(e) => Err(format!("Running {:?} failed: {:?}", program, e)),
+    }
+}
+
+impl DeviceWatcher {
+    /// Device rule matching a newly added device node, if any.
+    fn matching_rule(&self, devname: &Path, devpath: &str) -> Option<DeviceNode> {
+        let sysfs_path = Path::new("/sys").join(devpath.trim_start_matches('/'));
+        self.device_cfgs
+            .iter()
+            .find(|device_cfg| device_cfg.matches_hotplug(devname, &sysfs_path))
+            .map(|device_cfg| device_cfg.device_node(devname.to_path_buf()))
+    }
+
+    /// Replace the container's device permissions by the current set of
+    /// device nodes.
+    async fn update_device_allow(&self) -> Result<(), String> {
+        let mut args = vec![
+            "set-property".to_string(),
+            "--runtime".to_string(),
+            self.unit_name.clone(),
+            // An empty assignment resets the list:
+            "DeviceAllow=".to_string(),
+        ];
+        args.extend(
+            self.nodes
+                .iter()
+                .map(|node| format!("DeviceAllow={} {}", node.node.display(), node.access)),
+        );
+        run_command("systemctl", &args).await.map(|_| ())
+    }
+
+    async fn bind_node(&self, node: &DeviceNode) -> Result<(), String> {
+        let mut args = vec!["bind".to_string(), "--mkdir".to_string()];
+        if node.add_mount == DeviceConfigAddMount::ReadOnly {
+            args.push("--read-only".to_string());
+        }
+        args.extend([self.machine_name.clone(), node.node.display().to_string()]);
+        run_command("machinectl", &args).await.map(|_| ())
+    }
+
+    async fn unbind_node(&self, node: &DeviceNode) -> Result<(), String> {
+        let leader = run_command(
+            "machinectl",
+            &[
+                "show".to_string(),
+                "--property=Leader".to_string(),
+                "--value".to_string(),
+                self.machine_name.clone(),
+            ],
+        )
+        .await?;
+        run_command(
+            "nsenter",
+            &[
+                format!("--target={}", leader.trim()),
+                "--mount".to_string(),
+                "--".to_string(),
+                "umount".to_string(),
+                node.node.display().to_string(),
+            ],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn handle_event(&mut self, event: &UdevEvent) -> Result<(), String> {
+        let (Some(action), Some(devname), Some(devpath)) = (
+            event.get("ACTION"),
+            event.get("DEVNAME"),
+            event.get("DEVPATH"),
+        ) else {
+            return Ok(());
+        };
+        let devname = Path::new("/dev").join(devname.trim_start_matches("/dev/"));
+
+        match action.as_str() {
+            "add" => {
+                if self.nodes.iter().any(|node| node.node == devname) {
+                    return Ok(());
+                }
+                let Some(node) = self.matching_rule(&devname, devpath) else {
+                    return Ok(());
+                };
+                if node.access.is_empty() {
+                    return Ok(());
+                }
+
+                info!("Device node {:?} appeared, passing it through", devname);
+                self.nodes.push(node.clone());
+                self.save_nodes().await?;
+                self.update_device_allow().await?;
+                if node.add_mount != DeviceConfigAddMount::No {
+                    self.bind_node(&node).await?;
+                }
+            }
+            "remove" => {
+                let Some(idx) = self.nodes.iter().position(|node| node.node == devname) else {
+                    return Ok(());
+                };
+
+                info!("Device node {:?} disappeared, revoking it", devname);
+                let node = self.nodes.remove(idx);
+                self.save_nodes().await?;
+                self.update_device_allow().await?;
+                if node.add_mount != DeviceConfigAddMount::No {
+                    self.unbind_node(&node).await?;
+                }
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    /// Record the current set of device nodes in the job's metadata.
+    async fn save_nodes(&self) -> Result<(), String> {
+        let nodes = self.nodes.clone();
+        self.driver
+            .executor()
+            .update_metadata(self.job_id, |metadata| metadata.device_nodes = nodes)
+            .await
+    }
+}
+
+impl NspawnRunnerEnvironmentDeviceConfig {
+    /// Whether a device node that appeared belongs to this rule. Fixed device
+    /// nodes are matched by path, USB devices by the USB device the node's
+    /// sysfs path is nested in.
+    fn matches_hotplug(&self, devname: &Path, sysfs_path: &Path) -> bool {
+        match (&self.device_node, &self.usb) {
+            (Some(device_node), _) => {
+                let device_node = if self.resolve_symlink {
+                    std::fs::canonicalize(device_node).unwrap_or_else(|_| device_node.clone())
+                } else {
+                    device_node.clone()
+                };
+                device_node == devname
+            }
+            (None, Some(usb_match)) => sysfs_path
+                .ancestors()
+                .find_map(|ancestor| {
+                    let name = ancestor.file_name()?.to_str()?;
+                    devices::is_usb_device_name(name).then_some((name, ancestor))
+                })
+                .is_some_and(|(port_path, device_dir)| usb_match.matches(port_path, device_dir)),
+            (None, None) => false,
+        }
+    }
+}
+
+/// Name under which `systemd-nspawn` registered the job's container.
+/// Containers launched without an explicit name, by previous runner versions,
+/// are named after the last component of their root directory.
+fn machine_name(metadata: &NspawnRunnerJobMetadata) -> Option<String> {
+    metadata.machine_name.clone().or_else(|| {
+        metadata
+            .root_fs_mountpoint
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+    })
+}
+
+/// Follow udev events and keep the container's devices in sync with the
+/// environment's device rules. Returns `None` if the environment has no
+/// devices.
+pub fn spawn_device_watcher(
+    driver: &Arc<JobDriver<NspawnRunner>>,
+    metadata: &NspawnRunnerJobMetadata,
+) -> Option<tokio::task::JoinHandle<()>> {
+    let device_cfgs = metadata.environment_config.device.clone();
+    if device_cfgs.is_empty() {
+        return None;
+    }
+
+    let job_id = metadata.job_id;
+    let mut watcher = DeviceWatcher {
+        driver: driver.clone(),
+        job_id,
+        device_cfgs,
+        unit_name: metadata.unit_name.clone()?,
+        machine_name: machine_name(metadata)?,
+        nodes: metadata.device_nodes.clone(),
+    };
+
+    let mut args = vec![
+        "monitor".to_string(),
+        "--udev".to_string(),
+        "--property".to_string(),
+    ];
+    args.extend(
+        WATCHED_SUBSYSTEMS
+            .iter()
+            .map(|subsystem| format!("--subsystem-match={}", subsystem)),
+    );
+
+    Some(tokio::spawn(async move {
+        let mut child = match Command::new("udevadm")
+            .args(&args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => {
+                warn!(
+                    "Failed to watch devices of job {:?}, hot-plugging is disabled: {:?}",
+                    job_id, e
+                );
+                return;
+            }
+        };
+
+        // Events are printed as blocks of `KEY=VALUE` lines, separated by
+        // empty lines:
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        let mut event = UdevEvent::new();
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) if line.is_empty() => {
+                    if let Err(e) = watcher.handle_event(&event).await {
+                        warn!("Failed to update devices of job {:?}: {}", job_id, e);
+                    }
+                    event.clear();
+                }
+                Ok(Some(line)) => {
+                    if let Some((key, value)) = line.split_once('=') {
+                        event.insert(key.to_string(), value.to_string());
+                    }
+                }
+                Ok(None) => {
+                    warn!("udevadm monitor of job {:?} exited", job_id);
+                    break;
+                }
+                Err(e) => {
+                    warn!("Failed to read udev events of job {:?}: {:?}", job_id, e);
+                    break;
+                }
+            }
+        }
+    }))
+}
@@ -0,0 +1,470 @@
+//! Versioned root file system images of environments.
+//!
+//! Images (e.g., tarballs or the output of the mkosi definitions in
+//! `environments/`) are imported through `--import-image`, which populates a
+//! new image volume of the environment's [`RootFsProvider`]. A catalog in the
+//! runner's state directory records the versions of every environment. Jobs
+//! select a version through the `image_version` environment parameter, and
+//! otherwise use the most recently imported one. Environments without any
+//! imported image use the base configured for their provider.
+//!
+//! [`RootFsProvider`]: crate::rootfs::RootFsProvider
+
+use std::collections::{HashMap, HashSet};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use treadmill_rs::api::coord_runner::sse as sse_api;
+use treadmill_rs::rollback::Rollback;
+
+use crate::gc::GcReport;
+use crate::rootfs::{command_output, unix_timestamp};
+use crate::{NspawnRunner, NspawnRunnerJobMetadata};
+
+/// Environment parameter selecting the image version of a job.
+pub const IMAGE_VERSION_PARAMETER: &str = "image_version";
+
+fn default_keep_versions() -> usize {
+    3
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NspawnRunnerImagesConfig {
+    /// Number of image versions to keep per environment, newest first. Older
+    /// versions are destroyed by the garbage collector, unless they are still
+    /// in use.
+    #[serde(default = "default_keep_versions")]
+    keep_versions: usize,
+}
+
+impl Default for NspawnRunnerImagesConfig {
+    fn default() -> Self {
+        NspawnRunnerImagesConfig {
+            keep_versions: default_keep_versions(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageVersion {
+    pub version: String,
+    /// Backend-specific identifier of the image volume.
+    pub image: String,
+    /// Path the image was imported from.
+    pub source: PathBuf,
+    /// When the image was imported, in seconds since the UNIX epoch.
+    pub imported_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ImageCatalog {
+    #[serde(default)]
+    pub environments: HashMap<Uuid, Vec<ImageVersion>>,
+}
+
+impl ImageCatalog {
+    /// The most recently imported image of an environment.
+    pub fn latest(&self, environment_id: Uuid) -> Option<&ImageVersion> {
+        self.environments
+            .get(&environment_id)?
+            .iter()
+            .max_by_key(|image| image.imported_at)
+    }
+}
+
+/// Image versions become part of volume names, and must not contain any
+/// characters interpreted by ZFS, `mount` or overlayfs.
+fn validate_version(version: &str) -> Result<(), String> {
+    if version.is_empty()
+        || version.starts_with(['.', '-'])
+        || !version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+    {
+        return Err(format!("Invalid image version {:?}", version));
+    }
+    Ok(())
+}
+
+/// Copy the contents of an image file or directory into `target`.
+async fn extract_image(source: &Path, target: &Path, scratch_dir: &Path) -> Result<(), String> {
+    let metadata = tokio::fs::metadata(source)
+        .await
+        .map_err(|e| format!("Failed to access image {:?}: {:?}", source, e))?;
+    let source_str = source.to_string_lossy();
+    let target_str = target.to_string_lossy();
+
+    if metadata.is_dir() {
+        // For instance, mkosi's `directory` output format:
+        return command_output(
+            "cp",
+            &[
+                "-a",
+                "--reflink=auto",
+                &format!("{}/.", source_str),
+                &target_str,
+            ],
+        )
+        .await
+        .map(|_| ());
+    }
+
+    if !matches!(
+        source.extension().and_then(|ext| ext.to_str()),
+        Some("raw" | "img")
+    ) {
+        // tar detects compressed archives by itself:
+        return command_output(
+            "tar",
+            &[
+                "--numeric-owner",
+                "--xattrs",
+                "--xattrs-include=*",
+                "--acls",
+                "-xpf",
+                &source_str,
+                "-C",
+                &target_str,
+            ],
+        )
+        .await
+        .map(|_| ());
+    }
+
+    // Disk images are mounted read-only, and their file systems copied:
+    tokio::fs::create_dir_all(scratch_dir)
+        .await
+        .map_err(|e| format!("Failed to create directory {:?}: {:?}", scratch_dir, e))?;
+    let scratch_str = scratch_dir.to_string_lossy();
+    command_output(
+        "systemd-dissect",
+        &["--mount", "--read-only", &source_str, &scratch_str],
+    )
+    .await?;
+
+    let copy_res = command_output(
+        "cp",
+        &[
+            "-a",
+            "--reflink=auto",
+            &format!("{}/.", scratch_str),
+            &target_str,
+        ],
+    )
+    .await;
+    let umount_res = command_output("systemd-dissect", &["--umount", &scratch_str]).await;
+    let _ = tokio::fs::remove_dir(scratch_dir).await;
+
+    copy_res?;
+    umount_res.map(|_| ())
+}
+
+/// Holds an exclusive lock on the image catalog until dropped.
+struct CatalogLock(#[allow(dead_code)] std::fs::File);
+
+impl NspawnRunner {
+    fn images_dir(&self) -> PathBuf {
+        self.config.state_dir.join("images")
+    }
+
+    /// Serialize modifications of the catalog between the runner and
+    /// invocations of `--import-image`.
+    async fn lock_catalog(&self) -> Result<CatalogLock, String> {
+        let images_dir = self.images_dir();
+        tokio::fs::create_dir_all(&images_dir).await.map_err(|e| {
+            format!(
+                "Failed to create images directory {:?}: {:?}",
+                images_dir, e
+            )
+        })?;
+
+        let lock_path = images_dir.join("catalog.lock");
+        tokio::task::spawn_blocking(move || -> std::io::Result<CatalogLock> {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&lock_path)?;
+            nix::fcntl::flock(file.as_raw_fd(), nix::fcntl::FlockArg::LockExclusive)?;
+            Ok(CatalogLock(file))
+        })
+        .await
+        .map_err(|e| format!("Failed to lock image catalog: {:?}", e))?
+        .map_err(|e| format!("Failed to lock image catalog: {:?}", e))
+    }
+
+    pub async fn load_catalog(&self) -> Result<ImageCatalog, String> {
+        let path = self.images_dir().join("catalog.json");
+        match tokio::fs::read(&path).await {
+            Ok(serialized) => serde_json::from_slice(&serialized)
+                .map_err(|e| format!("Failed to parse image catalog {:?}: {:?}", path, e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(ImageCatalog::default()),
+            Err(e) => Err(format!("Failed to read image catalog {:?}: {:?}", path, e)),
+        }
+    }
+
+    async fn store_catalog(&self, catalog: &ImageCatalog) -> Result<(), String> {
+        let path = self.images_dir().join("catalog.json");
+        let tmp_path = path.with_extension("tmp");
+        let res: std::io::Result<()> = async {
+            let serialized = serde_json::to_vec_pretty(catalog)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            tokio::fs::write(&tmp_path, serialized).await?;
+            tokio::fs::rename(&tmp_path, &path).await
+        }
+        .await;
+
+        res.map_err(|e| format!("Failed to write image catalog {:?}: {:?}", path, e))
+    }
+
+    /// Import an image from a tarball, raw disk image or directory as a new
+    /// version of an environment's image. Returns the imported version.
+    pub async fn import_image(
+        &self,
+        environment_id: Uuid,
+        version: Option<String>,
+        source: &Path,
+    ) -> Result<String, String> {
+        let environment_cfg = self
+            .config
+            .environments
+            .get(&environment_id)
+            .ok_or_else(|| format!("Unknown environment {:?}", environment_id))?;
+        let version = version.unwrap_or_else(|| unix_timestamp().to_string());
+        validate_version(&version)?;
+
+        if self
+            .load_catalog()
+            .await?
+            .environments
+            .get(&environment_id)
+            .is_some_and(|images| images.iter().any(|image| image.version == version))
+        {
+            return Err(format!(
+                "Image version {:?} of environment {:?} already exists",
+                version, environment_id
+            ));
+        }
+
+        let root_fs_provider = Self::root_fs_provider(environment_id, environment_cfg)?;
+        let mut rollback = Rollback::new();
+        let image_volume = root_fs_provider
+            .create_image(&version, &mut rollback)
+            .await?;
+
+        info!(
+            "Importing {:?} as image version {:?} of environment {:?}",
+            source, version, environment_id
+        );
+        let scratch_dir = self
+            .images_dir()
+            .join(format!("mnt-{}-{}", environment_id, version));
+        let extract_res = extract_image(source, &image_volume.path, &scratch_dir)
+            .await
+            .map_err(|e| format!("Failed to import image {:?}: {}", source, e));
+        rollback.check(extract_res).await?;
+
+        let seal_res = root_fs_provider.seal_image(&image_volume).await;
+        rollback.check(seal_res).await?;
+
+        let add_res = async {
+            let _lock = self.lock_catalog().await?;
+            let mut catalog = self.load_catalog().await?;
+            let images = catalog.environments.entry(environment_id).or_default();
+            // Another import of the same version may have completed in the
+            // meantime:
+            if images.iter().any(|image| image.version == version) {
+                return Err(format!(
+                    "Image version {:?} of environment {:?} already exists",
+                    version, environment_id
+                ));
+            }
+            images.push(ImageVersion {
+                version: version.clone(),
+                image: image_volume.image.clone(),
+                source: source.to_path_buf(),
+                imported_at: unix_timestamp(),
+            });
+            self.store_catalog(&catalog).await
+        }
+        .await;
+        rollback.check(add_res).await?;
+
+        rollback.commit();
+        Ok(version)
+    }
+
+    /// The image to base a job's root file system on. Returns `None` if no
+    /// image has been imported for the job's environment.
+    pub async fn resolve_image(
+        &self,
+        msg: &sse_api::StartJobMessage,
+    ) -> Result<Option<ImageVersion>, String> {
+        let catalog = self.load_catalog().await?;
+
+        match msg.environment_parameters.get(IMAGE_VERSION_PARAMETER) {
+            Some(requested) => catalog
+                .environments
+                .get(&msg.environment_id)
+                .and_then(|images| images.iter().find(|image| image.version == requested.value))
+                .cloned()
+                .map(Some)
+                .ok_or_else(|| {
+                    format!(
+                        "Image version {:?} of environment {:?} does not exist",
+                        requested.value, msg.environment_id
+                    )
+                }),
+            None => Ok(catalog.latest(msg.environment_id).cloned()),
+        }
+    }
+
+    /// Image versions used by jobs with metadata in the state directory.
+    async fn image_versions_in_use(&self) -> Result<HashSet<(Uuid, String)>, String> {
+        let jobs_dir = self.config.state_dir.join("jobs");
+        let job_dirs = crate::gc::job_dir_entries(&jobs_dir)
+            .await
+            .map_err(|e| format!("Failed to read jobs directory {:?}: {:?}", jobs_dir, e))?;
+
+        let mut in_use = HashSet::new();
+        for (_, job_dir) in job_dirs {
+            let metadata_path = job_dir.join("metadata.json");
+            let serialized = match tokio::fs::read(&metadata_path).await {
+                Ok(serialized) => serialized,
+                // Removed by the garbage collector before:
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(format!(
+                        "Failed to read job metadata {:?}: {:?}",
+                        metadata_path, e
+                    ))
+                }
+            };
+            let metadata: NspawnRunnerJobMetadata =
+                serde_json::from_slice(&serialized).map_err(|e| {
+                    format!("Failed to parse job metadata {:?}: {:?}", metadata_path, e)
+                })?;
+            if let Some(image_version) = metadata.image_version {
+                in_use.insert((metadata.environment_id, image_version));
+            }
+        }
+
+        Ok(in_use)
+    }
+
+    /// Destroy image versions beyond the configured number of versions to
+    /// keep, which are not used by any job. Must not run concurrently to a
+    /// job being started.
+    pub async fn collect_images(&self, report: &mut GcReport) {
+        let in_use = match self.image_versions_in_use().await {
+            Ok(in_use) => in_use,
+            Err(e) => {
+                report.error(format!("Not collecting images: {}", e));
+                return;
+            }
+        };
+
+        let _lock = match self.lock_catalog().await {
+            Ok(lock) => lock,
+            Err(e) => {
+                report.error(e);
+                return;
+            }
+        };
+        let mut catalog = match self.load_catalog().await {
+            Ok(catalog) => catalog,
+            Err(e) => {
+                report.error(e);
+                return;
+            }
+        };
+
+        let mut modified = false;
+        for (environment_id, images) in catalog.environments.iter_mut() {
+            // The newest version is used by default, and is always kept:
+            let keep_versions = self.config.images.keep_versions.max(1);
+            if images.len() <= keep_versions {
+                continue;
+            }
+
+            let Some(environment_cfg) = self.config.environments.get(environment_id) else {
+                report.error(format!(
+                    "Not collecting images of unknown environment {:?}",
+                    environment_id
+                ));
+                continue;
+            };
+            let root_fs_provider = match Self::root_fs_provider(*environment_id, environment_cfg) {
+                Ok(root_fs_provider) => root_fs_provider,
+                Err(e) => {
+                    report.error(e);
+                    continue;
+                }
+            };
+
+            images.sort_by_key(|image| std::cmp::Reverse(image.imported_at));
+            let mut idx = keep_versions;
+            while idx < images.len() {
+                let image = &images[idx];
+                if in_use.contains(&(*environment_id, image.version.clone())) {
+                    idx += 1;
+                    continue;
+                }
+
+                match root_fs_provider.destroy_image(&image.image).await {
+                    Ok(true) => {
+                        report.action(format!(
+                            "Destroyed image version {:?} of environment {:?}",
+                            image.version, environment_id
+                        ));
+                        images.remove(idx);
+                        modified = true;
+                    }
+                    Ok(false) => {
+                        report.action(format!(
+                            "Kept image version {:?} of environment {:?}, still in use by \
+                             retained volumes",
+                            image.version, environment_id
+                        ));
+                        idx += 1;
+                    }
+                    Err(e) => {
+                        report.error(e);
+                        idx += 1;
+                    }
+                }
+            }
+        }
+
+        if modified {
+            if let Err(e) = self.store_catalog(&catalog).await {
+                report.error(e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_plain_versions() {
+        for version in ["1", "2024.01.15", "v1.2.3-rc_1", "A"] {
+            assert!(validate_version(version).is_ok(), "{:?}", version);
+        }
+    }
+
+    #[test]
+    fn rejects_unsafe_versions() {
+        for version in [
+            "", ".", "..", ".hidden", "-o", "1/2", "../1", "1 2", "1,2", "1:2", "1@2", "ü",
+        ] {
+            assert!(validate_version(version).is_err(), "{:?}", version);
+        }
+    }
+}
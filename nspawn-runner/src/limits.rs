@@ -0,0 +1,333 @@
+//! cgroup resource limits of job containers.
+//!
+//! Environments may limit the resources available to their jobs, which are
+//! passed as properties of the container's scope unit. Jobs can request lower
+//! limits through job parameters of the form `limits.<name>`, e.g.,
+//! `limits.memory_max`. All limits are validated against the capacity of the
+//! host before the job is started.
+
+use std::collections::{BTreeSet, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use treadmill_rs::api::coord_runner::sse as sse_api;
+
+/// Prefix of job parameters requesting lower limits.
+const LIMITS_PARAMETER_PREFIX: &str = "limits.";
+
+/// Valid range of systemd's `CPUWeight=` and `IOWeight=`.
+const WEIGHT_RANGE: std::ops::RangeInclusive<u64> = 1..=10000;
+
+/// Number of CPUs supported by the kernel at most (`CONFIG_NR_CPUS`), which
+/// bounds the ranges of CPU lists.
+const MAX_CPUS: u64 = 8192;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NspawnRunnerEnvironmentLimitsConfig {
+    /// CPU time available to the job, in percent of a single CPU.
+    #[serde(default)]
+    cpu_quota_percent: Option<u64>,
+    #[serde(default)]
+    cpu_weight: Option<u64>,
+    /// Hard memory limit, in bytes or with a K, M, G or T suffix.
+    #[serde(default)]
+    memory_max: Option<String>,
+    /// Memory usage above which the job is throttled, in bytes or with a K,
+    /// M, G or T suffix.
+    #[serde(default)]
+    memory_high: Option<String>,
+    #[serde(default)]
+    tasks_max: Option<u64>,
+    #[serde(default)]
+    io_weight: Option<u64>,
+    /// CPUs the job may run on, as a list of CPUs and ranges (e.g., "0-3,6").
+    #[serde(default)]
+    allowed_cpus: Option<String>,
+}
+
+/// Resources of the host, which no limit may exceed.
+struct HostCapacity {
+    cpus: BTreeSet<u64>,
+    memory_bytes: u64,
+}
+
+impl HostCapacity {
+    fn query() -> Result<Self, String> {
+        let online = std::fs::read_to_string("/sys/devices/system/cpu/online")
+            .map_err(|e| format!("Failed to read online CPUs: {:?}", e))?;
+        let cpus = parse_cpu_list(online.trim())?;
+
+        let meminfo = std::fs::read_to_string("/proc/meminfo")
+            .map_err(|e| format!("Failed to read /proc/meminfo: {:?}", e))?;
+        let memory_bytes = meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix("MemTotal:"))
+            .and_then(|total| total.trim().strip_suffix("kB"))
+            .and_then(|kb| kb.trim().parse::<u64>().ok())
+            .map(|kb| kb * 1024)
+            .ok_or_else(|| "Failed to parse MemTotal of /proc/meminfo".to_string())?;
+
+        Ok(HostCapacity { cpus, memory_bytes })
+    }
+}
+
+/// Parse a list of CPUs and CPU ranges, such as "0-3,6".
+fn parse_cpu_list(list: &str) -> Result<BTreeSet<u64>, String> {
+    let mut cpus = BTreeSet::new();
+    for item in list
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+    {
+        let parse = |cpu: &str| {
+            cpu.trim()
+                .parse::<u64>()
+                .ok()
+                .filter(|cpu| *cpu < MAX_CPUS)
+                .ok_or_else(|| format!("Invalid CPU list {:?}", list))
+        };
+        match item.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (parse(first)?, parse(last)?);
+                if first > last {
+                    return Err(format!("Invalid CPU range {:?} in {:?}", item, list));
+                }
+                cpus.extend(first..=last);
+            }
+            None => {
+                cpus.insert(parse(item)?);
+            }
+        }
+    }
+    if cpus.is_empty() {
+        return Err(format!("Empty CPU list {:?}", list));
+    }
+    Ok(cpus)
+}
+
+fn format_cpu_list(cpus: &BTreeSet<u64>) -> String {
+    cpus.iter()
+        .map(|cpu| cpu.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Parse a size in bytes, with an optional binary K, M, G or T suffix.
+fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (digits, shift) = match size.chars().last() {
+        Some('K') => (&size[..size.len() - 1], 10),
+        Some('M') => (&size[..size.len() - 1], 20),
+        Some('G') => (&size[..size.len() - 1], 30),
+        Some('T') => (&size[..size.len() - 1], 40),
+        _ => (size, 0),
+    };
+    digits
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|value| value.checked_mul(1 << shift))
+        .ok_or_else(|| format!("Invalid size {:?}", size))
+}
+
+/// Combine an environment's limit with a limit requested by a job, which may
+/// only be lower.
+fn effective_limit<T: PartialOrd + std::fmt::Debug>(
+    name: &str,
+    environment: Option<T>,
+    requested: Option<T>,
+) -> Result<Option<T>, String> {
+    match (environment, requested) {
+        (Some(environment), Some(requested)) if requested > environment => Err(format!(
+            "Requested {} of {:?} exceeds the environment's limit of {:?}",
+            name, requested, environment
+        )),
+        (environment, requested) => Ok(requested.or(environment)),
+    }
+}
+
+impl NspawnRunnerEnvironmentLimitsConfig {
+    /// Properties of the scope unit of a job's container, applying the limits
+    /// requested by the job.
+    pub fn unit_properties(
+        &self,
+        job_parameters: &HashMap<String, sse_api::ParameterValue>,
+    ) -> Result<Vec<String>, String> {
+        let mut requested: HashMap<&str, &str> = HashMap::new();
+        for (key, parameter) in job_parameters.iter() {
+            if let Some(name) = key.strip_prefix(LIMITS_PARAMETER_PREFIX) {
+                requested.insert(name, parameter.value.as_str());
+            }
+        }
+
+        let mut take_number = |name: &str| -> Result<Option<u64>, String> {
+            requested
+                .remove(name)
+                .map(|value| {
+                    value
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("Invalid {} {:?}", name, value))
+                })
+                .transpose()
+        };
+        let cpu_quota_percent = take_number("cpu_quota_percent")?;
+        let cpu_weight = take_number("cpu_weight")?;
+        let tasks_max = take_number("tasks_max")?;
+        let io_weight = take_number("io_weight")?;
+        let memory_max = requested.remove("memory_max").map(parse_size).transpose()?;
+        let memory_high = requested
+            .remove("memory_high")
+            .map(parse_size)
+            .transpose()?;
+        let allowed_cpus = requested
+            .remove("allowed_cpus")
+            .map(parse_cpu_list)
+            .transpose()?;
+        if let Some(name) = requested.keys().next() {
+            return Err(format!("Unknown limit {:?}", name));
+        }
+
+        let host = HostCapacity::query()?;
+        let mut properties = vec![];
+
+        if let Some(quota) = effective_limit(
+            "cpu_quota_percent",
+            self.cpu_quota_percent,
+            cpu_quota_percent,
+        )? {
+            let max_quota = host.cpus.len() as u64 * 100;
+            if quota == 0 || quota > max_quota {
+                return Err(format!(
+                    "CPU quota of {}% is outside of the host's capacity of {}%",
+                    quota, max_quota
+                ));
+            }
+            properties.push(format!("CPUQuota={}%", quota));
+        }
+
+        for (name, property, environment, requested) in [
+            ("cpu_weight", "CPUWeight", self.cpu_weight, cpu_weight),
+            ("io_weight", "IOWeight", self.io_weight, io_weight),
+        ] {
+            if let Some(weight) = effective_limit(name, environment, requested)? {
+                if !WEIGHT_RANGE.contains(&weight) {
+                    return Err(format!("Invalid {} {}", name, weight));
+                }
+                properties.push(format!("{}={}", property, weight));
+            }
+        }
+
+        let memory_max = effective_limit(
+            "memory_max",
+            self.memory_max.as_deref().map(parse_size).transpose()?,
+            memory_max,
+        )?;
+        let memory_high = effective_limit(
+            "memory_high",
+            self.memory_high.as_deref().map(parse_size).transpose()?,
+            memory_high,
+        )?;
+        for (name, property, limit) in [
+            ("memory_max", "MemoryMax", memory_max),
+            ("memory_high", "MemoryHigh", memory_high),
+        ] {
+            if let Some(bytes) = limit {
+                if bytes == 0 || bytes > host.memory_bytes {
+                    return Err(format!(
+                        "{} of {} bytes is outside of the host's {} bytes of memory",
+                        name, bytes, host.memory_bytes
+                    ));
+                }
+                properties.push(format!("{}={}", property, bytes));
+            }
+        }
+        if let (Some(max), Some(high)) = (memory_max, memory_high) {
+            if high > max {
+                return Err(format!(
+                    "memory_high of {} bytes exceeds memory_max of {} bytes",
+                    high, max
+                ));
+            }
+        }
+
+        if let Some(tasks) = effective_limit("tasks_max", self.tasks_max, tasks_max)? {
+            if tasks == 0 {
+                return Err("tasks_max must not be zero".to_string());
+            }
+            properties.push(format!("TasksMax={}", tasks));
+        }
+
+        let environment_cpus = self
+            .allowed_cpus
+            .as_deref()
+            .map(parse_cpu_list)
+            .transpose()?;
+        let cpus = match (environment_cpus, allowed_cpus) {
+            (Some(environment), Some(requested)) if !requested.is_subset(&environment) => {
+                return Err(format!(
+                    "Requested CPUs {} are not a subset of the environment's CPUs {}",
+                    format_cpu_list(&requested),
+                    format_cpu_list(&environment)
+                ))
+            }
+            (environment, requested) => requested.or(environment),
+        };
+        if let Some(cpus) = cpus {
+            if !cpus.is_subset(&host.cpus) {
+                return Err(format!(
+                    "CPUs {} are not a subset of the host's online CPUs {}",
+                    format_cpu_list(&cpus),
+                    format_cpu_list(&host.cpus)
+                ));
+            }
+            properties.push(format!("AllowedCPUs={}", format_cpu_list(&cpus)));
+        }
+
+        Ok(properties)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_list() {
+        assert_eq!(
+            parse_cpu_list("0-3,6").unwrap(),
+            BTreeSet::from([0, 1, 2, 3, 6])
+        );
+        assert_eq!(
+            parse_cpu_list(" 2 , 1-1, ").unwrap(),
+            BTreeSet::from([1, 2])
+        );
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("a").is_err());
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("0-18446744073709551615").is_err());
+        assert!(parse_cpu_list(&MAX_CPUS.to_string()).is_err());
+    }
+
+    #[test]
+    fn size() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("512K").unwrap(), 512 << 10);
+        assert_eq!(parse_size(" 2 G ").unwrap(), 2 << 30);
+        assert_eq!(parse_size("1T").unwrap(), 1 << 40);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("1X").is_err());
+        assert!(parse_size("-1M").is_err());
+        assert!(parse_size("16777216T").is_err());
+    }
+
+    #[test]
+    fn limit_may_only_be_lowered() {
+        assert_eq!(effective_limit::<u64>("x", None, None).unwrap(), None);
+        assert_eq!(effective_limit("x", Some(10), None).unwrap(), Some(10));
+        assert_eq!(effective_limit("x", None, Some(20)).unwrap(), Some(20));
+        assert_eq!(effective_limit("x", Some(10), Some(5)).unwrap(), Some(5));
+        assert_eq!(effective_limit("x", Some(10), Some(10)).unwrap(), Some(10));
+        assert!(effective_limit("x", Some(10), Some(11)).is_err());
+    }
+}
@@ -1,26 +1,49 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::{Context, Result};
 use async_trait::async_trait;
 use clap::Parser;
-use log::{debug, info, warn};
-use serde::Deserialize;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
 use simplelog::{ColorChoice, Config as SimpleLogConfig, LevelFilter, TermLogger, TerminalMode};
-use tokio::process::Command;
 use tokio::sync::Mutex;
 use uuid::Uuid;
 
 use treadmill_rs::api::coord_runner::rest as rest_api;
 use treadmill_rs::api::coord_runner::sse as sse_api;
-use treadmill_rs::connector;
-use treadmill_rs::control_socket;
+use treadmill_rs::api::runner_puppet;
+use treadmill_rs::console::{ConsoleFileFollower, ConsoleStreamer, ConsoleStreamerConfig};
 use treadmill_rs::dummy_connector::DummyRunnerConnector;
-use treadmill_sse_connector::SSERunnerConnector;
+use treadmill_rs::executor::{self, JobDriver, JobExecutor, RecoveredJob};
+use treadmill_rs::rollback::Rollback;
+use treadmill_rs::ssh_host_keys;
+use treadmill_sse_connector::{JobStateOutbox, SSERunnerConnector};
 use treadmill_unix_seqpacket_control_socket::UnixSeqpacketControlSocket;
 
+mod devices;
+mod egress;
+mod gc;
+mod hotplug;
+mod images;
+mod limits;
+mod network;
+mod prewarm;
+mod provision;
+mod retention;
+mod rootfs;
+mod usage;
+
+use rootfs::btrfs::{BtrfsRootFsProvider, NspawnRunnerEnvironmentBtrfsRootConfig};
+use rootfs::overlay::{NspawnRunnerEnvironmentOverlayRootConfig, OverlayRootFsProvider};
+use rootfs::zfs::{NspawnRunnerEnvironmentZfsRootConfig, ZfsRootFsProvider};
+use rootfs::RootFsProvider;
+
+/// Interval at which to check whether a container has exited.
+const PROCESS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Parser, Debug, Clone)]
 struct NspawnRunnerArgs {
     /// Path to the TOML configuration file
@@ -30,18 +53,30 @@ struct NspawnRunnerArgs {
     /// Whether to test-start a given environment
     #[arg(long)]
     test_env: Option<Uuid>,
-}
 
-#[derive(Deserialize, Debug, Clone)]
-pub struct NspawnRunnerEnvironmentZfsRootConfig {
-    // Should contain full filesystem snapshot spec
-    clone_from: Option<String>,
-    parent: String,
-    mount_base: String,
-    quota: Option<String>,
+    /// Release resources of orphaned jobs, report what was done and exit
+    #[arg(long, conflicts_with = "test_env")]
+    gc: bool,
+
+    /// Import a root filesystem image (tarball, raw disk image or directory)
+    /// for the environment given by --environment, and exit
+    #[arg(long, requires = "environment", conflicts_with_all = ["test_env", "gc"])]
+    import_image: Option<PathBuf>,
+
+    /// Environment to import an image for
+    #[arg(long, requires = "import_image")]
+    environment: Option<Uuid>,
+
+    /// Version to import the image as. Defaults to the current UNIX timestamp
+    #[arg(long, requires = "import_image")]
+    image_version: Option<String>,
+
+    /// List the imported image versions of all environments, and exit
+    #[arg(long, conflicts_with_all = ["test_env", "gc", "import_image"])]
+    list_images: bool,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct NspawnRunnerEnvironmentMountConfig {
     src: PathBuf,
     dst: PathBuf,
@@ -52,23 +87,24 @@ fn default_device_config_resolve_symlink() -> bool {
     true
 }
 
-#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
 #[serde(rename_all = "snake_case")]
 pub enum DeviceConfigAddMount {
+    #[default]
     No,
     ReadWrite,
     ReadOnly,
 }
 
-impl Default for DeviceConfigAddMount {
-    fn default() -> Self {
-        DeviceConfigAddMount::No
-    }
-}
-
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct NspawnRunnerEnvironmentDeviceConfig {
-    device_node: PathBuf,
+    /// Fixed device node. Mutually exclusive with `usb`.
+    #[serde(default)]
+    device_node: Option<PathBuf>,
+    /// Select all device nodes of USB devices matching these criteria,
+    /// resolved when a job starts.
+    #[serde(default)]
+    usb: Option<devices::UsbDeviceMatch>,
     #[serde(default = "default_device_config_resolve_symlink")]
     resolve_symlink: bool,
     #[serde(default)]
@@ -78,13 +114,13 @@ pub struct NspawnRunnerEnvironmentDeviceConfig {
     create: bool,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct NspawnRunnerEnvironmentVethConfig {
     ifname_host: String,
     ifname_container: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct NspawnRunnerEnvironmentIpv4NetworkConfig {
     address: std::net::Ipv4Addr,
     prefix_length: u8,
@@ -94,7 +130,7 @@ pub struct NspawnRunnerEnvironmentIpv4NetworkConfig {
     nameservers: Vec<std::net::Ipv4Addr>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct NspawnRunnerEnvironmentIpv6NetworkConfig {
     address: std::net::Ipv6Addr,
     prefix_length: u8,
@@ -104,21 +140,7 @@ pub struct NspawnRunnerEnvironmentIpv6NetworkConfig {
     nameservers: Vec<std::net::Ipv6Addr>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
-#[serde(rename_all = "snake_case")]
-pub enum SSHPreferredIPVersion {
-    Unspecified,
-    V4,
-    V6,
-}
-
-impl Default for SSHPreferredIPVersion {
-    fn default() -> Self {
-        SSHPreferredIPVersion::Unspecified
-    }
-}
-
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct NspawnRunnerEnvironmentConfig {
     #[serde(default)]
     init: Option<String>,
@@ -129,17 +151,54 @@ pub struct NspawnRunnerEnvironmentConfig {
     device: Vec<NspawnRunnerEnvironmentDeviceConfig>,
     #[serde(default)]
     zfsroot: Option<NspawnRunnerEnvironmentZfsRootConfig>,
+    #[serde(default)]
+    btrfsroot: Option<NspawnRunnerEnvironmentBtrfsRootConfig>,
+    #[serde(default)]
+    overlayroot: Option<NspawnRunnerEnvironmentOverlayRootConfig>,
+    #[serde(default)]
+    retention: Option<retention::NspawnRunnerEnvironmentRetentionConfig>,
+    /// Keep a root filesystem allocated (and the puppet installed into it) in
+    /// the background, to be claimed by the next job of this environment.
+    #[serde(default)]
+    prewarm: bool,
+    #[serde(default)]
+    provision: Option<provision::NspawnRunnerEnvironmentProvisionConfig>,
+    #[serde(default)]
+    limits: limits::NspawnRunnerEnvironmentLimitsConfig,
     control_socket_path: PathBuf,
     #[serde(default)]
     veth: Vec<NspawnRunnerEnvironmentVethConfig>,
     #[serde(default)]
     ssh_port: Option<u16>,
     #[serde(default)]
-    ssh_preferred_ip_version: SSHPreferredIPVersion,
+    ssh_preferred_ip_version: executor::SSHPreferredIPVersion,
+    /// Advertise direct SSH connections to the container, once reachable.
+    #[serde(default)]
+    direct_ssh: Option<executor::DirectSSHConfig>,
+    /// Accept SSH user certificates signed by these CAs, installed by the
+    /// puppet.
+    #[serde(default)]
+    ssh_user_ca: Option<executor::SSHUserCAConfig>,
     #[serde(default)]
     ipv4_network: Option<NspawnRunnerEnvironmentIpv4NetworkConfig>,
     #[serde(default)]
     ipv6_network: Option<NspawnRunnerEnvironmentIpv6NetworkConfig>,
+    /// Host networking managed by the runner, replacing the static
+    /// `ipv4_network` and `ipv6_network`.
+    #[serde(default)]
+    host_network: Option<network::NspawnRunnerEnvironmentHostNetworkConfig>,
+}
+
+fn default_state_dir() -> PathBuf {
+    PathBuf::from("/var/lib/treadmill/nspawn-runner")
+}
+
+fn default_retention_interval() -> u64 {
+    600
+}
+
+fn default_usage_report_interval() -> u64 {
+    60
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -148,399 +207,753 @@ pub struct NspawnRunnerConfig {
     board_id: Uuid,
     keepalive_timeout: u64,
     reconnect_wait: u64,
+    #[serde(default = "default_state_dir")]
+    state_dir: PathBuf,
+    #[serde(default)]
+    gc: gc::NspawnRunnerGcConfig,
+    #[serde(default)]
+    images: images::NspawnRunnerImagesConfig,
+    /// Interval in seconds at which to enforce the retention policies of all
+    /// environments.
+    #[serde(default = "default_retention_interval")]
+    retention_interval: u64,
+    /// Interval in seconds at which to report the resource usage of running
+    /// jobs.
+    #[serde(default = "default_usage_report_interval")]
+    usage_report_interval: u64,
     environments: HashMap<Uuid, NspawnRunnerEnvironmentConfig>,
 }
 
-pub struct NspawnRunnerJob {
+/// Persistent record of a job, stored in its state directory. This allows a
+/// restarted runner to reattach to the job's container, or to release its
+/// resources.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NspawnRunnerJobMetadata {
     job_id: Uuid,
-    _environment_id: Uuid,
+    environment_id: Uuid,
     environment_config: NspawnRunnerEnvironmentConfig,
     ssh_keys: Vec<String>,
-    nspawn_proc: Arc<Mutex<tokio::process::Child>>,
-    console_streamer_handle: tokio::task::JoinHandle<()>,
-    console_streamer_cmd_chan: tokio::sync::mpsc::Sender<ConsoleStreamerCommand>,
-    control_socket: UnixSeqpacketControlSocket<NspawnRunner>,
-    ssh_rendezvous_proxies: Vec<rendezvous_proxy::RendezvousProxy>,
+    #[serde(default)]
+    user_accounts: Vec<runner_puppet::UserAccount>,
+    /// SSH host keys generated for the job, if the environment installs
+    /// them.
+    #[serde(default)]
+    ssh_host_keys: Vec<ssh_host_keys::JobSSHHostKey>,
+    ssh_rendezvous_servers: Vec<sse_api::RendezvousServerSpec>,
 
     // Pointers to created resources, to delete when shutting down (if not
     // indicated otherwise):
-    root_fs_mountpoint: Option<PathBuf>,
-    zfs_root_fs: Option<String>,
-}
+    root_fs_mountpoint: PathBuf,
+    #[serde(alias = "zfs_root_fs")]
+    root_fs_volume: String,
+    #[serde(default)]
+    image_version: Option<String>,
+    /// ID the root filesystem was allocated under, if it was pre-warmed
+    /// instead of being allocated for this job.
+    #[serde(default)]
+    root_fs_id: Option<Uuid>,
+    /// Resource limits of the container's scope unit, as `systemd-run`
+    /// properties.
+    #[serde(default)]
+    unit_properties: Vec<String>,
+    /// Device nodes to pass through to the container, as resolved when the
+    /// job started and updated as devices are hot-plugged. Only the
+    /// persisted metadata is kept up to date while the job runs.
+    #[serde(default)]
+    device_nodes: Vec<devices::DeviceNode>,
+    /// Interfaces and addresses allocated for the container, if the runner
+    /// manages its host networking.
+    #[serde(default)]
+    network: Option<network::HostNetworkAllocation>,
 
-pub struct NspawnRunner {
-    connector: Arc<dyn connector::RunnerConnector>,
-    current_job: Mutex<Option<NspawnRunnerJob>>,
-    config: NspawnRunnerConfig,
+    // Set once the container has been launched:
+    unit_name: Option<String>,
+    nspawn_pid: Option<u32>,
+    #[serde(default)]
+    machine_name: Option<String>,
 }
 
-impl NspawnRunner {
-    pub fn new(connector: Arc<dyn connector::RunnerConnector>, config: NspawnRunnerConfig) -> Self {
-        NspawnRunner {
-            connector,
-            config,
-            current_job: Mutex::new(None),
+impl NspawnRunnerJobMetadata {
+    /// IPv4 address of the container, either allocated by the runner or
+    /// statically configured.
+    fn ipv4_address(&self) -> Option<std::net::Ipv4Addr> {
+        match self.network {
+            Some(ref network) => network.ipv4.as_ref().map(|lease| lease.address),
+            None => self
+                .environment_config
+                .ipv4_network
+                .as_ref()
+                .map(|ip4| ip4.address),
         }
     }
 
-    async fn allocate_zfs_root(
-        &self,
-        job_id: Uuid,
-        _environment_id: Uuid,
-        zfs_root_cfg: &NspawnRunnerEnvironmentZfsRootConfig,
-    ) -> Result<(PathBuf, String), String> {
-        let mut zfs_create_cmd = vec![
-            (if zfs_root_cfg.clone_from.is_some() {
-                "clone"
-            } else {
-                "create"
-            })
-            .to_string(),
-            "-o".to_string(),
-            "mountpoint=legacy".to_string(),
-        ];
+    /// IPv6 address of the container, either allocated by the runner or
+    /// statically configured.
+    fn ipv6_address(&self) -> Option<std::net::Ipv6Addr> {
+        match self.network {
+            Some(ref network) => network.ipv6.as_ref().map(|lease| lease.address),
+            None => self
+                .environment_config
+                .ipv6_network
+                .as_ref()
+                .map(|ip6| ip6.address),
+        }
+    }
+}
+
+/// The `systemd-nspawn` process of a job's container.
+pub enum NspawnProcess {
+    /// Spawned by this runner instance.
+    Child(tokio::process::Child),
 
-        if let Some(quota) = &zfs_root_cfg.quota {
-            zfs_create_cmd.push("-o".to_string());
-            zfs_create_cmd.push(format!("quota={}", quota));
+    /// Spawned by a previous runner instance. We can't wait on this process,
+    /// and instead check whether it is still alive and part of the job's
+    /// scope unit.
+    Reattached { pid: u32, unit_name: String },
+}
+
+impl NspawnProcess {
+    fn id(&self) -> Option<u32> {
+        match self {
+            NspawnProcess::Child(child) => child.id(),
+            NspawnProcess::Reattached { pid, .. } => Some(*pid),
         }
+    }
 
-        if let Some(ref source_fs) = zfs_root_cfg.clone_from {
-            zfs_create_cmd.push(source_fs.clone());
+    /// Check whether the process has exited. Returns `Some` with the exit
+    /// status, if it is known, once the process has exited.
+    fn try_wait(&mut self) -> std::io::Result<Option<Option<std::process::ExitStatus>>> {
+        match self {
+            NspawnProcess::Child(child) => Ok(child.try_wait()?.map(Some)),
+            NspawnProcess::Reattached { pid, unit_name } => {
+                if process_in_unit(*pid, unit_name)? {
+                    Ok(None)
+                } else {
+                    Ok(Some(None))
+                }
+            }
         }
+    }
 
-        let zfs_fs = format!("{}/{}", zfs_root_cfg.parent, job_id.to_string());
-        zfs_create_cmd.push(zfs_fs.clone());
-
-        // Create the file system:
-        match Command::new("zfs").args(&zfs_create_cmd).output().await {
-            Ok(std::process::Output {
-                status,
-                stdout,
-                stderr,
-            }) => {
-                if !status.success() {
-                    return Err(format!(
-                        "Creating root filesystem through \"zfs {:?}\" failed \
-                         with exit-status {:?}. Stdout: {}, Stderr: {}",
-                        &zfs_create_cmd,
-                        status.code(),
-                        String::from_utf8_lossy(&stdout),
-                        String::from_utf8_lossy(&stderr)
-                    ));
+    async fn wait(&mut self) -> std::io::Result<()> {
+        match self {
+            NspawnProcess::Child(child) => child.wait().await.map(|_| ()),
+            NspawnProcess::Reattached { .. } => {
+                while self.try_wait()?.is_none() {
+                    tokio::time::sleep(PROCESS_POLL_INTERVAL).await;
                 }
+                Ok(())
             }
-            Err(e) => {
-                return Err(format!(
-                    "Creating root filesystem through \"zfs {:?}\" failed: {:?}",
-                    &zfs_create_cmd, e
-                ));
+        }
+    }
+
+    /// Send a signal to the process, if it is still running.
+    fn signal(&mut self, signal: nix::sys::signal::Signal) {
+        // Don't signal a reattached process which has exited, its PID may
+        // have been reused:
+        if let NspawnProcess::Reattached { .. } = self {
+            if !matches!(self.try_wait(), Ok(None)) {
+                return;
             }
         }
 
-        // Mount the file system at the indicated path. First, create this path:
-        let mountpoint = format!("{}/{}", zfs_root_cfg.mount_base, job_id);
-        if let Err(e) = tokio::fs::create_dir_all(&mountpoint).await {
-            return Err(format!(
-                "Failed to create root mountpoint {:?}: {:?}",
-                &mountpoint, e,
-            ));
+        if let Some(pid) = self.id() {
+            let _ =
+                nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid.try_into().unwrap()), signal);
+        }
+    }
+
+    async fn kill(&mut self) -> std::io::Result<()> {
+        match self {
+            NspawnProcess::Child(child) => child.kill().await,
+            NspawnProcess::Reattached { .. } => {
+                self.signal(nix::sys::signal::Signal::SIGKILL);
+                self.wait().await
+            }
+        }
+    }
+}
+
+/// Check whether the process `pid` is alive and part of the systemd unit
+/// `unit_name`, guarding against PIDs being reused by unrelated processes.
+fn process_in_unit(pid: u32, unit_name: &str) -> std::io::Result<bool> {
+    match std::fs::read_to_string(format!("/proc/{}/cgroup", pid)) {
+        Ok(cgroups) => Ok(cgroups
+            .lines()
+            .any(|cgroup| cgroup.split('/').any(|component| component == unit_name))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Mount points in /proc/self/mounts escape whitespace as octal sequences.
+fn escape_mount_path(path: &Path) -> String {
+    format!("{}", path.display())
+        .replace('\\', "\\134")
+        .replace(' ', "\\040")
+        .replace('\t', "\\011")
+        .replace('\n', "\\012")
+}
+
+/// Check whether a file system is mounted at `path`.
+fn is_mountpoint(path: &Path) -> std::io::Result<bool> {
+    let escaped_path = escape_mount_path(path);
+    Ok(std::fs::read_to_string("/proc/self/mounts")?
+        .lines()
+        .any(|mount| mount.split(' ').nth(1) == Some(escaped_path.as_str())))
+}
+
+/// Whether `path`, or anything below it, is a mount point.
+fn has_mountpoint_below(path: &Path) -> std::io::Result<bool> {
+    let escaped_path = escape_mount_path(path);
+    let escaped_prefix = format!("{}/", escaped_path.trim_end_matches('/'));
+    Ok(std::fs::read_to_string("/proc/self/mounts")?
+        .lines()
+        .filter_map(|mount| mount.split(' ').nth(1))
+        .any(|mountpoint| mountpoint == escaped_path || mountpoint.starts_with(&escaped_prefix)))
+}
+
+pub struct NspawnRunnerJob {
+    metadata: NspawnRunnerJobMetadata,
+    nspawn_proc: Option<Arc<Mutex<NspawnProcess>>>,
+    exit_watcher: Option<tokio::task::JoinHandle<()>>,
+    usage: Arc<usage::UsageTracker>,
+    usage_reporter: Option<tokio::task::JoinHandle<()>>,
+    device_watcher: Option<tokio::task::JoinHandle<()>>,
+    // Exit status of the `systemd-nspawn` process, once it has exited:
+    exit_status: Option<rest_api::JobExitStatus>,
+    console_streamer: Option<ConsoleStreamer>,
+    console_followers: Vec<ConsoleFileFollower>,
+    allocation_message: Option<String>,
+}
+
+pub struct NspawnRunner {
+    config: NspawnRunnerConfig,
+    warm_roots: prewarm::WarmRoots,
+    // Serializes updates of persisted job metadata:
+    metadata_lock: Mutex<()>,
+}
+
+impl NspawnRunner {
+    pub fn new(config: NspawnRunnerConfig) -> Self {
+        NspawnRunner {
+            config,
+            warm_roots: prewarm::WarmRoots::default(),
+            metadata_lock: Mutex::new(()),
         }
+    }
+
+    fn job_state_dir(&self, job_id: Uuid) -> PathBuf {
+        self.config.state_dir.join("jobs").join(job_id.to_string())
+    }
+
+    fn console_paths(&self, job_id: Uuid) -> (PathBuf, PathBuf) {
+        let job_state_dir = self.job_state_dir(job_id);
+        (
+            job_state_dir.join("console.stdout"),
+            job_state_dir.join("console.stderr"),
+        )
+    }
+
+    async fn save_metadata(&self, metadata: &NspawnRunnerJobMetadata) -> Result<(), String> {
+        use tokio::io::AsyncWriteExt;
+
+        let job_state_dir = self.job_state_dir(metadata.job_id);
+        let metadata_path = job_state_dir.join("metadata.json");
+
+        let res: std::io::Result<()> = async {
+            tokio::fs::create_dir_all(&job_state_dir).await?;
+
+            let serialized = serde_json::to_vec(metadata)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+            // The metadata contains rendezvous server credentials, don't make
+            // it world-readable. Atomically replace any previous version:
+            let tmp_path = metadata_path.with_extension("tmp");
+            let mut file = tokio::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&tmp_path)
+                .await?;
+            file.write_all(&serialized).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&tmp_path, &metadata_path).await
+        }
+        .await;
+
+        res.map_err(|e| {
+            format!(
+                "Failed to persist job metadata to {:?}: {:?}",
+                metadata_path, e
+            )
+        })
+    }
+
+    /// Apply `update` to the job's persisted metadata. Used for updates made
+    /// while the job runs, possibly from tasks which don't hold the job,
+    /// such that they don't overwrite each other.
+    async fn update_metadata(
+        &self,
+        job_id: Uuid,
+        update: impl FnOnce(&mut NspawnRunnerJobMetadata),
+    ) -> Result<(), String> {
+        let _guard = self.metadata_lock.lock().await;
 
-        // Now, attempt to mount it:
-        match Command::new("mount")
-            .args(&["-t", "zfs", &zfs_fs, &mountpoint])
-            .output()
+        let metadata_path = self.job_state_dir(job_id).join("metadata.json");
+        let mut metadata: NspawnRunnerJobMetadata = tokio::fs::read(&metadata_path)
             .await
-        {
-            Ok(std::process::Output {
-                status,
-                stdout,
-                stderr,
-            }) => {
-                if !status.success() {
-                    return Err(format!(
-                        "Mounting ZFS root filesystem failed with exit-status \
-                         {:?}. Stdout: {}, Stderr: {}",
-                        status.code(),
-                        String::from_utf8_lossy(&stdout),
-                        String::from_utf8_lossy(&stderr)
-                    ));
+            .map_err(|e| format!("{:?}", e))
+            .and_then(|bytes| serde_json::from_slice(&bytes).map_err(|e| format!("{:?}", e)))
+            .map_err(|e| {
+                format!(
+                    "Failed to read job metadata from {:?}: {}",
+                    metadata_path, e
+                )
+            })?;
+        update(&mut metadata);
+        self.save_metadata(&metadata).await
+    }
+
+    /// Stream the job's console output files to the coordinator, continuing
+    /// from where a previous runner instance may have left off.
+    async fn start_console(
+        &self,
+        driver: &Arc<JobDriver<Self>>,
+        job: &mut NspawnRunnerJob,
+    ) -> Result<(), String> {
+        let console_streamer = ConsoleStreamer::start(
+            job.metadata.job_id,
+            driver.connector().clone(),
+            self.job_state_dir(job.metadata.job_id)
+                .join("console-spool"),
+            ConsoleStreamerConfig::default(),
+        )
+        .map_err(|e| format!("Failed to create console spool: {:?}", e))?;
+
+        let resume_cursor = console_streamer.resume_cursor();
+        let (stdout_path, stderr_path) = self.console_paths(job.metadata.job_id);
+        for (stdio_fd, path) in [
+            (rest_api::StdioFd::Stdout, stdout_path),
+            (rest_api::StdioFd::Stderr, stderr_path),
+        ] {
+            match console_streamer
+                .follow_file(stdio_fd, &path, resume_cursor.stream_position(stdio_fd))
+                .await
+            {
+                Ok(follower) => job.console_followers.push(follower),
+                Err(e) => {
+                    job.console_streamer = Some(console_streamer);
+                    Self::stop_console(job).await;
+                    return Err(format!("Failed to open console file {:?}: {:?}", path, e));
                 }
             }
-            Err(e) => {
-                return Err(format!("Mounting ZFS root filesystem failed: {:?}", e,));
+        }
+
+        job.console_streamer = Some(console_streamer);
+        Ok(())
+    }
+
+    /// Read all remaining console output and flush it to the coordinator.
+    async fn stop_console(job: &mut NspawnRunnerJob) {
+        for follower in job.console_followers.drain(..) {
+            if let Err(e) = follower.stop().await {
+                warn!("Error reading console output: {:?}", e);
             }
         }
 
-        Ok((PathBuf::from(mountpoint), zfs_fs))
+        if let Some(console_streamer) = job.console_streamer.take() {
+            console_streamer.shutdown().await;
+        }
     }
 
-    async fn destroy_zfs_root(&self, zfs_root_fs: &str) -> Result<(), String> {
-        match Command::new("zfs")
-            .args(&["destroy", "-v", zfs_root_fs])
-            .output()
-            .await
-        {
-            Ok(std::process::Output {
-                status,
-                stdout,
-                stderr,
-            }) => {
-                if !status.success() {
-                    Err(format!(
-                        "Destroying ZFS root filesystem failed with exit-status \
-                                     {:?}. Stdout: {}, Stderr: {}",
-                        status.code(),
-                        String::from_utf8_lossy(&stdout),
-                        String::from_utf8_lossy(&stderr)
-                    ))
-                } else {
-                    Ok(())
+    /// Watch for the container to exit on its own, and stop the job when it
+    /// does.
+    fn spawn_exit_watcher(
+        driver: &Arc<JobDriver<Self>>,
+        job_id: Uuid,
+        nspawn_proc: Arc<Mutex<NspawnProcess>>,
+    ) -> tokio::task::JoinHandle<()> {
+        let driver = driver.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(PROCESS_POLL_INTERVAL).await;
+
+                // The process may already be shutting down (e.g. because of an
+                // invocation of `stop_job`). In this case, we can't acquire
+                // the lock and don't attempt to stop the job again (as this
+                // would result in a deadlock). The shutdown will abort this
+                // task.
+                let Ok(mut proc) = nspawn_proc.try_lock() else {
+                    continue;
+                };
+
+                match proc.try_wait() {
+                    Ok(Some(_)) => break,
+                    Ok(None) => (),
+                    Err(e) => {
+                        warn!("Error while determining whether container exited: {:?}", e);
+                    }
                 }
             }
-            Err(e) => Err(format!(
-                "Destroying ZFS filesystem failed with error: {:?}",
-                e
+
+            // This must be executed in an asynchronous task, independent of
+            // this current one, as stopping the job aborts this task. The
+            // exit status is collected again when shutting down the job:
+            tokio::spawn(async move {
+                JobDriver::environment_exited(&driver, job_id).await;
+            });
+        })
+    }
+
+    /// Storage backend creating the root file systems of an environment.
+    fn root_fs_provider<'a>(
+        environment_id: Uuid,
+        environment_cfg: &'a NspawnRunnerEnvironmentConfig,
+    ) -> Result<Box<dyn RootFsProvider + 'a>, String> {
+        match (
+            &environment_cfg.zfsroot,
+            &environment_cfg.btrfsroot,
+            &environment_cfg.overlayroot,
+        ) {
+            (Some(zfs_root_cfg), None, None) => Ok(Box::new(ZfsRootFsProvider::new(
+                environment_id,
+                zfs_root_cfg,
+            ))),
+            (None, Some(btrfs_root_cfg), None) => Ok(Box::new(BtrfsRootFsProvider::new(
+                environment_id,
+                btrfs_root_cfg,
+            ))),
+            (None, None, Some(overlay_root_cfg)) => Ok(Box::new(OverlayRootFsProvider::new(
+                environment_id,
+                overlay_root_cfg,
+            ))),
+            (None, None, None) => Err(format!(
+                "No root filesystem provider found for environment {:?}.",
+                environment_id
+            )),
+            _ => Err(format!(
+                "Multiple root filesystem providers configured for environment {:?}.",
+                environment_id
             )),
         }
     }
 }
 
-#[derive(Clone, Debug)]
-enum ConsoleStreamerCommand {
-    Shutdown,
-}
-
 #[async_trait]
-impl connector::Runner for NspawnRunner {
-    async fn start_job(this: &Arc<Self>, msg: sse_api::StartJobMessage) {
-        // This method must not block for long periods of time. We're provided
-        // an &Arc<Self> to be able to launch async tasks, while returning
-        // immediately. For now, we assume that all actions performed here are
-        // reasonably fast, and we thus only return once the container is
-        // started.
-
-        // First, grab the `current_job` mutex. If there is already another job
-        // running, we abort:
-        let mut current_job_lg = this.current_job.lock().await;
-        if let Some(NspawnRunnerJob {
-            job_id: ref running_job_id,
-            ..
-        }) = *current_job_lg
-        {
-            this.connector
-                .post_job_state(
-                    msg.job_id,
-                    rest_api::JobState::Failed {
-                        status_message: Some(format!(
-                            "Cannot start job {:?} on board {:?}, still executing job {:?}",
-                            msg.job_id, this.config.board_id, running_job_id
-                        )),
-                    },
+impl JobExecutor for NspawnRunner {
+    type EnvironmentConfig = NspawnRunnerEnvironmentConfig;
+    type Job = NspawnRunnerJob;
+    type ControlSocket = UnixSeqpacketControlSocket<JobDriver<NspawnRunner>>;
+
+    fn board_id(&self) -> Uuid {
+        self.config.board_id
+    }
+
+    fn environment_config(&self, environment_id: Uuid) -> Option<&NspawnRunnerEnvironmentConfig> {
+        self.config.environments.get(&environment_id)
+    }
+
+    async fn allocate(
+        &self,
+        msg: &sse_api::StartJobMessage,
+        environment_cfg: &NspawnRunnerEnvironmentConfig,
+    ) -> Result<NspawnRunnerJob, String> {
+        // Reject jobs requesting limits the environment or host can't satisfy
+        // before allocating anything:
+        let unit_properties = environment_cfg
+            .limits
+            .unit_properties(&msg.job_parameters)
+            .map_err(|e| {
+                format!(
+                    "Cannot start job {:?} with the requested resource limits: {}",
+                    msg.job_id, e
                 )
-                .await;
-            return;
-        }
+            })?;
 
-        // Try to get a hold of the requested job environment. Error out if the
-        // environment can't be found:
-        let environment_cfg =
-            if let Some(env_cfg) = this.config.environments.get(&msg.environment_id) {
-                env_cfg
-            } else {
-                this.connector
-                    .post_job_state(
-                        msg.job_id,
-                        rest_api::JobState::Failed {
-                            status_message: Some(format!(
-                                "Cannot start job {:?} on board {:?}, unknown environment {:?}",
-                                msg.job_id, this.config.board_id, msg.environment_id
-                            )),
-                        },
-                    )
-                    .await;
-                return;
-            };
+        // Devices selected by their USB identity may be absent:
+        let device_nodes = devices::resolve_device_nodes(&environment_cfg.device)
+            .await
+            .map_err(|e| {
+                format!(
+                    "Cannot start job {:?} on board {:?}: {}",
+                    msg.job_id, self.config.board_id, e
+                )
+            })?;
 
-        // We're not executing any job and acquired the lock, begin allocating
-        // the root file system (volume):
-        this.connector
-            .post_job_state(
-                msg.job_id,
-                rest_api::JobState::Starting {
-                    stage: rest_api::JobStartingStage::Allocating,
-                    status_message: None,
-                },
-            )
-            .await;
-
-        // Dispatch to the methods for allocating the root file system:
-        let root_fs_res: Result<(PathBuf, Option<String>), String> =
-            if let Some(zfs_root_cfg) = &environment_cfg.zfsroot {
-                this.allocate_zfs_root(msg.job_id, msg.environment_id, zfs_root_cfg)
-                    .await
-                    .map(|(mountpoint, zfs_root_fs)| (mountpoint, Some(zfs_root_fs)))
-            } else {
-                Err(format!(
-                    "Cannot start job {:?} on board {:?}, no root filesystem provider found.",
-                    msg.job_id, this.config.board_id,
-                ))
-            };
+        let ssh_host_keys = match environment_cfg
+            .provision
+            .as_ref()
+            .and_then(|provision_cfg| provision_cfg.ssh_host_keys.as_ref())
+        {
+            Some(_) => ssh_host_keys::generate(msg.job_id).await.map_err(|e| {
+                format!(
+                    "Failed to generate SSH host keys for job {:?}: {}",
+                    msg.job_id, e
+                )
+            })?,
+            None => vec![],
+        };
 
-        let (root_fs_mountpoint, zfs_root_fs) = match root_fs_res {
-            Ok(t) => t,
-            Err(emsg) => {
-                this.connector
-                    .post_job_state(
+        // Every step registers how to undo it, such that no resources are
+        // leaked when a later step fails:
+        let mut rollback = Rollback::new();
+
+        // Create the root file system through the environment's provider:
+        let root_fs_provider = Self::root_fs_provider(msg.environment_id, environment_cfg)
+            .map_err(|e| {
+                format!(
+                    "Cannot start job {:?} on board {:?}: {}",
+                    msg.job_id, self.config.board_id, e
+                )
+            })?;
+        // Base the root file system on the requested or latest image, if any
+        // have been imported for this environment:
+        let image = self.resolve_image(msg).await?;
+        if let Some(ref image) = image {
+            info!(
+                "Starting job {:?} with image version {:?}",
+                msg.job_id, image.version
+            );
+        }
+        let image_version = image.as_ref().map(|image| image.version.clone());
+
+        // Claim the environment's pre-warmed root filesystem, if it is based
+        // on the same image:
+        let warm_root = self.claim_warm_root(msg.environment_id, image_version.as_deref());
+        let (root_fs_mountpoint, root_fs_volume, allocation_message) = match warm_root {
+            Some(ref warm_root) => {
+                info!(
+                    "Claimed warm root filesystem {:?} for job {:?}",
+                    warm_root.root_fs_volume, msg.job_id
+                );
+                (
+                    warm_root.root_fs_mountpoint.clone(),
+                    warm_root.root_fs_volume.clone(),
+                    Some(format!(
+                        "Claimed a pre-warmed root filesystem, saving {:.1}s of allocation.",
+                        warm_root.allocation_time.as_secs_f64()
+                    )),
+                )
+            }
+            None => {
+                let root_fs = root_fs_provider
+                    .allocate(
                         msg.job_id,
-                        rest_api::JobState::Failed {
-                            status_message: Some(emsg),
-                        },
+                        image.as_ref().map(|image| image.image.as_str()),
+                        &mut rollback,
                     )
+                    .await?;
+                (root_fs.mountpoint, root_fs.volume, None)
+            }
+        };
+
+        // Lease the container's addresses:
+        let network = match environment_cfg.host_network {
+            Some(ref host_network_cfg) => {
+                let network_res = host_network_cfg
+                    .allocate(self.config.board_id, msg.job_id, &mut rollback)
                     .await;
-                return;
+                if network_res.is_err() {
+                    if let Some(warm_root) = warm_root.clone() {
+                        self.return_warm_root(msg.environment_id, warm_root);
+                    }
+                }
+                Some(rollback.check(network_res).await?)
             }
+            None => None,
         };
 
+        let job = NspawnRunnerJob {
+            metadata: NspawnRunnerJobMetadata {
+                job_id: msg.job_id,
+                environment_id: msg.environment_id,
+                environment_config: environment_cfg.clone(),
+                ssh_keys: msg.ssh_keys.clone(),
+                user_accounts: msg.user_accounts.clone(),
+                ssh_host_keys,
+                ssh_rendezvous_servers: msg.ssh_rendezvous_servers.clone(),
+                root_fs_mountpoint,
+                root_fs_volume,
+                image_version,
+                root_fs_id: warm_root.as_ref().map(|warm_root| warm_root.root_fs_id),
+                unit_properties,
+                device_nodes,
+                network,
+                unit_name: None,
+                nspawn_pid: None,
+                machine_name: None,
+            },
+            nspawn_proc: None,
+            exit_watcher: None,
+            usage: Arc::new(usage::UsageTracker::default()),
+            usage_reporter: None,
+            device_watcher: None,
+            exit_status: None,
+            console_streamer: None,
+            console_followers: vec![],
+            allocation_message,
+        };
+
+        let save_res = self.save_metadata(&job.metadata).await;
+        if save_res.is_err() {
+            // The state directory may have been created, and must not be
+            // mistaken for a job to recover:
+            let job_state_dir = self.job_state_dir(msg.job_id);
+            rollback.push(
+                format!("create job state directory {:?}", job_state_dir),
+                async move {
+                    match tokio::fs::remove_dir_all(&job_state_dir).await {
+                        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                            Err(format!("{:?}", e))
+                        }
+                        _ => Ok(()),
+                    }
+                },
+            );
+            // The warm root filesystem is still recorded as such:
+            if let Some(warm_root) = warm_root.clone() {
+                self.return_warm_root(msg.environment_id, warm_root);
+            }
+        }
+        rollback.check(save_res).await?;
+
+        rollback.commit();
+        if let Some(warm_root) = warm_root {
+            self.transfer_warm_root(warm_root.root_fs_id).await;
+        }
+        Ok(job)
+    }
+
+    async fn provision(
+        &self,
+        driver: &Arc<JobDriver<Self>>,
+        msg: &sse_api::StartJobMessage,
+        job: &mut NspawnRunnerJob,
+    ) -> Result<(), String> {
+        self.provision_root_fs(driver, msg, job).await
+    }
+
+    async fn control_socket(
+        &self,
+        driver: &Arc<JobDriver<Self>>,
+        job: &NspawnRunnerJob,
+    ) -> Result<Self::ControlSocket, String> {
         // Create the control socket in the container's root path.
 
         // Get the absolute path to the socket (convert `control_socket_path`
         // into relative, then join with the `root_fs_mountpoint`):
-        let control_socket_path_rel = environment_cfg
+        let control_socket_path_rel = job
+            .metadata
+            .environment_config
             .control_socket_path
             .strip_prefix("/")
-            .unwrap_or(&environment_cfg.control_socket_path);
-        let control_socket_path_abs = root_fs_mountpoint.join(control_socket_path_rel);
+            .unwrap_or(&job.metadata.environment_config.control_socket_path);
+        let control_socket_path_abs = job
+            .metadata
+            .root_fs_mountpoint
+            .join(control_socket_path_rel);
         // Make sure that the final path is within the container:
-        assert!(control_socket_path_abs.starts_with(&root_fs_mountpoint));
+        assert!(control_socket_path_abs.starts_with(&job.metadata.root_fs_mountpoint));
+
+        // When reattaching to a job, the socket of the previous runner
+        // instance is still present:
+        if let Err(e) = tokio::fs::remove_file(&control_socket_path_abs).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(format!(
+                    "Removing stale control socket {:?} failed: {:?}",
+                    control_socket_path_abs, e
+                ));
+            }
+        }
 
         // Start the control socket handler and create a new UNIX SeqPacket socket:
-        let control_socket = UnixSeqpacketControlSocket::new_unix_seqpacket(
-            msg.job_id,
+        UnixSeqpacketControlSocket::new_unix_seqpacket(
+            job.metadata.job_id,
             &control_socket_path_abs,
-            this.clone(),
+            driver.clone(),
         )
         .await
-        .with_context(|| {
+        .map_err(|e| {
             format!(
-                "Creating control socket under \"{:?}\"",
-                control_socket_path_abs
+                "Creating control socket under {:?} failed: {:?}",
+                control_socket_path_abs, e
             )
         })
-        .unwrap();
+    }
 
+    fn ssh_socket_addr(&self, job: &NspawnRunnerJob) -> Option<std::net::SocketAddr> {
         // Spawn rendezvous proxy clients for SSH connections to the
         // container IP, if one is configured that we can reach.
-        use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+        executor::ssh_socket_addr(
+            job.metadata.environment_config.ssh_port,
+            &job.metadata.environment_config.ssh_preferred_ip_version,
+            job.metadata.ipv4_address(),
+            job.metadata.ipv6_address(),
+        )
+    }
 
-        let ssh_socket_addr = match (
-            &environment_cfg.ssh_port,
-            &environment_cfg.ssh_preferred_ip_version,
-            &environment_cfg.ipv4_network,
-            &environment_cfg.ipv6_network,
-        ) {
-            (None, _, _, _) => None,
-            (Some(port), SSHPreferredIPVersion::V4, Some(ipv4_network), _) => Some(SocketAddr::V4(
-                SocketAddrV4::new(ipv4_network.address, *port),
-            )),
-            (Some(port), SSHPreferredIPVersion::V6, _, Some(ipv6_network)) => Some(SocketAddr::V6(
-                SocketAddrV6::new(ipv6_network.address, *port, 0, 0),
-            )),
-            (Some(port), _, Some(ipv4_network), _) => Some(SocketAddr::V4(SocketAddrV4::new(
-                ipv4_network.address,
-                *port,
-            ))),
-            (Some(port), _, _, Some(ipv6_network)) => Some(SocketAddr::V6(SocketAddrV6::new(
-                ipv6_network.address,
-                *port,
-                0,
-                0,
-            ))),
-            _ => None,
-        };
+    fn direct_ssh_config(&self, job: &NspawnRunnerJob) -> Option<executor::DirectSSHConfig> {
+        job.metadata.environment_config.direct_ssh.clone()
+    }
 
-        let mut ssh_rendezvous_proxies = Vec::with_capacity(msg.ssh_rendezvous_servers.len());
-        if let Some(sa) = ssh_socket_addr {
-            for server_spec in &msg.ssh_rendezvous_servers {
-                ssh_rendezvous_proxies.push(
-                    rendezvous_proxy::RendezvousProxy::start(
-                        server_spec.client_id.clone(),
-                        server_spec.server_base_url.clone(),
-                        sa,
-                        server_spec.auth_token.clone(),
-                        Duration::from_secs(60),
-                        Duration::from_secs(10),
-                    )
-                    .await,
-                );
-            }
-        }
+    fn ssh_user_ca_config(&self, job: &NspawnRunnerJob) -> Option<executor::SSHUserCAConfig> {
+        job.metadata.environment_config.ssh_user_ca.clone()
+    }
 
-        // All resources have been allocated, mark the container as booting:
-        this.connector
-            .post_job_state(
-                msg.job_id,
-                rest_api::JobState::Starting {
-                    stage: rest_api::JobStartingStage::Booting,
-                    status_message: None,
-                },
-            )
-            .await;
+    fn ssh_host_keys<'a>(&self, job: &'a NspawnRunnerJob) -> &'a [ssh_host_keys::JobSSHHostKey] {
+        &job.metadata.ssh_host_keys
+    }
 
+    async fn boot(
+        &self,
+        driver: &Arc<JobDriver<Self>>,
+        job: &mut NspawnRunnerJob,
+    ) -> Result<(), String> {
+        let environment_cfg = &job.metadata.environment_config;
+
+        // Run the container in a well-known scope unit, such that we can find
+        // it again after restarting the runner:
+        let unit_name = format!("treadmill-job-{}.scope", job.metadata.job_id);
+        let machine_name = job.metadata.job_id.to_string();
         let mut run_args = vec![
             "--scope".to_string(),
+            format!("--unit={}", unit_name),
             "--property=DevicePolicy=closed".to_string(),
         ];
+        run_args.extend(
+            job.metadata
+                .unit_properties
+                .iter()
+                .map(|property| format!("--property={}", property)),
+        );
 
         let mut device_mounts = vec![];
-        for device_cfg in environment_cfg.device.iter() {
-            if !device_cfg.read && !device_cfg.write && !device_cfg.create {
-                // Don't add devices with no permissions:
-                continue;
-            }
-
-            let mut device_node = device_cfg.device_node.clone();
-
-            if device_cfg.resolve_symlink {
-                match tokio::fs::canonicalize(&device_cfg.device_node).await {
-                    Ok(canon) => {
-                        device_node = canon;
-                    }
-                    Err(e) => {
-                        warn!(
-                            "Failed to get canonical path to device node {:?}: {:?}",
-                            device_cfg.device_node, e
-                        );
-                    }
-                }
-            }
-
-            match &device_cfg.add_mount {
+        for device_node in job.metadata.device_nodes.iter() {
+            match &device_node.add_mount {
                 DeviceConfigAddMount::No => (),
                 DeviceConfigAddMount::ReadOnly => {
                     device_mounts.push(NspawnRunnerEnvironmentMountConfig {
-                        src: device_node.clone(),
-                        dst: device_node.clone(),
+                        src: device_node.node.clone(),
+                        dst: device_node.node.clone(),
                         readonly: true,
                     })
                 }
                 DeviceConfigAddMount::ReadWrite => {
                     device_mounts.push(NspawnRunnerEnvironmentMountConfig {
-                        src: device_node.clone(),
-                        dst: device_node.clone(),
+                        src: device_node.node.clone(),
+                        dst: device_node.node.clone(),
                         readonly: false,
                     })
                 }
             }
 
             run_args.push(format!(
-                "--property=DeviceAllow={} {}{}{}",
+                "--property=DeviceAllow={} {}",
                 // TODO: this should retain non-UTF8 characters
-                device_node.display(),
-                if device_cfg.read { "r" } else { "" },
-                if device_cfg.write { "w" } else { "" },
-                if device_cfg.create { "m" } else { "" },
+                device_node.node.display(),
+                device_node.access,
             ));
         }
 
@@ -549,13 +962,22 @@ impl connector::Runner for NspawnRunner {
             "systemd-nspawn".to_string(),
             "-D".to_string(),
             // TODO: what to do about non-Unicode paths?
-            format!("{}", root_fs_mountpoint.display()),
+            format!("{}", job.metadata.root_fs_mountpoint.display()),
+            // Register the container under the job's ID, which device
+            // hot-plugging refers to it by:
+            format!("--machine={}", machine_name),
             "--keep-unit".to_string(),
             "--private-users=pick".to_string(),
             "--private-network".to_string(),
         ]);
 
         // Add veth network interfaces:
+        if let Some(ref network) = job.metadata.network {
+            run_args.push(format!(
+                "--network-veth-extra={}:{}",
+                network.ifname_host, network.ifname_container
+            ));
+        }
         for veth_cfg in environment_cfg.veth.iter() {
             run_args.push(format!(
                 "--network-veth-extra={}:{}",
@@ -595,515 +1017,388 @@ impl connector::Runner for NspawnRunner {
             run_args.push("--boot".to_string());
         }
 
+        // The container's output is written to files in the job's state
+        // directory, which outlive this runner process:
+        let (stdout_path, stderr_path) = self.console_paths(job.metadata.job_id);
+        let open_console_file = |path: &PathBuf| {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("Failed to create console file {:?}: {:?}", path, e))
+        };
+        let stdout = open_console_file(&stdout_path)?;
+        let stderr = open_console_file(&stderr_path)?;
+
+        // Start streaming the console before spawning the container, such that
+        // failing to do so doesn't leave a running process behind:
+        self.start_console(driver, job).await?;
+
         info!("Executing \"systemd-run\" with arguments {:?}", run_args);
 
-        let mut child = match tokio::process::Command::new("systemd-run")
+        let child = match tokio::process::Command::new("systemd-run")
             .args(run_args)
             .stdin(std::process::Stdio::null())
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped())
+            .stdout(stdout)
+            .stderr(stderr)
             .spawn()
         {
             Ok(child) => child,
             Err(e) => {
-                // TODO: cleanup root file system!
-                this.connector
-                    .post_job_state(
-                        msg.job_id,
-                        rest_api::JobState::Failed {
-                            status_message: Some(format!("Failed to spawn container: {:?}", e,)),
-                        },
-                    )
-                    .await;
-                return;
+                Self::stop_console(job).await;
+                return Err(format!("Failed to spawn container: {:?}", e));
             }
         };
 
-        // Acquire the stdout and stderr pipes, and spawn a new log-streamer
-        // task that collects all log output and streams it to the coordinator:
-        let stdout = child
-            .stdout
-            .take()
-            .expect("Failed to acquire stdout from child process");
-        let stderr = child
-            .stderr
-            .take()
-            .expect("Failed to acquire stderr from child process");
-
-        let child = Arc::new(Mutex::new(child));
-
-        let this_streamer = this.clone();
-        let msg_streamer = msg.clone();
-        let (streamer_chan_tx, mut streamer_chan_rx) = tokio::sync::mpsc::channel(1);
-        let streamer_child = child.clone();
-        let console_streamer = tokio::spawn(async move {
-            use tokio::io::AsyncReadExt;
-
-            let this = this_streamer;
-            let msg = msg_streamer;
-
-            // Create BufReaders from the file descriptors for streaming:
-            let mut stdout_reader = tokio::io::BufReader::with_capacity(64 * 1024, stdout);
-            let mut stderr_reader = tokio::io::BufReader::with_capacity(64 * 1024, stderr);
-
-            // We also allocate buffers (VecDeques) which are used to buffer
-            // output it is acknowledged by the coordinator:
-            let mut console_queue = std::collections::VecDeque::new();
-            let mut console_queue_offset = 0;
-            let _console_queue_sent = 0;
-
-            let mut stdout_buf = [0; 64 * 1024];
-            let mut stdout_closed = false;
-            let mut stderr_buf = [0; 64 * 1024];
-            let mut stderr_closed = false;
-
-            enum ReadConsoleRes {
-                ZeroBytes,
-                Data,
-                Shutdown,
-                Error(std::io::Error),
+        // `systemd-run --scope` executes the command itself, so the child is
+        // the `systemd-nspawn` process:
+        job.metadata.unit_name = Some(unit_name);
+        job.metadata.nspawn_pid = child.id();
+        job.metadata.machine_name = Some(machine_name);
+        let nspawn_proc = Arc::new(Mutex::new(NspawnProcess::Child(child)));
+        job.exit_watcher = Some(Self::spawn_exit_watcher(
+            driver,
+            job.metadata.job_id,
+            nspawn_proc.clone(),
+        ));
+        job.nspawn_proc = Some(nspawn_proc);
+        job.usage_reporter = Some(self.spawn_usage_reporter(driver, job));
+
+        if let Err(e) = self.save_metadata(&job.metadata).await {
+            // Without its metadata, we'll be unable to reattach to this job
+            // after a restart, but it can still continue to run:
+            warn!("{}", e);
+        }
+
+        // Only start watching devices once the metadata has been saved, as
+        // the watcher persists its updates to it:
+        job.device_watcher = hotplug::spawn_device_watcher(driver, &job.metadata);
+
+        // The host side of the container's veth pair only exists once
+        // `systemd-nspawn` has set up the container:
+        if let (Some(host_network_cfg), Some(network)) = (
+            job.metadata.environment_config.host_network.clone(),
+            job.metadata.network.clone(),
+        ) {
+            if let Err(e) = host_network_cfg
+                .configure_host(job.metadata.job_id, &network)
+                .await
+            {
+                if let Err(shutdown_e) = self.shutdown(job).await {
+                    warn!("Failed to shut down container: {}", shutdown_e);
+                }
+                return Err(format!("Failed to configure host networking: {}", e));
             }
+        }
 
-            loop {
-                // TODO: force buf flush on timeout?
-                #[rustfmt::skip]
-                let res = tokio::select! {
-                    streamer_cmd_opt = streamer_chan_rx.recv() => {
-                        match streamer_cmd_opt {
-                            Some(ConsoleStreamerCommand::Shutdown) => ReadConsoleRes::Shutdown,
-                            None => {
-                                panic!("Streamer command channel TX dropped!");
-                            }
-                        }
-                    }
+        Ok(())
+    }
 
-                    read_res = stdout_reader.read(&mut stdout_buf), if !stdout_closed => {
-                        match read_res {
-                            Ok(0) => {
-                                // Mark as closed, so we don't loop reading zero bytes:
-                                stdout_closed = true;
-                                ReadConsoleRes::ZeroBytes
-                            },
-                            Ok(read_len) => {
-                                console_queue.push_back((
-                                    rest_api::StdioFd::Stdout,
-                                    stdout_buf[..read_len].to_vec()
-                                ));
-                                ReadConsoleRes::Data
-                            }
-                            Err(e) => ReadConsoleRes::Error(e),
-                        }
-                    }
+    async fn shutdown(&self, job: &mut NspawnRunnerJob) -> Result<(), String> {
+        // Stop passing hot-plugged devices through to the container:
+        if let Some(device_watcher) = job.device_watcher.take() {
+            device_watcher.abort();
+        }
 
-                    read_res = stderr_reader.read(&mut stderr_buf), if !stderr_closed => {
-                        match read_res {
-                            Ok(0) => {
-                                // Mark as closed, so we don't loop reading zero bytes:
-                                stderr_closed = true;
-                                ReadConsoleRes::ZeroBytes
-                            },
-                            Ok(read_len) => {
-                                console_queue.push_back((
-                                    rest_api::StdioFd::Stderr,
-                                    stderr_buf[..read_len].to_vec()
-                                ));
-                                ReadConsoleRes::Data
-                            },
-                            Err(e) => ReadConsoleRes::Error(e),
-                        }
-                    }
-                };
+        // The container's cgroup is removed once it has exited. Take a final
+        // sample of its resource usage:
+        if let Some(usage_reporter) = job.usage_reporter.take() {
+            usage_reporter.abort();
+        }
+        job.usage.sample(&job.metadata).await;
+
+        if let Some(ref nspawn_proc) = job.nspawn_proc {
+            // First, instruct the container to shut down. We attempt a graceful
+            // shutdown by sending a SIGTERM to the systemd-nspawn process,
+            // which should send a SIGRTMIN+3 to the container's PID1, which
+            // will initiate an orderly shutdown:
+            let mut child = nspawn_proc.lock().await;
+            debug!("Sending SIGTERM to nspawn process...");
+            child.signal(nix::sys::signal::Signal::SIGTERM);
 
-                match res {
-                    ReadConsoleRes::Data => {
-                        // TODO: this simply assumes that a single buffer
-                        // element has been appended to the VecDeque:
-                        let (stdio_fd, buf) = console_queue.back().unwrap();
-
-                        this.connector
-                            .send_job_console_log(
-                                msg.job_id,
-                                console_queue_offset,
-                                console_queue_offset + 1,
-                                &[(*stdio_fd, buf.len())],
-                                buf.clone(),
-                            )
-                            .await;
-                        console_queue_offset += 1;
-                    }
+            // Now, wait for the container to shut down, or until the shutdown
+            // timeout expires:
+            debug!(
+                "Waiting on process exit or shutdown timeout ({} secs)",
+                job.metadata.environment_config.shutdown_timeout
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(job.metadata.environment_config.shutdown_timeout)) => {},
+                _ = child.wait() => {}
+            };
 
-                    ReadConsoleRes::Shutdown => {
-                        // Asked to shut down. Once we implement chunking, do
-                        // one last flush to the coordinator.
-                        debug!("Shutting down console log streamer.");
-                        break;
+            // Attempt to get the process' exit status or, if that doesn't
+            // succeed, kill it, in a loop:
+            debug!("Process exited OR timeout fired. Check exit code or kill child in a loop.");
+            let mut exit_status = None;
+            while exit_status.is_none() {
+                match child.try_wait() {
+                    Ok(Some(es)) => {
+                        debug!("Child exited.");
+                        exit_status = Some(es);
                     }
-
-                    ReadConsoleRes::ZeroBytes => {
-                        info!("Handling ZeroBytes case, acquiring lock...");
-                        // Reading zero bytes is an indication that the process
-                        // might've exited. Check whether this is the case. If
-                        // the process has died, perform one last read and
-                        // invoke the shutdown code for this job. Prior to that
-                        // we must release the child lock! This code path may
-                        // also be invoked while the process is already shutting
-                        // down (e.g. because of an invocation of
-                        // `stop_job`). In this case, simply attempt another
-                        // loop iteration and don't attempt to stop the job
-                        // again (as this would result in a deadlock).
-                        let mut child = streamer_child.try_lock();
-
-                        let exited = match child.as_mut().map(|c| c.try_wait()) {
-                            // We have the lock, and the child has exited:
-                            Ok(Ok(Some(_))) => true,
-                            // We have the lock, but the child has not exited:
-                            Ok(Ok(None)) => false,
-                            // We have the lock, but couldn't determine the exit
-                            // status:
-                            Ok(Err(e)) => {
-                                panic!("Error while determining whether child exited: {:?}", e);
-                            }
-                            // We couldn't acquire the lock (presumably because
-                            // a stop was already requested, and thus the
-                            // process has shut down):
-                            Err(_) => false,
-                        };
-
-                        // Unlock the mutex:
-                        std::mem::drop(child);
-
-                        if exited {
-                            // TODO: Once we implement chunking, do one last
-                            // read on both FDs and flush to the coordinator.
-
-                            // This must be executed in an asynchronous task,
-                            // inpendent of this current one, or otherwise we'd
-                            // deadlock. This is because stop_job will await
-                            // this task's join.
-                            let stop_this = this.clone();
-                            tokio::spawn(async move {
-                                NspawnRunner::stop_job(
-                                    &stop_this,
-                                    sse_api::StopJobMessage { job_id: msg.job_id },
-                                )
-                                .await;
-                            });
-
-                            // Don't break out of the loop -- we still expect
-                            // the official Shutdown signal, as usual.
+                    Ok(None) => {
+                        if let Err(e) = child.kill().await {
+                            warn!("Error while killing nspawn process: {:?}", e);
                         }
                     }
-
-                    ReadConsoleRes::Error(e) => {
-                        panic!("Error reading process output: {:?}", e);
+                    Err(e) => {
+                        // For reattached processes, this reads the process'
+                        // cgroup from procfs, which may fail:
+                        return Err(format!(
+                            "Failed to determine whether the nspawn process has exited: {:?}",
+                            e
+                        ));
                     }
                 }
             }
-        });
+            debug!(
+                "Child is dead and exited with status: {:?}, code: {:?}",
+                exit_status,
+                exit_status.flatten().map(|es| es.code())
+            );
 
-        // TODO: it'd be nice if this didn't have to be
-        // sequential. But using tokio's JoinSet we get lifetime
-        // issues here, as .spawn() requires a 'static borrow of the
-        // rendezvous proxies.
-        let mut rendezvous_proxy_addrs = vec![];
-        for proxy in ssh_rendezvous_proxies.iter() {
-            match proxy.public_addr(Duration::from_secs(5)).await {
-                Some((hostname, port)) => {
-                    rendezvous_proxy_addrs.push(
-                        rest_api::JobSessionConnectionInfo::RendezvousSSH {
-                            hostname,
-                            port,
-                            host_key_fingerprints: vec![],
-                        },
-                    );
-                }
-                None => {
-                    warn!("Rendezvous proxy did not provide public address before timeout.");
-                }
+            // Without `--boot`, systemd-nspawn exits with the exit code of
+            // the container's init process. The status of a reattached
+            // process is unknown:
+            job.exit_status = exit_status.flatten().map(|es| rest_api::JobExitStatus {
+                code: es.code(),
+                signal: es.signal(),
+            });
+
+            // Stop the exit watcher. We must still hold the child lock here,
+            // such that it doesn't attempt to stop this job again:
+            if let Some(exit_watcher) = job.exit_watcher.take() {
+                exit_watcher.abort();
             }
         }
 
-        this.connector
-            .post_job_state(
-                msg.job_id,
-                rest_api::JobState::Ready {
-                    connection_info: rendezvous_proxy_addrs,
-                    status_message: None,
-                },
-            )
-            .await;
-
-        *current_job_lg = Some(NspawnRunnerJob {
-            job_id: msg.job_id,
-            _environment_id: msg.environment_id,
-            environment_config: environment_cfg.clone(),
-            ssh_keys: msg.ssh_keys,
-            nspawn_proc: child,
-            console_streamer_handle: console_streamer,
-            console_streamer_cmd_chan: streamer_chan_tx,
-            root_fs_mountpoint: Some(root_fs_mountpoint),
-            ssh_rendezvous_proxies,
-            control_socket,
-            zfs_root_fs,
-        });
-    }
+        // Read the remaining console output and wait for it to be posted to
+        // the coordinator:
+        Self::stop_console(job).await;
 
-    async fn stop_job(this: &Arc<Self>, msg: sse_api::StopJobMessage) {
-        // Temporary workaround to avoid deleting the container root file
-        // system. In the future, this should be an option passed from by the
-        // coordinator.
-        const MSG_DELETE_DATA: bool = false;
-
-        // This method must not block for long periods of time. We're provided
-        // an &Arc<Self> to be able to launch async tasks, while returning
-        // immediately. For now, we assume that all actions performed here are
-        // reasonably fast, and we thus only return once the container is
-        // started.
-
-        // First, grab the `current_job` mutex. If there is already another job
-        // running, we abort. We remove take the job object from the option, but
-        // to prevent another task to race with this method, hold the lock guard
-        // til the very end:
-        let mut current_job_lg = this.current_job.lock().await;
-        match *current_job_lg {
-            Some(ref job) => {
-                if job.job_id != msg.job_id {
-                    this.connector
-                        .post_job_state(
-                            msg.job_id,
-                            rest_api::JobState::Failed {
-                                status_message: Some(format!(
-                                    "Cannot stop job {:?} on board {:?}, not running!",
-                                    msg.job_id, this.config.board_id,
-                                )),
-                            },
-                        )
-                        .await;
-                    return;
-                }
-            }
+        Ok(())
+    }
 
-            None => {
-                this.connector
-                    .post_job_state(
-                        msg.job_id,
-                        rest_api::JobState::Failed {
-                            status_message: Some(format!(
-                                "Cannot stop job {:?} on board {:?}, not running!",
-                                msg.job_id, this.config.board_id,
-                            )),
-                        },
-                    )
-                    .await;
-                return;
+    async fn cleanup(
+        &self,
+        job: NspawnRunnerJob,
+        retention: &sse_api::JobDataRetention,
+    ) -> Result<(), String> {
+        // Remove the container's firewall rules and release its addresses,
+        // even if releasing the root file system fails:
+        let network_res = match (
+            &job.metadata.environment_config.host_network,
+            &job.metadata.network,
+        ) {
+            (Some(host_network_cfg), Some(network)) => {
+                host_network_cfg.release(job.metadata.job_id, network).await
             }
+            _ => Ok(()),
         };
 
-        // Take the job object, such that we own it:
-        let job = current_job_lg.take().unwrap();
+        // Release the container's root file system, retaining its data as
+        // requested. Retained file systems are eventually destroyed by the
+        // retention reaper:
+        Self::root_fs_provider(
+            job.metadata.environment_id,
+            &job.metadata.environment_config,
+        )?
+        .release(
+            &job.metadata.root_fs_mountpoint,
+            &job.metadata.root_fs_volume,
+            retention,
+        )
+        .await?;
+        network_res?;
 
-        // The requested job is currently running, procede to stop it.
-        // Transition into the shutdown state:
-        this.connector
-            .post_job_state(
-                msg.job_id,
-                rest_api::JobState::Stopping {
-                    status_message: None,
-                },
-            )
-            .await;
-
-        // First, instruct the container to shut down. We attempt a graceful
-        // shutdown by sending a SIGTERM to the systemd-nspawn process, which
-        // should send a SIGRTMIN+3 to the container's PID1, which will initiate
-        // an orderly shutdown:
-        let mut child = job.nspawn_proc.lock().await;
-        if let Some(pid) = child.id() {
-            debug!("Sending SIGTERM to nspawn process...");
-            let _ = nix::sys::signal::kill(
-                nix::unistd::Pid::from_raw(pid.try_into().unwrap()),
-                nix::sys::signal::Signal::SIGTERM,
+        // All resources have been released, forget about this job:
+        let job_state_dir = self.job_state_dir(job.metadata.job_id);
+        tokio::fs::remove_dir_all(&job_state_dir)
+            .await
+            .map_err(|e| {
+                format!(
+                    "Failed to remove job state directory {:?}: {:?}",
+                    job_state_dir, e
+                )
+            })
+    }
+
+    fn network_config(&self, job: &NspawnRunnerJob) -> Option<runner_puppet::NetworkConfig> {
+        let environment_config = &job.metadata.environment_config;
+        if let (Some(host_network_cfg), Some(network)) =
+            (&environment_config.host_network, &job.metadata.network)
+        {
+            return Some(
+                host_network_cfg
+                    .puppet_config(executor::job_hostname(job.metadata.job_id), network),
             );
         }
 
-        // Now, wait for the container to shut down, or until the shutdown
-        // timeout expires:
-        debug!(
-            "Waiting on process exit or shutdown timeout ({} secs)",
-            job.environment_config.shutdown_timeout
-        );
-        tokio::select! {
-            _ = tokio::time::sleep(Duration::from_secs(job.environment_config.shutdown_timeout)) => {},
-            _ = child.wait() => {}
-        };
-
-        // Attempt to get the process' exit status or, if that doesn't succeed,
-        // kill it, in a loop:
-        debug!("Process exited OR timeout fired. Check exit code or kill child in a loop.");
-        let mut exit_status = None;
-        while exit_status.is_none() {
-            match child.try_wait() {
-                Ok(Some(es)) => {
-                    debug!("Child exited.");
-                    exit_status = Some(es);
+        Some(runner_puppet::NetworkConfig {
+            hostname: executor::job_hostname(job.metadata.job_id),
+            interface: Some("host0".to_string()),
+            ipv4: environment_config.ipv4_network.as_ref().map(|ip4| {
+                runner_puppet::Ipv4NetworkConfig {
+                    address: ip4.address,
+                    prefix_length: ip4.prefix_length,
+                    gateway: ip4.gateway,
+                    nameservers: ip4.nameservers.clone(),
                 }
-                Ok(None) => child.kill().await.unwrap(),
-                Err(e) => {
-                    panic!("Error while killing nspawn process: {:?}", e);
+            }),
+            ipv6: environment_config.ipv6_network.as_ref().map(|ip6| {
+                runner_puppet::Ipv6NetworkConfig {
+                    address: ip6.address,
+                    prefix_length: ip6.prefix_length,
+                    gateway: ip6.gateway,
+                    nameservers: ip6.nameservers.clone(),
                 }
-            }
-        }
-        debug!(
-            "Child is dead and exited with status: {:?}, code: {:?}",
-            exit_status,
-            exit_status.map(|es| es.code())
-        );
+            }),
+        })
+    }
+
+    async fn update_ssh_keys(
+        &self,
+        job: &mut NspawnRunnerJob,
+        ssh_keys: &[String],
+        user_accounts: &[runner_puppet::UserAccount],
+    ) -> Result<(), String> {
+        // The authorized keys file written while provisioning is not
+        // updated, as the running container controls its root file system
+        // (and may, for instance, have replaced its parent directories by
+        // symlinks). Updates only reach the container through the puppet.
+        job.metadata.ssh_keys = ssh_keys.to_vec();
+        job.metadata.user_accounts = user_accounts.to_vec();
+        self.update_metadata(job.metadata.job_id, |metadata| {
+            metadata.ssh_keys = ssh_keys.to_vec();
+            metadata.user_accounts = user_accounts.to_vec();
+        })
+        .await
+    }
 
-        // The process is dead. Destroy the control socket:
-        job.control_socket.shutdown().await.unwrap();
+    async fn resource_usage(&self, job: &NspawnRunnerJob) -> Option<rest_api::JobResourceUsage> {
+        Some(job.usage.sample(&job.metadata).await)
+    }
 
-        // Instruct the log streamer to shutdown and wait for the last console
-        // logs to be posted to the coordinator.
-        debug!("Requesting console streamer to shut down.");
-        job.console_streamer_cmd_chan
-            .send(ConsoleStreamerCommand::Shutdown)
-            .await
-            .expect("Console streamer task has quit before receiving shutdown signal!");
-        job.console_streamer_handle.await.unwrap();
-        debug!("Console streamer has shut down.");
-
-        // Shut down all rendezvous proxy clients:
-        for proxy in job.ssh_rendezvous_proxies {
-            if let Err(e) = proxy.shutdown().await {
-                warn!("Error while shutting down rendezvous proxy client: {:?}", e);
+    fn exit_status(&self, job: &NspawnRunnerJob) -> Option<rest_api::JobExitStatus> {
+        job.exit_status
+    }
+
+    fn allocation_message(&self, job: &NspawnRunnerJob) -> Option<String> {
+        job.allocation_message.clone()
+    }
+
+    fn console_offset(&self, job: &NspawnRunnerJob) -> Option<usize> {
+        job.console_streamer
+            .as_ref()
+            .map(|console_streamer| console_streamer.acked_offset())
+    }
+
+    async fn recover(&self) -> Result<Vec<RecoveredJob<NspawnRunnerJob>>, String> {
+        let jobs_dir = self.config.state_dir.join("jobs");
+        let mut dir_entries = match tokio::fs::read_dir(&jobs_dir).await {
+            Ok(dir_entries) => dir_entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => {
+                return Err(format!(
+                    "Failed to read jobs directory {:?}: {:?}",
+                    jobs_dir, e
+                ))
             }
-        }
+        };
 
-        // Unmount the container's root file system:
-        if let Some(mountpoint) = job.root_fs_mountpoint {
-            match Command::new("umount").args(&[mountpoint]).output().await {
-                Ok(std::process::Output {
-                    status,
-                    stdout,
-                    stderr,
-                }) => {
-                    if !status.success() {
-                        this.connector
-                            .post_job_state(
-                                msg.job_id,
-                                rest_api::JobState::Failed {
-                                    status_message: Some(format!(
-                                        "Unmounting root filesystem failed with exit-status \
-                                         {:?}. Stdout: {}, Stderr: {}",
-                                        status.code(),
-                                        String::from_utf8_lossy(&stdout),
-                                        String::from_utf8_lossy(&stderr)
-                                    )),
-                                },
-                            )
-                            .await;
-                        return;
-                    }
-                }
+        let mut recovered_jobs = vec![];
+        while let Some(dir_entry) = dir_entries
+            .next_entry()
+            .await
+            .map_err(|e| format!("Failed to read jobs directory {:?}: {:?}", jobs_dir, e))?
+        {
+            let metadata_path = dir_entry.path().join("metadata.json");
+            let metadata: NspawnRunnerJobMetadata = match tokio::fs::read(&metadata_path)
+                .await
+                .map_err(|e| format!("{:?}", e))
+                .and_then(|serialized| {
+                    serde_json::from_slice(&serialized).map_err(|e| format!("{:?}", e))
+                }) {
+                Ok(metadata) => metadata,
                 Err(e) => {
-                    this.connector
-                        .post_job_state(
-                            msg.job_id,
-                            rest_api::JobState::Failed {
-                                status_message: Some(format!(
-                                    "Unmounting root filesystem failed with error: {:?}",
-                                    e
-                                )),
-                            },
-                        )
-                        .await;
-                    return;
+                    warn!("Skipping job metadata {:?}: {}", metadata_path, e);
+                    continue;
                 }
-            }
-        }
+            };
 
-        if MSG_DELETE_DATA {
-            // If we've created a ZFS file system for this container, destroy it:
-            if let Some(zfs_fs) = job.zfs_root_fs {
-                if let Err(emsg) = this.destroy_zfs_root(&zfs_fs).await {
-                    this.connector
-                        .post_job_state(
-                            msg.job_id,
-                            rest_api::JobState::Failed {
-                                status_message: Some(emsg),
-                            },
-                        )
-                        .await;
-                    return;
-                }
-            }
-        }
+            let nspawn_proc = match (&metadata.unit_name, metadata.nspawn_pid) {
+                (Some(unit_name), Some(pid)) => match process_in_unit(pid, unit_name) {
+                    Ok(true) => Some(NspawnProcess::Reattached {
+                        pid,
+                        unit_name: unit_name.clone(),
+                    }),
+                    Ok(false) => None,
+                    Err(e) => {
+                        warn!(
+                            "Failed to determine whether job {:?} is running: {:?}",
+                            metadata.job_id, e
+                        );
+                        None
+                    }
+                },
+                _ => None,
+            };
 
-        // Manually drop the lock guard here, to ensure that it stays in scope
-        // til the end of this function:
-        core::mem::drop(current_job_lg);
+            info!(
+                "Found job {:?} of previous runner instance, running: {}",
+                metadata.job_id,
+                nspawn_proc.is_some()
+            );
 
-        // Mark job as finished:
-        this.connector
-            .post_job_state(
-                msg.job_id,
-                rest_api::JobState::Finished {
-                    status_message: None,
+            recovered_jobs.push(RecoveredJob {
+                job_id: metadata.job_id,
+                ssh_keys: metadata.ssh_keys.clone(),
+                user_accounts: metadata.user_accounts.clone(),
+                ssh_rendezvous_servers: metadata.ssh_rendezvous_servers.clone(),
+                running: nspawn_proc.is_some(),
+                job: NspawnRunnerJob {
+                    metadata,
+                    nspawn_proc: nspawn_proc.map(|proc| Arc::new(Mutex::new(proc))),
+                    exit_watcher: None,
+                    usage: Arc::new(usage::UsageTracker::default()),
+                    usage_reporter: None,
+                    device_watcher: None,
+                    exit_status: None,
+                    console_streamer: None,
+                    console_followers: vec![],
+                    allocation_message: None,
                 },
-            )
-            .await;
-    }
-}
-
-#[async_trait]
-impl control_socket::Runner for NspawnRunner {
-    async fn ssh_keys(&self, tgt_job_id: Uuid) -> Option<Vec<String>> {
-        match *self.current_job.lock().await {
-            None => None,
-            Some(NspawnRunnerJob {
-                ref job_id,
-                ref ssh_keys,
-                ..
-            }) if *job_id == tgt_job_id => Some(ssh_keys.clone()),
-            Some(_) => None,
+            });
         }
+
+        Ok(recovered_jobs)
     }
 
-    async fn network_config(
+    async fn reattach(
         &self,
-        tgt_job_id: Uuid,
-    ) -> Option<treadmill_rs::api::runner_puppet::NetworkConfig> {
-        match *self.current_job.lock().await {
-            None => None,
-            Some(NspawnRunnerJob {
-                ref job_id,
-                ref environment_config,
-                ..
-            }) if *job_id == tgt_job_id => {
-                let hostname = format!("job-{}", format!("{}", job_id).split_at(10).0);
-                Some(treadmill_rs::api::runner_puppet::NetworkConfig {
-                    hostname,
-                    interface: Some("host0".to_string()),
-                    ipv4: environment_config.ipv4_network.as_ref().map(|ip4| {
-                        treadmill_rs::api::runner_puppet::Ipv4NetworkConfig {
-                            address: ip4.address,
-                            prefix_length: ip4.prefix_length,
-                            gateway: ip4.gateway,
-                            nameservers: ip4.nameservers.clone(),
-                        }
-                    }),
-                    ipv6: environment_config.ipv6_network.as_ref().map(|ip6| {
-                        treadmill_rs::api::runner_puppet::Ipv6NetworkConfig {
-                            address: ip6.address,
-                            prefix_length: ip6.prefix_length,
-                            gateway: ip6.gateway,
-                            nameservers: ip6.nameservers.clone(),
-                        }
-                    }),
-                })
-            }
-            Some(_) => None,
-        }
+        driver: &Arc<JobDriver<Self>>,
+        job: &mut NspawnRunnerJob,
+    ) -> Result<(), String> {
+        let Some(nspawn_proc) = job.nspawn_proc.clone() else {
+            return Err(format!(
+                "Cannot reattach to job {:?}, container is not running.",
+                job.metadata.job_id
+            ));
+        };
+
+        // Resume streaming the console where the coordinator left off:
+        self.start_console(driver, job).await?;
+
+        job.exit_watcher = Some(Self::spawn_exit_watcher(
+            driver,
+            job.metadata.job_id,
+            nspawn_proc,
+        ));
+        job.usage_reporter = Some(self.spawn_usage_reporter(driver, job));
+        job.device_watcher = hotplug::spawn_device_watcher(driver, &job.metadata);
+
+        Ok(())
     }
 }
 
@@ -1124,34 +1419,114 @@ async fn main() {
 
     let config_str = std::fs::read_to_string(args.config_file).unwrap();
     let config: NspawnRunnerConfig = toml::from_str(&config_str).unwrap();
+    let retention_interval = config.retention_interval;
+
+    if let Some(source) = args.import_image {
+        // Required by clap:
+        let environment_id = args.environment.unwrap();
+        match NspawnRunner::new(config)
+            .import_image(environment_id, args.image_version, &source)
+            .await
+        {
+            Ok(version) => info!(
+                "Imported image version {:?} of environment {:?}",
+                version, environment_id
+            ),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if args.list_images {
+        let catalog = match NspawnRunner::new(config).load_catalog().await {
+            Ok(catalog) => catalog,
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        for (environment_id, images) in catalog.environments.iter() {
+            let latest = catalog.latest(*environment_id).map(|image| &image.version);
+            for image in images {
+                println!(
+                    "{}\t{}\t{}\t{:?}{}",
+                    environment_id,
+                    image.version,
+                    image.imported_at,
+                    image.source,
+                    if Some(&image.version) == latest {
+                        "\t(latest)"
+                    } else {
+                        ""
+                    }
+                );
+            }
+        }
+    } else if args.gc {
+        // Refuse to collect the resources of jobs a live runner is starting:
+        let _state_dir_lock = match gc::lock_state_dir(&config.state_dir) {
+            Ok(lock) => lock,
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        };
+
+        // All actions and errors are logged by the garbage collector:
+        let report = NspawnRunner::new(config).collect_garbage().await;
+        if !report.errors.is_empty() {
+            std::process::exit(1);
+        }
+    } else if let Some(test_env_uuid) = args.test_env {
+        let _state_dir_lock = gc::lock_state_dir(&config.state_dir).unwrap();
 
-    if let Some(test_env_uuid) = args.test_env {
         let mut connector_opt = None;
-        let _nspawn_runner = Arc::new_cyclic(|weak_runner| {
+        let nspawn_runner = Arc::new_cyclic(|weak_runner| {
             let connector = Arc::new(DummyRunnerConnector::new(
                 config.board_id,
                 test_env_uuid,
                 weak_runner.clone(),
             ));
             connector_opt = Some(connector.clone());
-            NspawnRunner::new(connector, config)
+            JobDriver::new(connector, NspawnRunner::new(config))
         });
         let connector = connector_opt.take().unwrap();
+        JobDriver::recover(&nspawn_runner).await;
+        nspawn_runner.executor().collect_garbage().await;
+        retention::spawn_reaper(
+            nspawn_runner.clone(),
+            Duration::from_secs(retention_interval),
+        );
+        prewarm::spawn_warmer(nspawn_runner.clone());
         connector.run().await;
     } else {
+        // Held for the runner's entire lifetime:
+        let _state_dir_lock = gc::lock_state_dir(&config.state_dir).unwrap();
+
+        let outbox = JobStateOutbox::open(config.state_dir.join("outbox"))
+            .expect("Failed to open job state outbox");
+
         let mut connector_opt = None;
-        let _nspawn_runner = Arc::new_cyclic(|weak_runner| {
+        let nspawn_runner = Arc::new_cyclic(|weak_runner| {
             let connector = Arc::new(SSERunnerConnector::new(
                 config.coordinator_base_url.clone(),
                 config.board_id,
                 weak_runner.clone(),
                 Duration::from_secs(config.keepalive_timeout),
                 Duration::from_secs(config.reconnect_wait),
+                outbox,
             ));
             connector_opt = Some(connector.clone());
-            NspawnRunner::new(connector, config)
+            JobDriver::new(connector, NspawnRunner::new(config))
         });
         let connector = connector_opt.take().unwrap();
+        JobDriver::recover(&nspawn_runner).await;
+        nspawn_runner.executor().collect_garbage().await;
+        retention::spawn_reaper(
+            nspawn_runner.clone(),
+            Duration::from_secs(retention_interval),
+        );
+        prewarm::spawn_warmer(nspawn_runner.clone());
         connector.run().await;
     }
 }
@@ -0,0 +1,795 @@
+//! Runner-managed host networking of job containers.
+//!
+//! Environments with a `host_network` configuration are given a veth pair
+//! per job, whose host side is configured by the runner once the container
+//! has been launched: it is either attached to an existing bridge, or
+//! assigned the host's address of a point-to-point subnet. Container addresses
+//! (or, in point-to-point mode, subnets) are allocated from a configured pool,
+//! and traffic of the container may be masqueraded behind the host's
+//! addresses. The puppet's network configuration is derived from this
+//! allocation.
+//!
+//! Addresses are leased by creating a file named after the address in the
+//! lease directory, which may be shared by multiple runners on the same host.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use uuid::Uuid;
+
+use treadmill_rs::api::runner_puppet;
+use treadmill_rs::rollback::Rollback;
+
+use crate::egress::{self, NspawnRunnerEnvironmentEgressConfig};
+use crate::gc::GcReport;
+use crate::NspawnRunner;
+
+/// Maximum number of addresses to attempt to lease from a pool, bounding the
+/// search in large IPv6 pools.
+const MAX_LEASE_CANDIDATES: u128 = 65536;
+
+/// Time to wait for `systemd-nspawn` to create the host side of the veth
+/// pair after the container has been launched.
+const HOST_INTERFACE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const HOST_INTERFACE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn default_ifname_container() -> String {
+    "host0".to_string()
+}
+
+fn default_lease_dir() -> PathBuf {
+    PathBuf::from("/var/lib/treadmill/ipam")
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(bound(deserialize = "A: Deserialize<'de>"))]
+pub struct NspawnRunnerHostNetworkPoolConfig<A> {
+    /// Network to allocate container addresses, or point-to-point subnets,
+    /// from.
+    network: A,
+    prefix_length: u8,
+    /// Prefix length of every job's point-to-point subnet. Defaults to
+    /// subnets of four addresses. Unused in bridged mode.
+    #[serde(default)]
+    subnet_prefix_length: Option<u8>,
+    /// Gateway of the bridged network, typically the bridge's address. In
+    /// point-to-point mode, the host's address is used instead.
+    #[serde(default)]
+    gateway: Option<A>,
+    #[serde(default)]
+    nameservers: Vec<A>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NspawnRunnerEnvironmentHostNetworkConfig {
+    /// Name of the veth interface within the container.
+    #[serde(default = "default_ifname_container")]
+    ifname_container: String,
+    /// Bridge to attach the host side of the veth pair to. Without a bridge,
+    /// every job is assigned a point-to-point subnet instead.
+    #[serde(default)]
+    bridge: Option<String>,
+    #[serde(default)]
+    ipv4: Option<NspawnRunnerHostNetworkPoolConfig<Ipv4Addr>>,
+    #[serde(default)]
+    ipv6: Option<NspawnRunnerHostNetworkPoolConfig<Ipv6Addr>>,
+    /// Masquerade traffic of the container leaving through other interfaces
+    /// of the host.
+    #[serde(default)]
+    nat: bool,
+    /// Restrict the destinations the container may reach. Not supported in
+    /// combination with a bridge.
+    #[serde(default)]
+    egress: Option<NspawnRunnerEnvironmentEgressConfig>,
+    /// Directory of address leases, shared by all runners allocating from
+    /// the same pools.
+    #[serde(default = "default_lease_dir")]
+    lease_dir: PathBuf,
+}
+
+/// Address of a container, as allocated from a pool.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AddressLease<A> {
+    pub address: A,
+    pub prefix_length: u8,
+    pub gateway: Option<A>,
+    /// Address assigned to the host side of the veth pair, in point-to-point
+    /// mode.
+    pub host_address: Option<A>,
+}
+
+/// Host networking resources of a job, recorded in its metadata.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HostNetworkAllocation {
+    pub ifname_host: String,
+    pub ifname_container: String,
+    pub ipv4: Option<AddressLease<Ipv4Addr>>,
+    pub ipv6: Option<AddressLease<Ipv6Addr>>,
+}
+
+/// Contents of a lease file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct LeaseRecord {
+    board_id: Uuid,
+    job_id: Uuid,
+}
+
+/// Arithmetic on the addresses of a pool.
+trait PoolAddress: Copy + PartialEq + std::fmt::Display {
+    const BITS: u8;
+    fn to_bits(self) -> u128;
+    fn from_bits(bits: u128) -> Self;
+}
+
+impl PoolAddress for Ipv4Addr {
+    const BITS: u8 = 32;
+    fn to_bits(self) -> u128 {
+        u32::from(self) as u128
+    }
+    fn from_bits(bits: u128) -> Self {
+        Ipv4Addr::from(bits as u32)
+    }
+}
+
+impl PoolAddress for Ipv6Addr {
+    const BITS: u8 = 128;
+    fn to_bits(self) -> u128 {
+        u128::from(self)
+    }
+    fn from_bits(bits: u128) -> Self {
+        Ipv6Addr::from(bits)
+    }
+}
+
+/// Number of addresses in a network of the given prefix length, saturating
+/// for an entire IPv6 address space.
+fn network_size<A: PoolAddress>(prefix_length: u8) -> u128 {
+    1u128
+        .checked_shl((A::BITS - prefix_length) as u32)
+        .unwrap_or(u128::MAX)
+}
+
+/// Name of the host side of a job's veth pair, within the 15 characters
+/// permitted for interface names.
+pub fn host_ifname(job_id: Uuid) -> String {
+    format!("tm-{}", &job_id.simple().to_string()[..12])
+}
+
+/// Name of the nftables table holding a job's rules.
+pub fn nft_table_name(job_id: Uuid) -> String {
+    format!("treadmill_{}", job_id.simple())
+}
+
+fn lease_path(lease_dir: &Path, address: IpAddr) -> PathBuf {
+    lease_dir.join(address.to_string())
+}
+
+/// Lease an address by creating its lease file. Returns `false` if the
+/// address is already leased.
+async fn try_lease(
+    lease_dir: &Path,
+    address: IpAddr,
+    record: &LeaseRecord,
+) -> Result<bool, String> {
+    let path = lease_path(lease_dir, address);
+    let res: std::io::Result<bool> = async {
+        tokio::fs::create_dir_all(lease_dir).await?;
+
+        // Write the record to a file private to this job first, and link it
+        // into place. Linking fails if the address is already leased, and
+        // never exposes a partially written lease to other runners:
+        let tmp_path = lease_dir.join(format!(".{}.{}.tmp", address, record.job_id));
+        let serialized = serde_json::to_vec(record)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        let link_res = async {
+            file.write_all(&serialized).await?;
+            file.sync_all().await?;
+            match tokio::fs::hard_link(&tmp_path, &path).await {
+                Ok(()) => Ok(true),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(false),
+                Err(e) => Err(e),
+            }
+        }
+        .await;
+        tokio::fs::remove_file(&tmp_path).await?;
+        link_res
+    }
+    .await;
+
+    res.map_err(|e| format!("Failed to create lease {:?}: {:?}", path, e))
+}
+
+/// Remove a lease, provided that it is held by the given job.
+async fn release_lease(lease_dir: &Path, address: IpAddr, job_id: Uuid) -> Result<(), String> {
+    let path = lease_path(lease_dir, address);
+    let record: LeaseRecord = match tokio::fs::read(&path).await {
+        Ok(serialized) => serde_json::from_slice(&serialized)
+            .map_err(|e| format!("Failed to parse lease {:?}: {:?}", path, e))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("Failed to read lease {:?}: {:?}", path, e)),
+    };
+    if record.job_id != job_id {
+        warn!(
+            "Lease {:?} is held by job {:?}, not releasing it for job {:?}",
+            path, record.job_id, job_id
+        );
+        return Ok(());
+    }
+
+    tokio::fs::remove_file(&path)
+        .await
+        .map_err(|e| format!("Failed to remove lease {:?}: {:?}", path, e))
+}
+
+/// Lease the first available address of a pool.
+async fn lease_from_pool<A: PoolAddress + Into<IpAddr>>(
+    pool: &NspawnRunnerHostNetworkPoolConfig<A>,
+    bridged: bool,
+    lease_dir: &Path,
+    record: &LeaseRecord,
+) -> Result<AddressLease<A>, String> {
+    if pool.prefix_length > A::BITS {
+        return Err(format!(
+            "Invalid prefix length {} of pool {}",
+            pool.prefix_length, pool.network
+        ));
+    }
+    let pool_size = network_size::<A>(pool.prefix_length);
+    let network = pool.network.to_bits() & !(pool_size - 1);
+
+    let candidates: Vec<AddressLease<A>> = if bridged {
+        // Skip the network's first and last address:
+        (1..pool_size.saturating_sub(1))
+            .take(MAX_LEASE_CANDIDATES as usize)
+            .map(|offset| A::from_bits(network + offset))
+            .filter(|address| Some(*address) != pool.gateway)
+            .map(|address| AddressLease {
+                address,
+                prefix_length: pool.prefix_length,
+                gateway: pool.gateway,
+                host_address: None,
+            })
+            .collect()
+    } else {
+        // Every subnet holds the network address, the host's and the
+        // container's address:
+        let subnet_prefix_length = pool.subnet_prefix_length.unwrap_or(A::BITS - 2);
+        if subnet_prefix_length < pool.prefix_length || subnet_prefix_length > A::BITS - 2 {
+            return Err(format!(
+                "Invalid subnet prefix length {} for pool {}/{}",
+                subnet_prefix_length, pool.network, pool.prefix_length
+            ));
+        }
+        let subnet_size = network_size::<A>(subnet_prefix_length);
+        (0..pool_size / subnet_size)
+            .take(MAX_LEASE_CANDIDATES as usize)
+            .map(|subnet| {
+                let base = network + subnet * subnet_size;
+                AddressLease {
+                    address: A::from_bits(base + 2),
+                    prefix_length: subnet_prefix_length,
+                    gateway: Some(A::from_bits(base + 1)),
+                    host_address: Some(A::from_bits(base + 1)),
+                }
+            })
+            .collect()
+    };
+
+    for candidate in candidates {
+        if try_lease(lease_dir, candidate.address.into(), record).await? {
+            return Ok(candidate);
+        }
+    }
+
+    Err(format!(
+        "No addresses left in pool {}/{}",
+        pool.network, pool.prefix_length
+    ))
+}
+
+async fn run_command(program: &str, args: &[&str], stdin: Option<&str>) -> Result<(), String> {
+    debug!("Executing {:?} with arguments {:?}", program, args);
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Running {:?} failed: {:?}", program, e))?;
+
+    let mut child_stdin = child.stdin.take().unwrap();
+    if let Some(stdin) = stdin {
+        child_stdin
+            .write_all(stdin.as_bytes())
+            .await
+            .map_err(|e| format!("Writing to {:?} failed: {:?}", program, e))?;
+    }
+    std::mem::drop(child_stdin);
+
+    match child.wait_with_output().await {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => Err(format!(
+            "{} {} failed with exit-status {:?}. Stderr: {}",
+            program,
+            args.join(" "),
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr)
+        )),
+        Err(e) => Err(format!("Running {:?} failed: {:?}", program, e)),
+    }
+}
+
+/// Apply an nftables ruleset.
+pub async fn nft_apply(ruleset: &str) -> Result<(), String> {
+    run_command("nft", &["-f", "-"], Some(ruleset)).await
+}
+
+/// Delete a job's nftables table, if it exists.
+pub async fn nft_delete_table(job_id: Uuid) -> Result<(), String> {
+    let table = nft_table_name(job_id);
+    // Declaring the table first makes deleting it succeed if it is missing:
+    nft_apply(&format!(
+        "table inet {table}\ndelete table inet {table}\n",
+        table = table
+    ))
+    .await
+}
+
+fn nat_ruleset(job_id: Uuid, allocation: &HostNetworkAllocation) -> String {
+    let mut rules = String::new();
+    if let Some(ref ipv4) = allocation.ipv4 {
+        rules.push_str(&format!(
+            "    ip saddr {} oifname != \"{}\" masquerade\n",
+            ipv4.address, allocation.ifname_host
+        ));
+    }
+    if let Some(ref ipv6) = allocation.ipv6 {
+        rules.push_str(&format!(
+            "    ip6 saddr {} oifname != \"{}\" masquerade\n",
+            ipv6.address, allocation.ifname_host
+        ));
+    }
+
+    format!(
+        "table inet {} {{\n  \
+           chain postrouting {{\n    \
+             type nat hook postrouting priority srcnat; policy accept;\n\
+         {}  }}\n\
+         }}\n",
+        nft_table_name(job_id),
+        rules
+    )
+}
+
+async fn wait_for_interface(ifname: &str) -> Result<(), String> {
+    let path = Path::new("/sys/class/net").join(ifname);
+    let start = Instant::now();
+    while tokio::fs::metadata(&path).await.is_err() {
+        if start.elapsed() > HOST_INTERFACE_TIMEOUT {
+            return Err(format!(
+                "Interface {:?} did not appear within {:?}",
+                ifname, HOST_INTERFACE_TIMEOUT
+            ));
+        }
+        tokio::time::sleep(HOST_INTERFACE_POLL_INTERVAL).await;
+    }
+    Ok(())
+}
+
+impl NspawnRunnerEnvironmentHostNetworkConfig {
+    /// Lease the container's addresses. The leases are released when the
+    /// rollback is unwound, which also happens if leasing fails.
+    pub async fn allocate(
+        &self,
+        board_id: Uuid,
+        job_id: Uuid,
+        rollback: &mut Rollback,
+    ) -> Result<HostNetworkAllocation, String> {
+        let record = LeaseRecord { board_id, job_id };
+        let bridged = self.bridge.is_some();
+        if bridged && self.egress.is_some() {
+            return Err(format!(
+                "Cannot start job {:?}: egress policies are not supported for \
+                 containers attached to a bridge",
+                job_id
+            ));
+        }
+
+        let mut allocation = HostNetworkAllocation {
+            ifname_host: host_ifname(job_id),
+            ifname_container: self.ifname_container.clone(),
+            ipv4: None,
+            ipv6: None,
+        };
+
+        if let Some(ref pool) = self.ipv4 {
+            let lease_res = lease_from_pool(pool, bridged, &self.lease_dir, &record).await;
+            let lease = rollback.check(lease_res).await?;
+            let (lease_dir, address) = (self.lease_dir.clone(), lease.address.into());
+            rollback.push(format!("lease address {}", address), async move {
+                release_lease(&lease_dir, address, job_id).await
+            });
+            allocation.ipv4 = Some(lease);
+        }
+
+        if let Some(ref pool) = self.ipv6 {
+            let lease_res = lease_from_pool(pool, bridged, &self.lease_dir, &record).await;
+            let lease = rollback.check(lease_res).await?;
+            let (lease_dir, address) = (self.lease_dir.clone(), lease.address.into());
+            rollback.push(format!("lease address {}", address), async move {
+                release_lease(&lease_dir, address, job_id).await
+            });
+            allocation.ipv6 = Some(lease);
+        }
+
+        Ok(allocation)
+    }
+
+    /// Configure the host side of a job's veth pair, once the container has
+    /// been launched.
+    pub async fn configure_host(
+        &self,
+        job_id: Uuid,
+        allocation: &HostNetworkAllocation,
+    ) -> Result<(), String> {
+        let ifname = allocation.ifname_host.as_str();
+        wait_for_interface(ifname).await?;
+
+        if let Some(ref bridge) = self.bridge {
+            run_command(
+                "ip",
+                &["link", "set", "dev", ifname, "master", bridge],
+                None,
+            )
+            .await?;
+        } else {
+            let host_addresses = [
+                allocation.ipv4.as_ref().and_then(|lease| {
+                    lease
+                        .host_address
+                        .map(|address| format!("{}/{}", address, lease.prefix_length))
+                }),
+                allocation.ipv6.as_ref().and_then(|lease| {
+                    lease
+                        .host_address
+                        .map(|address| format!("{}/{}", address, lease.prefix_length))
+                }),
+            ];
+            for host_address in host_addresses.iter().flatten() {
+                run_command("ip", &["address", "add", host_address, "dev", ifname], None).await?;
+            }
+        }
+
+        // Enforce the egress policy before the interface is brought up:
+        if let Some(ref egress_cfg) = self.egress {
+            nft_apply(&egress_cfg.ruleset(job_id, allocation).await?).await?;
+        }
+
+        run_command("ip", &["link", "set", "dev", ifname, "up"], None).await?;
+
+        if self.nat {
+            if allocation.ipv4.is_some()
+                && tokio::fs::read_to_string("/proc/sys/net/ipv4/ip_forward")
+                    .await
+                    .map(|forward| forward.trim() == "0")
+                    .unwrap_or(false)
+            {
+                warn!(
+                    "IPv4 forwarding is disabled, NAT of job {:?} will not work",
+                    job_id
+                );
+            }
+            nft_apply(&nat_ruleset(job_id, allocation)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Puppet network configuration of a container with the given
+    /// allocation.
+    pub fn puppet_config(
+        &self,
+        hostname: String,
+        allocation: &HostNetworkAllocation,
+    ) -> runner_puppet::NetworkConfig {
+        runner_puppet::NetworkConfig {
+            hostname,
+            interface: Some(allocation.ifname_container.clone()),
+            ipv4: allocation
+                .ipv4
+                .as_ref()
+                .map(|lease| runner_puppet::Ipv4NetworkConfig {
+                    address: lease.address,
+                    prefix_length: lease.prefix_length,
+                    gateway: lease.gateway,
+                    nameservers: self
+                        .ipv4
+                        .as_ref()
+                        .map(|pool| pool.nameservers.clone())
+                        .unwrap_or_default(),
+                }),
+            ipv6: allocation
+                .ipv6
+                .as_ref()
+                .map(|lease| runner_puppet::Ipv6NetworkConfig {
+                    address: lease.address,
+                    prefix_length: lease.prefix_length,
+                    gateway: lease.gateway,
+                    nameservers: self
+                        .ipv6
+                        .as_ref()
+                        .map(|pool| pool.nameservers.clone())
+                        .unwrap_or_default(),
+                }),
+        }
+    }
+
+    /// Number of packets of a job denied by its egress policy, if one is
+    /// configured.
+    pub async fn egress_denied_packets(&self, job_id: Uuid) -> Result<Option<u64>, String> {
+        match self.egress {
+            Some(_) => egress::denied_packets(job_id).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove a job's firewall rules and release its address leases. The
+    /// veth pair itself is removed along with the container.
+    pub async fn release(
+        &self,
+        job_id: Uuid,
+        allocation: &HostNetworkAllocation,
+    ) -> Result<(), String> {
+        if self.nat || self.egress.is_some() {
+            nft_delete_table(job_id).await?;
+        }
+
+        let addresses = [
+            allocation
+                .ipv4
+                .as_ref()
+                .map(|lease| IpAddr::from(lease.address)),
+            allocation
+                .ipv6
+                .as_ref()
+                .map(|lease| IpAddr::from(lease.address)),
+        ];
+        for address in addresses.into_iter().flatten() {
+            release_lease(&self.lease_dir, address, job_id).await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl NspawnRunner {
+    /// Release address leases of this board held by unknown jobs, along with
+    /// their firewall rules.
+    pub async fn collect_leases(
+        &self,
+        known_job_ids: &std::collections::HashSet<Uuid>,
+        report: &mut GcReport,
+    ) {
+        let mut lease_dirs: Vec<&Path> = self
+            .config
+            .environments
+            .values()
+            .filter_map(|env_cfg| env_cfg.host_network.as_ref())
+            .map(|host_network_cfg| host_network_cfg.lease_dir.as_path())
+            .collect();
+        lease_dirs.sort();
+        lease_dirs.dedup();
+
+        for lease_dir in lease_dirs {
+            let mut dir_entries = match tokio::fs::read_dir(lease_dir).await {
+                Ok(dir_entries) => dir_entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    report.error(format!(
+                        "Failed to read lease directory {:?}: {:?}",
+                        lease_dir, e
+                    ));
+                    continue;
+                }
+            };
+
+            while let Ok(Some(dir_entry)) = dir_entries.next_entry().await {
+                // Leases being created, which may belong to a job starting
+                // right now:
+                if dir_entry.file_name().to_string_lossy().ends_with(".tmp") {
+                    continue;
+                }
+                let Some(record) = tokio::fs::read(dir_entry.path())
+                    .await
+                    .ok()
+                    .and_then(|serialized| serde_json::from_slice::<LeaseRecord>(&serialized).ok())
+                else {
+                    continue;
+                };
+                if record.board_id != self.config.board_id || known_job_ids.contains(&record.job_id)
+                {
+                    continue;
+                }
+
+                if let Err(e) = nft_delete_table(record.job_id).await {
+                    report.error(e);
+                }
+                match tokio::fs::remove_file(dir_entry.path()).await {
+                    Ok(()) => report.action(format!(
+                        "Released lease {:?} of unknown job {:?}",
+                        dir_entry.path(),
+                        record.job_id
+                    )),
+                    Err(e) => report.error(format!(
+                        "Failed to remove lease {:?}: {:?}",
+                        dir_entry.path(),
+                        e
+                    )),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool<A>(network: A, prefix_length: u8) -> NspawnRunnerHostNetworkPoolConfig<A> {
+        NspawnRunnerHostNetworkPoolConfig {
+            network,
+            prefix_length,
+            subnet_prefix_length: None,
+            gateway: None,
+            nameservers: vec![],
+        }
+    }
+
+    fn test_lease_dir(name: &str) -> PathBuf {
+        let lease_dir = std::env::temp_dir().join(format!(
+            "treadmill-lease-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&lease_dir);
+        lease_dir
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(network_size::<Ipv4Addr>(32), 1);
+        assert_eq!(network_size::<Ipv4Addr>(24), 256);
+        assert_eq!(network_size::<Ipv4Addr>(0), 1 << 32);
+        assert_eq!(network_size::<Ipv6Addr>(64), 1 << 64);
+        assert_eq!(network_size::<Ipv6Addr>(1), 1 << 127);
+        assert_eq!(network_size::<Ipv6Addr>(0), u128::MAX);
+    }
+
+    #[tokio::test]
+    async fn bridged_leases() {
+        let lease_dir = test_lease_dir("bridged");
+        let mut pool = pool(Ipv4Addr::new(10, 0, 0, 0), 30);
+        pool.gateway = Some(Ipv4Addr::new(10, 0, 0, 1));
+        let record = LeaseRecord {
+            board_id: Uuid::nil(),
+            job_id: Uuid::nil(),
+        };
+
+        // The network address, gateway and broadcast address are skipped:
+        let lease = lease_from_pool(&pool, true, &lease_dir, &record)
+            .await
+            .unwrap();
+        assert_eq!(lease.address, Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(lease.prefix_length, 30);
+        assert_eq!(lease.gateway, pool.gateway);
+        assert_eq!(lease.host_address, None);
+        assert!(lease_from_pool(&pool, true, &lease_dir, &record)
+            .await
+            .is_err());
+
+        // Only the lease itself remains in the lease directory:
+        assert_eq!(std::fs::read_dir(&lease_dir).unwrap().count(), 1);
+
+        release_lease(&lease_dir, lease.address.into(), record.job_id)
+            .await
+            .unwrap();
+        let lease = lease_from_pool(&pool, true, &lease_dir, &record)
+            .await
+            .unwrap();
+        assert_eq!(lease.address, Ipv4Addr::new(10, 0, 0, 2));
+
+        std::fs::remove_dir_all(&lease_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn point_to_point_leases() {
+        let lease_dir = test_lease_dir("ptp");
+        let pool = pool("fd00::1".parse::<Ipv6Addr>().unwrap(), 124);
+        let record = LeaseRecord {
+            board_id: Uuid::nil(),
+            job_id: Uuid::nil(),
+        };
+
+        // The pool's network is derived from its prefix length, and split
+        // into four subnets of four addresses each:
+        for subnet in 0..4u16 {
+            let lease = lease_from_pool(&pool, false, &lease_dir, &record)
+                .await
+                .unwrap();
+            let base = subnet * 4;
+            assert_eq!(
+                lease.address,
+                Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, base + 2)
+            );
+            assert_eq!(lease.prefix_length, 126);
+            assert_eq!(
+                lease.host_address,
+                Some(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, base + 1))
+            );
+            assert_eq!(lease.gateway, lease.host_address);
+        }
+        assert!(lease_from_pool(&pool, false, &lease_dir, &record)
+            .await
+            .is_err());
+
+        let mut invalid = pool.clone();
+        invalid.subnet_prefix_length = Some(120);
+        assert!(lease_from_pool(&invalid, false, &lease_dir, &record)
+            .await
+            .is_err());
+
+        std::fs::remove_dir_all(&lease_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn leases_are_held_per_job() {
+        let lease_dir = test_lease_dir("jobs");
+        let pool = pool(Ipv4Addr::new(10, 0, 0, 0), 29);
+        let records: Vec<LeaseRecord> = (0..2)
+            .map(|_| LeaseRecord {
+                board_id: Uuid::nil(),
+                job_id: Uuid::new_v4(),
+            })
+            .collect();
+
+        let first = lease_from_pool(&pool, true, &lease_dir, &records[0])
+            .await
+            .unwrap();
+        let second = lease_from_pool(&pool, true, &lease_dir, &records[1])
+            .await
+            .unwrap();
+        assert_eq!(first.address, Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(second.address, Ipv4Addr::new(10, 0, 0, 2));
+
+        // Releasing another job's lease leaves it in place:
+        release_lease(&lease_dir, first.address.into(), records[1].job_id)
+            .await
+            .unwrap();
+        assert!(lease_path(&lease_dir, first.address.into()).exists());
+
+        release_lease(&lease_dir, first.address.into(), records[0].job_id)
+            .await
+            .unwrap();
+        assert!(!lease_path(&lease_dir, first.address.into()).exists());
+
+        // Releasing an address that isn't leased succeeds:
+        release_lease(&lease_dir, first.address.into(), records[0].job_id)
+            .await
+            .unwrap();
+
+        // The first free address is leased again:
+        let third = lease_from_pool(&pool, true, &lease_dir, &records[1])
+            .await
+            .unwrap();
+        assert_eq!(third.address, first.address);
+
+        std::fs::remove_dir_all(&lease_dir).unwrap();
+    }
+}
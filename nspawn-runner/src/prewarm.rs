@@ -0,0 +1,380 @@
+//! Pre-warmed root file systems.
+//!
+//! Allocating a job's root file system (e.g., cloning and mounting a ZFS
+//! dataset) is on the critical path of starting a job. Environments with
+//! `prewarm` enabled keep one root file system allocated in the background,
+//! based on the environment's latest image. A job of this environment claims
+//! it, provided that it selects the same image version, and a new one is
+//! allocated once the job has started. Provisioning steps that do not depend
+//! on the job, i.e., installing the puppet, are applied to warm root file
+//! systems ahead of time as well, while the job-specific ones (files, SSH
+//! keys and scripts) still run when the job starts.
+//!
+//! Warm root file systems are allocated under a random ID in place of a job
+//! ID, and recorded in the runner's state directory. They do not hold any
+//! data, and are destroyed when the runner restarts.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use treadmill_rs::api::coord_runner::sse as sse_api;
+use treadmill_rs::executor::JobDriver;
+use treadmill_rs::rollback::Rollback;
+
+use crate::rootfs::RootFsProvider;
+use crate::{NspawnRunner, NspawnRunnerEnvironmentConfig, NspawnRunnerJobMetadata};
+
+/// Interval at which to check whether warm root file systems are still based
+/// on the latest image, in addition to replenishing them after every job.
+const PREWARM_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Persistent record of a warm root file system, stored as
+/// `<state_dir>/prewarm/<root_fs_id>`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct WarmRootRecord {
+    environment_id: Uuid,
+    image_version: Option<String>,
+    root_fs_mountpoint: PathBuf,
+    root_fs_volume: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct WarmRoot {
+    /// ID the root file system was allocated under, in place of a job ID.
+    pub root_fs_id: Uuid,
+    pub image_version: Option<String>,
+    pub root_fs_mountpoint: PathBuf,
+    pub root_fs_volume: String,
+    /// Time it took to allocate the root file system, which is saved by
+    /// claiming it.
+    pub allocation_time: Duration,
+}
+
+/// Warm root file systems which are ready to be claimed, by environment.
+#[derive(Default)]
+pub struct WarmRoots {
+    roots: std::sync::Mutex<HashMap<Uuid, WarmRoot>>,
+    replenish: tokio::sync::Notify,
+}
+
+impl NspawnRunner {
+    fn prewarm_dir(&self) -> PathBuf {
+        self.config.state_dir.join("prewarm")
+    }
+
+    /// Take the warm root file system of an environment, if it is based on
+    /// the requested image version.
+    pub fn claim_warm_root(
+        &self,
+        environment_id: Uuid,
+        image_version: Option<&str>,
+    ) -> Option<WarmRoot> {
+        let mut roots = self.warm_roots.roots.lock().unwrap();
+        if roots.get(&environment_id)?.image_version.as_deref() != image_version {
+            return None;
+        }
+        roots.remove(&environment_id)
+    }
+
+    /// Put back a claimed warm root file system, if the job it was claimed
+    /// for failed to take ownership of it.
+    pub fn return_warm_root(&self, environment_id: Uuid, warm_root: WarmRoot) {
+        let mut roots = self.warm_roots.roots.lock().unwrap();
+        if roots.contains_key(&environment_id) {
+            // Still recorded, and thus destroyed when the runner restarts:
+            warn!(
+                "Environment {:?} already has a warm root filesystem, not returning {:?}",
+                environment_id, warm_root.root_fs_volume
+            );
+            return;
+        }
+        roots.insert(environment_id, warm_root);
+    }
+
+    /// Hand a claimed warm root file system over to its job, and allocate a
+    /// new one in the background. Must only be called once the job's
+    /// metadata records the root file system.
+    pub async fn transfer_warm_root(&self, root_fs_id: Uuid) {
+        let record_path = self.prewarm_dir().join(root_fs_id.to_string());
+        if let Err(e) = tokio::fs::remove_file(&record_path).await {
+            warn!(
+                "Failed to remove warm root filesystem record {:?}: {:?}",
+                record_path, e
+            );
+        }
+        self.warm_roots.replenish.notify_one();
+    }
+
+    async fn write_warm_root_record(
+        &self,
+        root_fs_id: Uuid,
+        record: &WarmRootRecord,
+    ) -> Result<(), String> {
+        let prewarm_dir = self.prewarm_dir();
+        let path = prewarm_dir.join(root_fs_id.to_string());
+        let tmp_path = prewarm_dir.join(format!("{}.tmp", root_fs_id));
+        let res: std::io::Result<()> = async {
+            tokio::fs::create_dir_all(&prewarm_dir).await?;
+            let serialized = serde_json::to_vec(record)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            tokio::fs::write(&tmp_path, serialized).await?;
+            tokio::fs::rename(&tmp_path, &path).await
+        }
+        .await;
+
+        res.map_err(|e| {
+            format!(
+                "Failed to write warm root filesystem record {:?}: {:?}",
+                path, e
+            )
+        })
+    }
+
+    /// Destroy a warm root file system and remove its record.
+    async fn discard_warm_root(
+        &self,
+        root_fs_provider: &dyn RootFsProvider,
+        record_path: &Path,
+        root_fs_mountpoint: &Path,
+        root_fs_volume: &str,
+    ) -> Result<(), String> {
+        info!("Discarding warm root filesystem {:?}", root_fs_volume);
+        root_fs_provider
+            .release(
+                root_fs_mountpoint,
+                root_fs_volume,
+                &sse_api::JobDataRetention::Delete,
+            )
+            .await?;
+        tokio::fs::remove_file(record_path).await.map_err(|e| {
+            format!(
+                "Failed to remove warm root filesystem record {:?}: {:?}",
+                record_path, e
+            )
+        })
+    }
+
+    /// IDs of the warm root file systems claimed by jobs with metadata.
+    async fn claimed_root_fs_ids(&self) -> Result<HashSet<Uuid>, String> {
+        let jobs_dir = self.config.state_dir.join("jobs");
+        let job_dirs = crate::gc::job_dir_entries(&jobs_dir)
+            .await
+            .map_err(|e| format!("Failed to read jobs directory {:?}: {:?}", jobs_dir, e))?;
+
+        let mut claimed = HashSet::new();
+        for (_, job_dir) in job_dirs {
+            let metadata_path = job_dir.join("metadata.json");
+            let serialized = match tokio::fs::read(&metadata_path).await {
+                Ok(serialized) => serialized,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(format!("Failed to read {:?}: {:?}", metadata_path, e)),
+            };
+            let metadata: NspawnRunnerJobMetadata = serde_json::from_slice(&serialized)
+                .map_err(|e| format!("Failed to parse {:?}: {:?}", metadata_path, e))?;
+            claimed.extend(metadata.root_fs_id);
+        }
+        Ok(claimed)
+    }
+
+    /// Destroy warm root file systems of a previous runner instance.
+    pub async fn discard_recorded_warm_roots(&self) {
+        let records = match crate::gc::job_dir_entries(&self.prewarm_dir()).await {
+            Ok(records) => records,
+            Err(e) => {
+                warn!("Failed to read warm root filesystem records: {:?}", e);
+                return;
+            }
+        };
+
+        // The previous runner may have crashed after a job claimed a warm
+        // root file system, but before removing its record. Such root file
+        // systems belong to the job, and must not be destroyed:
+        let claimed = match self.claimed_root_fs_ids().await {
+            Ok(claimed) => claimed,
+            Err(e) => {
+                // Without knowing which, leave all of them in place:
+                warn!("Not discarding warm root filesystems: {}", e);
+                return;
+            }
+        };
+
+        for (root_fs_id, record_path) in records {
+            if claimed.contains(&root_fs_id) {
+                info!(
+                    "Warm root filesystem {:?} has been claimed by a job, removing its record.",
+                    root_fs_id
+                );
+                if let Err(e) = tokio::fs::remove_file(&record_path).await {
+                    warn!(
+                        "Failed to remove warm root filesystem record {:?}: {:?}",
+                        record_path, e
+                    );
+                }
+                continue;
+            }
+
+            let record: WarmRootRecord = match tokio::fs::read(&record_path)
+                .await
+                .map_err(|e| format!("{:?}", e))
+                .and_then(|serialized| {
+                    serde_json::from_slice(&serialized).map_err(|e| format!("{:?}", e))
+                }) {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!(
+                        "Skipping warm root filesystem record {:?}: {}",
+                        record_path, e
+                    );
+                    continue;
+                }
+            };
+
+            let res = match self.config.environments.get(&record.environment_id) {
+                Some(environment_cfg) => {
+                    match Self::root_fs_provider(record.environment_id, environment_cfg) {
+                        Ok(root_fs_provider) => {
+                            self.discard_warm_root(
+                                &*root_fs_provider,
+                                &record_path,
+                                &record.root_fs_mountpoint,
+                                &record.root_fs_volume,
+                            )
+                            .await
+                        }
+                        Err(e) => Err(e),
+                    }
+                }
+                None => Err(format!("Unknown environment {:?}", record.environment_id)),
+            };
+
+            if let Err(e) = res {
+                warn!(
+                    "Failed to discard warm root filesystem {:?}: {}",
+                    record.root_fs_volume, e
+                );
+            }
+        }
+    }
+
+    async fn replenish_warm_root(
+        &self,
+        environment_id: Uuid,
+        environment_cfg: &NspawnRunnerEnvironmentConfig,
+        root_fs_provider: &dyn RootFsProvider,
+    ) -> Result<(), String> {
+        // Prepare for the next job using the environment's default image:
+        let image = self.load_catalog().await?.latest(environment_id).cloned();
+        let image_version = image.as_ref().map(|image| image.version.clone());
+
+        let stale = {
+            let mut roots = self.warm_roots.roots.lock().unwrap();
+            match roots.get(&environment_id) {
+                Some(warm_root) if warm_root.image_version == image_version => return Ok(()),
+                Some(_) => roots.remove(&environment_id),
+                None => None,
+            }
+        };
+
+        if let Some(stale) = stale {
+            self.discard_warm_root(
+                root_fs_provider,
+                &self.prewarm_dir().join(stale.root_fs_id.to_string()),
+                &stale.root_fs_mountpoint,
+                &stale.root_fs_volume,
+            )
+            .await?;
+        }
+
+        let root_fs_id = Uuid::new_v4();
+        let start = Instant::now();
+        let mut rollback = Rollback::new();
+        let root_fs = root_fs_provider
+            .allocate(
+                root_fs_id,
+                image.as_ref().map(|image| image.image.as_str()),
+                &mut rollback,
+            )
+            .await?;
+        let allocation_time = start.elapsed();
+
+        let prewarm_res = self
+            .prewarm_root_fs(environment_cfg, &root_fs.mountpoint)
+            .await;
+        rollback.check(prewarm_res).await?;
+
+        let record = WarmRootRecord {
+            environment_id,
+            image_version: image_version.clone(),
+            root_fs_mountpoint: root_fs.mountpoint.clone(),
+            root_fs_volume: root_fs.volume.clone(),
+        };
+        let record_res = self.write_warm_root_record(root_fs_id, &record).await;
+        rollback.check(record_res).await?;
+        rollback.commit();
+
+        debug!(
+            "Allocated warm root filesystem {:?} for environment {:?} in {:?}",
+            root_fs.volume, environment_id, allocation_time
+        );
+        self.return_warm_root(
+            environment_id,
+            WarmRoot {
+                root_fs_id,
+                image_version,
+                root_fs_mountpoint: root_fs.mountpoint,
+                root_fs_volume: root_fs.volume,
+                allocation_time,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Allocate a warm root file system for every environment with `prewarm`
+    /// enabled, replacing those based on an outdated image.
+    pub async fn replenish_warm_roots(&self) {
+        for (environment_id, environment_cfg) in self.config.environments.iter() {
+            if !environment_cfg.prewarm {
+                continue;
+            }
+
+            let res = match Self::root_fs_provider(*environment_id, environment_cfg) {
+                Ok(root_fs_provider) => {
+                    self.replenish_warm_root(*environment_id, environment_cfg, &*root_fs_provider)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = res {
+                warn!(
+                    "Failed to pre-warm root filesystem of environment {:?}: {}",
+                    environment_id, e
+                );
+            }
+        }
+    }
+}
+
+/// Keep warm root file systems available for all environments with `prewarm`
+/// enabled.
+pub fn spawn_warmer(driver: Arc<JobDriver<NspawnRunner>>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let runner = driver.executor();
+        runner.discard_recorded_warm_roots().await;
+        loop {
+            runner.replenish_warm_roots().await;
+            let _ = tokio::time::timeout(
+                PREWARM_CHECK_INTERVAL,
+                runner.warm_roots.replenish.notified(),
+            )
+            .await;
+        }
+    })
+}
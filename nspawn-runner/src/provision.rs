@@ -0,0 +1,596 @@
+//! Provisioning of a job's root file system before its container is booted.
+//!
+//! Environments may declare files to write into the root file system,
+//! templated from the job's, board's and environment's parameters, have the
+//! puppet and its systemd unit installed, pre-seed SSH authorized keys and the
+//! job's SSH host keys, and run scripts in a chroot of the root file system.
+//! All of this happens in the job's `Provisioning` stage, except for
+//! installing the puppet into pre-warmed root file systems, which does not
+//! depend on the job and is done ahead of time.
+
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use treadmill_rs::api::coord_runner::sse as sse_api;
+use treadmill_rs::executor::JobDriver;
+
+use crate::{NspawnRunner, NspawnRunnerEnvironmentConfig, NspawnRunnerJob};
+
+/// Location of the puppet binary within the container.
+const PUPPET_BINARY_PATH: &str = "/usr/local/bin/treadmill-puppet";
+
+/// Location of the puppet's systemd unit within the container.
+const PUPPET_UNIT_PATH: &str = "/etc/systemd/system/treadmill-puppet.service";
+
+/// Location provisioning scripts are copied to within the container, while
+/// they are executed.
+const PROVISION_SCRIPT_PATH: &str = "/.treadmill-provision";
+
+fn default_file_mode() -> u32 {
+    0o644
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NspawnRunnerEnvironmentProvisionFileConfig {
+    /// Absolute path of the file within the container.
+    path: PathBuf,
+    /// Contents of the file. Placeholders of the form `{{name}}` are replaced
+    /// by `job_id`, `board_id`, `environment_id`, or the parameters
+    /// `job.<key>`, `board.<key>`, `environment.<key>` and
+    /// `board_environment.<key>` of the job.
+    content: String,
+    #[serde(default = "default_file_mode")]
+    mode: u32,
+    /// Permit placeholders referring to secret parameters. The file's `mode`
+    /// should restrict access to it accordingly.
+    #[serde(default)]
+    allow_secrets: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NspawnRunnerEnvironmentPuppetConfig {
+    /// Puppet binary on the host, installed into the container.
+    binary: PathBuf,
+    /// Script within the container to pass the network configuration to.
+    #[serde(default)]
+    network_config_script: Option<PathBuf>,
+    /// Files within the container to write the SSH user CA keys and accepted
+    /// certificate principals to, referenced by the SSH server's
+    /// `TrustedUserCAKeys` and `AuthorizedPrincipalsFile` options.
+    #[serde(default)]
+    trusted_user_ca_keys_file: Option<PathBuf>,
+    #[serde(default)]
+    authorized_principals_file: Option<PathBuf>,
+    /// Have the puppet create personal accounts for the job's users.
+    #[serde(default)]
+    user_accounts: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NspawnRunnerEnvironmentProvisionConfig {
+    #[serde(default)]
+    file: Vec<NspawnRunnerEnvironmentProvisionFileConfig>,
+    #[serde(default)]
+    puppet: Option<NspawnRunnerEnvironmentPuppetConfig>,
+    /// Path within the container to write the job's SSH keys to.
+    #[serde(default)]
+    authorized_keys: Option<PathBuf>,
+    /// Directory within the container to install SSH host keys generated for
+    /// the job into (e.g., "/etc/ssh"). Their fingerprints are published with
+    /// the job's connection information.
+    #[serde(default)]
+    pub ssh_host_keys: Option<PathBuf>,
+    /// Scripts on the host to run in a chroot of the root file system, in
+    /// order.
+    #[serde(default)]
+    scripts: Vec<PathBuf>,
+}
+
+/// Resolve an absolute path within the container to a path on the host.
+///
+/// The root file system is controlled by the job's image, so none of the
+/// path's existing components may be a symlink, which could otherwise point
+/// to a location outside of the root file system.
+async fn root_path(root: &Path, path: &Path) -> Result<PathBuf, String> {
+    let mut components = path.components();
+    if components.next() != Some(Component::RootDir)
+        || !components.all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(format!(
+            "Invalid path {:?}, must be absolute and normalized",
+            path
+        ));
+    }
+
+    let mut host_path = root.to_path_buf();
+    for component in path.components().skip(1) {
+        host_path.push(component);
+        match tokio::fs::symlink_metadata(&host_path).await {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                return Err(format!(
+                    "Refusing to resolve {:?} through symlink {:?}",
+                    path, host_path
+                ));
+            }
+            Ok(_) => (),
+            // All remaining components are yet to be created:
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => break,
+            Err(e) => return Err(format!("Failed to inspect {:?}: {:?}", host_path, e)),
+        }
+    }
+
+    Ok(root.join(path.strip_prefix("/").unwrap()))
+}
+
+/// Replace all `{{name}}` placeholders of a template. Secret parameters are
+/// only substituted if `allow_secrets` is set.
+fn render_template(
+    template: &str,
+    variables: &HashMap<String, sse_api::ParameterValue>,
+    allow_secrets: bool,
+) -> Result<String, String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find("}}")
+            .ok_or_else(|| "Unterminated placeholder".to_string())?;
+        let name = rest[start + 2..start + end].trim();
+        let variable = variables
+            .get(name)
+            .ok_or_else(|| format!("Unknown placeholder {:?}", name))?;
+        if variable.secret && !allow_secrets {
+            return Err(format!(
+                "Placeholder {:?} refers to a secret parameter, which requires \
+                 `allow_secrets`",
+                name
+            ));
+        }
+        rendered.push_str(&variable.value);
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+fn template_variables(
+    board_id: uuid::Uuid,
+    msg: &sse_api::StartJobMessage,
+) -> HashMap<String, sse_api::ParameterValue> {
+    let mut variables = HashMap::new();
+    for (name, id) in [
+        ("job_id", msg.job_id),
+        ("board_id", board_id),
+        ("environment_id", msg.environment_id),
+    ] {
+        variables.insert(
+            name.to_string(),
+            sse_api::ParameterValue {
+                value: id.to_string(),
+                secret: false,
+            },
+        );
+    }
+    for (prefix, parameters) in [
+        ("job", &msg.job_parameters),
+        ("board", &msg.board_parameters),
+        ("environment", &msg.environment_parameters),
+        ("board_environment", &msg.board_environment_parameters),
+    ] {
+        for (key, parameter) in parameters.iter() {
+            variables.insert(format!("{}.{}", prefix, key), parameter.clone());
+        }
+    }
+    variables
+}
+
+/// Owner of a path within the container, as the UID and GID of the user whose
+/// home directory contains it, according to the container's `passwd` file.
+fn passwd_owner(passwd: &str, path: &Path) -> Option<(u32, u32)> {
+    passwd
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            if fields.len() < 7 {
+                return None;
+            }
+            Some((
+                Path::new(fields[5]),
+                fields[2].parse().ok()?,
+                fields[3].parse().ok()?,
+            ))
+        })
+        .filter(|(home, _, _)| *home != Path::new("/") && path.starts_with(home))
+        .max_by_key(|(home, _, _)| home.components().count())
+        .map(|(_, uid, gid)| (uid, gid))
+}
+
+/// Create the missing parent directories of a file within the root file
+/// system, as resolved by [`root_path`]. Returns the created directories.
+async fn create_parent_dirs(path: &Path, mode: u32) -> Result<Vec<PathBuf>, String> {
+    let mut missing = vec![];
+    for ancestor in path.ancestors().skip(1) {
+        match tokio::fs::symlink_metadata(ancestor).await {
+            Ok(_) => break,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                missing.push(ancestor.to_path_buf())
+            }
+            Err(e) => return Err(format!("Failed to inspect {:?}: {:?}", ancestor, e)),
+        }
+    }
+
+    missing.reverse();
+    for dir in missing.iter() {
+        tokio::fs::DirBuilder::new()
+            .mode(mode)
+            .create(dir)
+            .await
+            .map_err(|e| format!("Failed to create directory {:?}: {:?}", dir, e))?;
+    }
+    Ok(missing)
+}
+
+/// Write a file within the root file system, as resolved by [`root_path`].
+/// The file itself is never written through a symlink either.
+async fn write_file(path: &Path, content: &[u8], mode: u32) -> Result<(), String> {
+    let res: std::io::Result<()> = async {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .custom_flags(nix::fcntl::OFlag::O_NOFOLLOW.bits())
+            .open(path)
+            .await?;
+        file.write_all(content).await?;
+        file.set_permissions(std::fs::Permissions::from_mode(mode))
+            .await
+    }
+    .await;
+
+    res.map_err(|e| format!("Failed to write {:?}: {:?}", path, e))
+}
+
+fn puppet_unit(
+    control_socket_path: &Path,
+    authorized_keys: Option<&Path>,
+    puppet_cfg: &NspawnRunnerEnvironmentPuppetConfig,
+) -> String {
+    let mut exec_start = format!(
+        "{} --transport unix_seqpacket --unix-seqpacket-control-socket {}",
+        PUPPET_BINARY_PATH,
+        control_socket_path.display()
+    );
+    if let Some(authorized_keys) = authorized_keys {
+        exec_start.push_str(&format!(
+            " --authorized-keys-file {}",
+            authorized_keys.display()
+        ));
+    }
+    if let Some(ref script) = puppet_cfg.network_config_script {
+        exec_start.push_str(&format!(" --network-config-script {}", script.display()));
+    }
+    if let (Some(ca_keys_file), Some(principals_file)) = (
+        &puppet_cfg.trusted_user_ca_keys_file,
+        &puppet_cfg.authorized_principals_file,
+    ) {
+        exec_start.push_str(&format!(
+            " --trusted-user-ca-keys-file {} --authorized-principals-file {}",
+            ca_keys_file.display(),
+            principals_file.display()
+        ));
+    }
+    if puppet_cfg.user_accounts {
+        exec_start.push_str(" --user-accounts");
+    }
+
+    format!(
+        "[Unit]\n\
+         Description=Treadmill puppet\n\
+         After=network.target\n\
+         \n\
+         [Service]\n\
+         Type=simple\n\
+         ExecStart={}\n\
+         \n\
+         [Install]\n\
+         WantedBy=multi-user.target\n",
+        exec_start
+    )
+}
+
+impl NspawnRunner {
+    async fn install_puppet(
+        &self,
+        root: &Path,
+        control_socket_path: &Path,
+        puppet_cfg: &NspawnRunnerEnvironmentPuppetConfig,
+        authorized_keys: Option<&Path>,
+    ) -> Result<(), String> {
+        let binary_path = root_path(root, Path::new(PUPPET_BINARY_PATH)).await?;
+        let binary = tokio::fs::read(&puppet_cfg.binary).await.map_err(|e| {
+            format!(
+                "Failed to read puppet binary {:?}: {:?}",
+                puppet_cfg.binary, e
+            )
+        })?;
+        write_file(&binary_path, &binary, 0o755).await?;
+
+        let unit = puppet_unit(control_socket_path, authorized_keys, puppet_cfg);
+        write_file(
+            &root_path(root, Path::new(PUPPET_UNIT_PATH)).await?,
+            unit.as_bytes(),
+            0o644,
+        )
+        .await?;
+
+        // Equivalent to `systemctl enable`:
+        let wants_dir = root_path(
+            root,
+            Path::new("/etc/systemd/system/multi-user.target.wants"),
+        )
+        .await?;
+        let link_path = wants_dir.join("treadmill-puppet.service");
+        let res: std::io::Result<()> = async {
+            tokio::fs::create_dir_all(&wants_dir).await?;
+            match tokio::fs::remove_file(&link_path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+            tokio::fs::symlink(PUPPET_UNIT_PATH, &link_path).await
+        }
+        .await;
+        res.map_err(|e| format!("Failed to enable puppet unit {:?}: {:?}", link_path, e))
+    }
+
+    async fn run_provision_script(
+        &self,
+        root: &Path,
+        msg: &sse_api::StartJobMessage,
+        script: &Path,
+    ) -> Result<(), String> {
+        let script_path = root_path(root, Path::new(PROVISION_SCRIPT_PATH)).await?;
+        let contents = tokio::fs::read(script)
+            .await
+            .map_err(|e| format!("Failed to read provisioning script {:?}: {:?}", script, e))?;
+        write_file(&script_path, &contents, 0o755).await?;
+
+        let output = Command::new("chroot")
+            .arg(root)
+            .arg(PROVISION_SCRIPT_PATH)
+            .env("TREADMILL_JOB_ID", msg.job_id.to_string())
+            .env("TREADMILL_BOARD_ID", self.config.board_id.to_string())
+            .env("TREADMILL_ENVIRONMENT_ID", msg.environment_id.to_string())
+            .output()
+            .await;
+        let _ = tokio::fs::remove_file(&script_path).await;
+
+        match output {
+            Ok(output) if output.status.success() => {
+                debug!(
+                    "Provisioning script {:?} of job {:?} finished. Stdout: {}",
+                    script,
+                    msg.job_id,
+                    String::from_utf8_lossy(&output.stdout)
+                );
+                Ok(())
+            }
+            Ok(output) => Err(format!(
+                "Provisioning script {:?} failed with exit-status {:?}. Stdout: {}, Stderr: {}",
+                script,
+                output.status.code(),
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            )),
+            Err(e) => Err(format!(
+                "Running provisioning script {:?} failed: {:?}",
+                script, e
+            )),
+        }
+    }
+
+    /// Apply the job-independent parts of an environment's provisioning
+    /// configuration to a pre-warmed root file system.
+    pub async fn prewarm_root_fs(
+        &self,
+        environment_cfg: &NspawnRunnerEnvironmentConfig,
+        root: &Path,
+    ) -> Result<(), String> {
+        let Some(ref provision_cfg) = environment_cfg.provision else {
+            return Ok(());
+        };
+
+        if let Some(ref puppet_cfg) = provision_cfg.puppet {
+            self.install_puppet(
+                root,
+                &environment_cfg.control_socket_path,
+                puppet_cfg,
+                provision_cfg.authorized_keys.as_deref(),
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Apply the provisioning configuration of the job's environment to its
+    /// root file system.
+    pub async fn provision_root_fs(
+        &self,
+        driver: &Arc<JobDriver<Self>>,
+        msg: &sse_api::StartJobMessage,
+        job: &NspawnRunnerJob,
+    ) -> Result<(), String> {
+        let Some(ref provision_cfg) = job.metadata.environment_config.provision else {
+            return Ok(());
+        };
+        let root = &job.metadata.root_fs_mountpoint;
+
+        if !provision_cfg.file.is_empty() {
+            driver
+                .report_provisioning(
+                    msg.job_id,
+                    format!("Writing {} files.", provision_cfg.file.len()),
+                )
+                .await;
+
+            let variables = template_variables(self.config.board_id, msg);
+            for file_cfg in provision_cfg.file.iter() {
+                let content =
+                    render_template(&file_cfg.content, &variables, file_cfg.allow_secrets)
+                        .map_err(|e| format!("Failed to render {:?}: {}", file_cfg.path, e))?;
+                write_file(
+                    &root_path(root, &file_cfg.path).await?,
+                    content.as_bytes(),
+                    file_cfg.mode,
+                )
+                .await?;
+            }
+        }
+
+        if let Some(ref authorized_keys) = provision_cfg.authorized_keys {
+            driver
+                .report_provisioning(msg.job_id, "Installing SSH authorized keys.".to_string())
+                .await;
+
+            let mut keys = job.metadata.ssh_keys.join("\n");
+            keys.push('\n');
+            let keys_path = root_path(root, authorized_keys).await?;
+            // Only restrict the directories created here, existing parents
+            // may be shared (e.g., /etc/ssh):
+            let created_dirs = create_parent_dirs(&keys_path, 0o700).await?;
+            write_file(&keys_path, keys.as_bytes(), 0o600).await?;
+
+            // The SSH server only accepts keys of a user other than root if
+            // they're owned by that user:
+            let passwd_path = root_path(root, Path::new("/etc/passwd")).await?;
+            let owner = match tokio::fs::read_to_string(&passwd_path).await {
+                Ok(passwd) => passwd_owner(&passwd, authorized_keys),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(format!("Failed to read {:?}: {:?}", passwd_path, e)),
+            };
+            if let Some((uid, gid)) = owner {
+                for path in created_dirs.iter().chain([&keys_path]) {
+                    std::os::unix::fs::lchown(path, Some(uid), Some(gid))
+                        .map_err(|e| format!("Failed to change owner of {:?}: {:?}", path, e))?;
+                }
+            }
+        }
+
+        if let Some(ref ssh_host_keys) = provision_cfg.ssh_host_keys {
+            driver
+                .report_provisioning(msg.job_id, "Installing SSH host keys.".to_string())
+                .await;
+
+            let keys_dir = root_path(root, ssh_host_keys).await?;
+            for host_key in job.metadata.ssh_host_keys.iter() {
+                let key_path = keys_dir.join(format!("ssh_host_{}_key", host_key.key.algorithm));
+                write_file(&key_path, host_key.key.private_key.as_bytes(), 0o600).await?;
+                write_file(
+                    &key_path.with_extension("pub"),
+                    host_key.key.public_key.as_bytes(),
+                    0o644,
+                )
+                .await?;
+            }
+        }
+
+        // Claimed warm root file systems already had the puppet installed by
+        // `prewarm_root_fs`:
+        if let (Some(ref puppet_cfg), None) = (&provision_cfg.puppet, job.metadata.root_fs_id) {
+            driver
+                .report_provisioning(msg.job_id, "Installing the puppet.".to_string())
+                .await;
+            self.install_puppet(
+                root,
+                &job.metadata.environment_config.control_socket_path,
+                puppet_cfg,
+                provision_cfg.authorized_keys.as_deref(),
+            )
+            .await?;
+        }
+
+        for script in provision_cfg.scripts.iter() {
+            driver
+                .report_provisioning(
+                    msg.job_id,
+                    format!("Running provisioning script {:?}.", script),
+                )
+                .await;
+            self.run_provision_script(root, msg, script).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_secrets_only_if_allowed() {
+        let variables = HashMap::from([
+            (
+                "job.name".to_string(),
+                sse_api::ParameterValue {
+                    value: "test".to_string(),
+                    secret: false,
+                },
+            ),
+            (
+                "job.token".to_string(),
+                sse_api::ParameterValue {
+                    value: "hunter2".to_string(),
+                    secret: true,
+                },
+            ),
+        ]);
+
+        assert_eq!(
+            render_template("name={{ job.name }}", &variables, false).unwrap(),
+            "name=test"
+        );
+        assert!(render_template("token={{job.token}}", &variables, false).is_err());
+        assert_eq!(
+            render_template("token={{job.token}}", &variables, true).unwrap(),
+            "token=hunter2"
+        );
+        assert!(render_template("{{job.missing}}", &variables, true).is_err());
+    }
+
+    #[test]
+    fn finds_owner_by_home_directory() {
+        let passwd = "root:x:0:0:root:/root:/bin/bash\n\
+                      daemon:x:1:1:daemon:/:/usr/sbin/nologin\n\
+                      alice:x:1000:1000::/home/alice:/bin/bash\n\
+                      git:x:1001:1001::/home/alice/git:/bin/sh\n";
+
+        assert_eq!(
+            passwd_owner(passwd, Path::new("/home/alice/.ssh/authorized_keys")),
+            Some((1000, 1000))
+        );
+        assert_eq!(
+            passwd_owner(passwd, Path::new("/home/alice/git/.ssh/authorized_keys")),
+            Some((1001, 1001))
+        );
+        assert_eq!(
+            passwd_owner(passwd, Path::new("/root/.ssh/authorized_keys")),
+            Some((0, 0))
+        );
+        assert_eq!(
+            passwd_owner(passwd, Path::new("/etc/ssh/authorized_keys")),
+            None
+        );
+    }
+}
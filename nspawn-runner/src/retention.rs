@@ -0,0 +1,171 @@
+//! Retention of the root file systems of stopped jobs.
+//!
+//! Depending on the [`JobDataRetention`] requested by the coordinator, a
+//! job's root file system may be kept after the job has stopped. Such volumes
+//! are marked as retained by their [`RootFsProvider`], and destroyed by a
+//! background reaper according to the retention policy of the environment
+//! they were created for.
+//!
+//! [`JobDataRetention`]: treadmill_rs::api::coord_runner::sse::JobDataRetention
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use treadmill_rs::executor::JobDriver;
+
+use crate::rootfs::{unix_timestamp, RootFsProvider, RootFsVolume};
+use crate::NspawnRunner;
+
+/// Retention policy for the root file systems of stopped jobs of an
+/// environment. A volume is destroyed as soon as any of the configured limits
+/// is exceeded.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NspawnRunnerEnvironmentRetentionConfig {
+    /// Keep at most this many volumes, destroying the oldest ones first.
+    keep_last: Option<usize>,
+    /// Destroy volumes retained for longer than this many hours.
+    max_age_hours: Option<u64>,
+    /// Destroy volumes, oldest first, while the usage of the storage holding
+    /// them exceeds this percentage.
+    max_pool_usage_percent: Option<u8>,
+}
+
+impl NspawnRunner {
+    async fn enforce_environment_retention(
+        &self,
+        environment_id: Uuid,
+        root_fs_provider: &dyn RootFsProvider,
+        retention_cfg: &NspawnRunnerEnvironmentRetentionConfig,
+        active_job_ids: &HashSet<Uuid>,
+    ) -> Result<(), String> {
+        // Retained volumes of this environment, from newest to oldest:
+        let mut volumes: Vec<(RootFsVolume, u64)> = root_fs_provider
+            .volumes()
+            .await?
+            .into_iter()
+            .filter(|volume| {
+                volume.environment_id == Some(environment_id)
+                    && !active_job_ids.contains(&volume.job_id)
+            })
+            .filter_map(|volume| volume.retained.map(|retained| (volume, retained)))
+            .collect();
+        volumes.sort_by_key(|(_, retained)| std::cmp::Reverse(*retained));
+
+        let min_retained = retention_cfg
+            .max_age_hours
+            .map(|hours| unix_timestamp().saturating_sub(hours * 3600));
+
+        // Retain the newest volumes within the age limit, destroy the rest:
+        let mut expired = vec![];
+        let mut idx = 0;
+        while idx < volumes.len() {
+            let over_count = retention_cfg
+                .keep_last
+                .is_some_and(|keep_last| idx >= keep_last);
+            let over_age = min_retained.is_some_and(|min| volumes[idx].1 < min);
+            if over_count || over_age {
+                expired.push(volumes.remove(idx).0);
+            } else {
+                idx += 1;
+            }
+        }
+
+        for volume in expired {
+            info!(
+                "Destroying volume {:?} according to the retention policy of environment {:?}",
+                volume.volume, environment_id
+            );
+            // A volume failing to be destroyed (e.g., as it is still busy)
+            // must not keep the others from being reaped:
+            if let Err(e) = root_fs_provider.destroy(&volume.volume).await {
+                warn!("Failed to destroy volume {:?}: {}", volume.volume, e);
+            }
+        }
+
+        // Free up space, oldest volumes first:
+        if let Some(max_pool_usage) = retention_cfg.max_pool_usage_percent {
+            while let Some((volume, _)) = volumes.pop() {
+                let usage = root_fs_provider.usage_percent().await?;
+                if usage <= max_pool_usage {
+                    break;
+                }
+
+                info!(
+                    "Storage usage at {}%, destroying volume {:?} of environment {:?}",
+                    usage, volume.volume, environment_id
+                );
+                if let Err(e) = root_fs_provider.destroy(&volume.volume).await {
+                    warn!("Failed to destroy volume {:?}: {}", volume.volume, e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Destroy the root file systems of stopped jobs exceeding the retention
+    /// policy of their environment.
+    pub async fn enforce_retention(&self) {
+        // Never touch volumes of jobs that are running, or have not yet been
+        // cleaned up completely:
+        let active_job_ids: HashSet<Uuid> =
+            match crate::gc::job_dir_entries(&self.config.state_dir.join("jobs")).await {
+                Ok(job_dirs) => job_dirs.into_iter().map(|(job_id, _)| job_id).collect(),
+                Err(e) => {
+                    warn!(
+                        "Failed to read jobs directory, not enforcing retention: {:?}",
+                        e
+                    );
+                    return;
+                }
+            };
+
+        for (environment_id, environment_cfg) in self.config.environments.iter() {
+            let Some(retention_cfg) = &environment_cfg.retention else {
+                continue;
+            };
+
+            let res = match Self::root_fs_provider(*environment_id, environment_cfg) {
+                Ok(root_fs_provider) => {
+                    debug!(
+                        "Enforcing retention policy of environment {:?}",
+                        environment_id
+                    );
+                    self.enforce_environment_retention(
+                        *environment_id,
+                        &*root_fs_provider,
+                        retention_cfg,
+                        &active_job_ids,
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = res {
+                warn!(
+                    "Failed to enforce retention policy of environment {:?}: {}",
+                    environment_id, e
+                );
+            }
+        }
+    }
+}
+
+/// Periodically enforce the retention policies of all environments.
+pub fn spawn_reaper(
+    driver: Arc<JobDriver<NspawnRunner>>,
+    interval: Duration,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            driver.executor().enforce_retention().await;
+            tokio::time::sleep(interval).await;
+        }
+    })
+}
@@ -0,0 +1,320 @@
+//! Root file systems as btrfs subvolumes, snapshotted from a base subvolume
+//! per job.
+//!
+//! btrfs has no user properties on subvolumes. Instead, the environment and
+//! retention state of every subvolume are recorded in a `<job_id>.json` file
+//! next to it.
+
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use treadmill_rs::api::coord_runner::sse as sse_api;
+use treadmill_rs::rollback::Rollback;
+
+use super::{
+    command_output, image_volume_name, unix_timestamp, AllocatedRootFs, ImageVolume,
+    RootFsProvider, RootFsVolume,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NspawnRunnerEnvironmentBtrfsRootConfig {
+    /// Subvolume to snapshot for every job. If not set, jobs start with an
+    /// empty subvolume.
+    #[serde(default)]
+    snapshot_from: Option<PathBuf>,
+    /// Directory on a btrfs file system to create per-job subvolumes in.
+    subvolume_base: PathBuf,
+    /// Limit of the qgroup of every job's subvolume. Requires quotas to be
+    /// enabled on the file system.
+    #[serde(default)]
+    quota: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SubvolumeMetadata {
+    environment_id: Uuid,
+    retained: Option<u64>,
+}
+
+pub struct BtrfsRootFsProvider<'a> {
+    environment_id: Uuid,
+    config: &'a NspawnRunnerEnvironmentBtrfsRootConfig,
+}
+
+impl<'a> BtrfsRootFsProvider<'a> {
+    pub fn new(environment_id: Uuid, config: &'a NspawnRunnerEnvironmentBtrfsRootConfig) -> Self {
+        BtrfsRootFsProvider {
+            environment_id,
+            config,
+        }
+    }
+}
+
+fn metadata_path(subvolume: &Path) -> PathBuf {
+    let mut path = subvolume.as_os_str().to_owned();
+    path.push(".json");
+    PathBuf::from(path)
+}
+
+fn final_snapshot_path(subvolume: &Path) -> PathBuf {
+    let mut path = subvolume.as_os_str().to_owned();
+    path.push("@final");
+    PathBuf::from(path)
+}
+
+async fn write_metadata(subvolume: &Path, metadata: &SubvolumeMetadata) -> Result<(), String> {
+    let path = metadata_path(subvolume);
+    let tmp_path = path.with_extension("tmp");
+    let res: std::io::Result<()> = async {
+        let serialized = serde_json::to_vec(metadata)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        tokio::fs::write(&tmp_path, serialized).await?;
+        tokio::fs::rename(&tmp_path, &path).await
+    }
+    .await;
+
+    res.map_err(|e| format!("Failed to write subvolume metadata {:?}: {:?}", path, e))
+}
+
+async fn read_metadata(subvolume: &Path) -> Option<SubvolumeMetadata> {
+    let serialized = tokio::fs::read(metadata_path(subvolume)).await.ok()?;
+    serde_json::from_slice(&serialized).ok()
+}
+
+async fn delete_subvolume(subvolume: &Path) -> Result<(), String> {
+    command_output(
+        "btrfs",
+        &["subvolume", "delete", &subvolume.to_string_lossy()],
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| format!("Deleting btrfs subvolume failed: {}", e))
+}
+
+#[async_trait]
+impl RootFsProvider for BtrfsRootFsProvider<'_> {
+    async fn allocate(
+        &self,
+        job_id: Uuid,
+        image: Option<&str>,
+        rollback: &mut Rollback,
+    ) -> Result<AllocatedRootFs, String> {
+        let subvolume = self.config.subvolume_base.join(job_id.to_string());
+        let subvolume_str = subvolume.to_string_lossy();
+
+        // Create the subvolume, as a writable snapshot of the image or base
+        // subvolume if one is given:
+        let snapshot_from = image
+            .map(Path::new)
+            .or(self.config.snapshot_from.as_deref());
+        let create_res = if let Some(snapshot_from) = snapshot_from {
+            command_output(
+                "btrfs",
+                &[
+                    "subvolume",
+                    "snapshot",
+                    &snapshot_from.to_string_lossy(),
+                    &subvolume_str,
+                ],
+            )
+            .await
+        } else {
+            command_output("btrfs", &["subvolume", "create", &subvolume_str]).await
+        }
+        .map_err(|e| format!("Creating btrfs root subvolume failed: {}", e));
+        rollback.check(create_res).await?;
+
+        let undo_subvolume = subvolume.clone();
+        rollback.push(
+            format!("create btrfs subvolume {:?}", subvolume),
+            async move { delete_subvolume(&undo_subvolume).await },
+        );
+
+        if let Some(ref quota) = self.config.quota {
+            let quota_res = command_output("btrfs", &["qgroup", "limit", quota, &subvolume_str])
+                .await
+                .map_err(|e| format!("Limiting btrfs root subvolume failed: {}", e));
+            rollback.check(quota_res).await?;
+        }
+
+        // Record the environment, to apply its retention policy:
+        let metadata_res = write_metadata(
+            &subvolume,
+            &SubvolumeMetadata {
+                environment_id: self.environment_id,
+                retained: None,
+            },
+        )
+        .await;
+        rollback.check(metadata_res).await?;
+
+        let undo_metadata_path = metadata_path(&subvolume);
+        rollback.push(
+            format!("write metadata {:?}", undo_metadata_path),
+            async move {
+                tokio::fs::remove_file(&undo_metadata_path)
+                    .await
+                    .map_err(|e| format!("{:?}", e))
+            },
+        );
+
+        Ok(AllocatedRootFs {
+            volume: subvolume_str.into_owned(),
+            mountpoint: subvolume,
+        })
+    }
+
+    async fn release(
+        &self,
+        _mountpoint: &Path,
+        volume: &str,
+        retention: &sse_api::JobDataRetention,
+    ) -> Result<(), String> {
+        let subvolume = Path::new(volume);
+
+        if *retention == sse_api::JobDataRetention::Delete {
+            return self.destroy(volume).await;
+        }
+
+        if *retention == sse_api::JobDataRetention::Snapshot {
+            command_output(
+                "btrfs",
+                &[
+                    "subvolume",
+                    "snapshot",
+                    "-r",
+                    volume,
+                    &final_snapshot_path(subvolume).to_string_lossy(),
+                ],
+            )
+            .await
+            .map_err(|e| format!("Snapshotting btrfs root subvolume failed: {}", e))?;
+        }
+
+        // Hand the subvolume over to the retention reaper:
+        write_metadata(
+            subvolume,
+            &SubvolumeMetadata {
+                environment_id: self.environment_id,
+                retained: Some(unix_timestamp()),
+            },
+        )
+        .await
+    }
+
+    async fn destroy(&self, volume: &str) -> Result<(), String> {
+        let subvolume = Path::new(volume);
+
+        let final_snapshot = final_snapshot_path(subvolume);
+        if tokio::fs::symlink_metadata(&final_snapshot).await.is_ok() {
+            delete_subvolume(&final_snapshot).await?;
+        }
+
+        delete_subvolume(subvolume).await?;
+
+        match tokio::fs::remove_file(metadata_path(subvolume)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(format!(
+                "Failed to remove subvolume metadata of {:?}: {:?}",
+                subvolume, e
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    async fn volumes(&self) -> Result<Vec<RootFsVolume>, String> {
+        let mut dir_entries = match tokio::fs::read_dir(&self.config.subvolume_base).await {
+            Ok(dir_entries) => dir_entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => {
+                return Err(format!(
+                    "Failed to read subvolume base {:?}: {:?}",
+                    self.config.subvolume_base, e
+                ))
+            }
+        };
+
+        let mut volumes = vec![];
+        while let Some(dir_entry) = dir_entries.next_entry().await.map_err(|e| {
+            format!(
+                "Failed to read subvolume base {:?}: {:?}",
+                self.config.subvolume_base, e
+            )
+        })? {
+            let Some(job_id) = dir_entry
+                .file_name()
+                .to_str()
+                .and_then(|name| Uuid::parse_str(name).ok())
+            else {
+                continue;
+            };
+
+            let subvolume = dir_entry.path();
+            let metadata = read_metadata(&subvolume).await;
+            volumes.push(RootFsVolume {
+                job_id,
+                volume: subvolume.to_string_lossy().into_owned(),
+                environment_id: metadata.as_ref().map(|m| m.environment_id),
+                retained: metadata.and_then(|m| m.retained),
+            });
+        }
+
+        Ok(volumes)
+    }
+
+    async fn create_image(
+        &self,
+        version: &str,
+        rollback: &mut Rollback,
+    ) -> Result<ImageVolume, String> {
+        let subvolume = self
+            .config
+            .subvolume_base
+            .join(image_volume_name(self.environment_id, version));
+
+        let create_res = command_output(
+            "btrfs",
+            &["subvolume", "create", &subvolume.to_string_lossy()],
+        )
+        .await
+        .map_err(|e| format!("Creating btrfs image subvolume failed: {}", e));
+        rollback.check(create_res).await?;
+
+        let undo_subvolume = subvolume.clone();
+        rollback.push(
+            format!("create btrfs subvolume {:?}", subvolume),
+            async move { delete_subvolume(&undo_subvolume).await },
+        );
+
+        Ok(ImageVolume {
+            image: subvolume.to_string_lossy().into_owned(),
+            path: subvolume,
+        })
+    }
+
+    async fn seal_image(&self, image: &ImageVolume) -> Result<(), String> {
+        command_output(
+            "btrfs",
+            &["property", "set", "-ts", &image.image, "ro", "true"],
+        )
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("Marking btrfs image subvolume read-only failed: {}", e))
+    }
+
+    async fn destroy_image(&self, image: &str) -> Result<bool, String> {
+        // Snapshots do not depend on the subvolume they were taken of:
+        delete_subvolume(Path::new(image)).await?;
+        Ok(true)
+    }
+
+    async fn usage_percent(&self) -> Result<u8, String> {
+        super::statvfs_usage_percent(&self.config.subvolume_base)
+    }
+
+    fn mount_base(&self) -> Option<&Path> {
+        None
+    }
+}
@@ -0,0 +1,176 @@
+//! Providers of job root file systems.
+//!
+//! Every environment of this runner configures a storage backend which
+//! creates a fresh root file system per job (e.g., by cloning a ZFS dataset or
+//! snapshotting a btrfs subvolume). These backends implement the
+//! [`RootFsProvider`] trait, such that the remainder of the runner (job
+//! lifecycle, garbage collection, retention) is agnostic to the backend used.
+
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use tokio::process::Command;
+use uuid::Uuid;
+
+use treadmill_rs::api::coord_runner::sse as sse_api;
+use treadmill_rs::rollback::Rollback;
+
+pub mod btrfs;
+pub mod overlay;
+pub mod zfs;
+
+/// A job's root file system, as created by [`RootFsProvider::allocate`].
+pub struct AllocatedRootFs {
+    /// Path to pass to the container as its root directory.
+    pub mountpoint: PathBuf,
+    /// Backend-specific identifier of the volume holding the root file
+    /// system, e.g., a ZFS dataset name.
+    pub volume: String,
+}
+
+/// A volume managed by a [`RootFsProvider`], which may or may not belong to a
+/// job that is still known to the runner.
+pub struct RootFsVolume {
+    pub job_id: Uuid,
+    pub volume: String,
+    /// The environment the volume was created for, if recorded.
+    pub environment_id: Option<Uuid>,
+    /// When the volume was retained after its job had stopped, in seconds
+    /// since the UNIX epoch. Volumes which have not been retained may still
+    /// be in use.
+    pub retained: Option<u64>,
+}
+
+/// A versioned base image of an environment, as created by
+/// [`RootFsProvider::create_image`].
+pub struct ImageVolume {
+    /// Backend-specific identifier of the image, from which job root file
+    /// systems are created by [`RootFsProvider::allocate`].
+    pub image: String,
+    /// Writable directory to populate the image in, until it is sealed.
+    pub path: PathBuf,
+}
+
+#[async_trait]
+pub trait RootFsProvider: Send + Sync {
+    /// Create a root file system for a job and make it available under the
+    /// returned mountpoint. Every step registers its undo action with the
+    /// provided [`Rollback`].
+    ///
+    /// If `image` is set, the root file system is based on this imported
+    /// image, instead of the base configured for the environment.
+    async fn allocate(
+        &self,
+        job_id: Uuid,
+        image: Option<&str>,
+        rollback: &mut Rollback,
+    ) -> Result<AllocatedRootFs, String>;
+
+    /// Release a job's root file system after it has been stopped, retaining
+    /// its data as requested. Retained volumes are marked as such, and are
+    /// eventually destroyed through [`RootFsProvider::destroy`].
+    async fn release(
+        &self,
+        mountpoint: &Path,
+        volume: &str,
+        retention: &sse_api::JobDataRetention,
+    ) -> Result<(), String>;
+
+    /// Destroy a volume that is no longer used, including any snapshots of
+    /// it.
+    async fn destroy(&self, volume: &str) -> Result<(), String>;
+
+    /// Unmount a volume of an unknown job without destroying it, if it is not
+    /// mounted under [`RootFsProvider::mount_base`]. Returns whether anything
+    /// was unmounted.
+    async fn unmount(&self, _volume: &str) -> Result<bool, String> {
+        Ok(false)
+    }
+
+    /// List all per-job volumes managed by this provider. This includes
+    /// volumes of other environments sharing the same storage location.
+    async fn volumes(&self) -> Result<Vec<RootFsVolume>, String>;
+
+    /// Space occupied by a volume in bytes, if the backend can determine it
+    /// cheaply.
+    async fn used_bytes(&self, _volume: &str) -> Result<Option<u64>, String> {
+        Ok(None)
+    }
+
+    /// Create an empty image of the given version for this provider's
+    /// environment, to be populated under [`ImageVolume::path`]. Every step
+    /// registers its undo action with the provided [`Rollback`].
+    async fn create_image(
+        &self,
+        version: &str,
+        rollback: &mut Rollback,
+    ) -> Result<ImageVolume, String>;
+
+    /// Make a populated image immutable, such that jobs can be based on it.
+    async fn seal_image(&self, image: &ImageVolume) -> Result<(), String>;
+
+    /// Destroy an image that is no longer selected by jobs. Returns `false`
+    /// and keeps the image if volumes still depend on it.
+    async fn destroy_image(&self, image: &str) -> Result<bool, String>;
+
+    /// Percentage of the capacity of the underlying storage in use.
+    async fn usage_percent(&self) -> Result<u8, String>;
+
+    /// Directory under which root file systems are mounted, if they must be
+    /// mounted separately from being created.
+    fn mount_base(&self) -> Option<&Path>;
+}
+
+/// Run an external command, returning its standard output.
+pub async fn command_output(cmd: &str, args: &[&str]) -> Result<String, String> {
+    match Command::new(cmd).args(args).output().await {
+        Ok(std::process::Output {
+            status,
+            stdout,
+            stderr,
+        }) => {
+            if !status.success() {
+                Err(format!(
+                    "Running \"{} {:?}\" failed with exit-status {:?}. \
+                     Stdout: {}, Stderr: {}",
+                    cmd,
+                    args,
+                    status.code(),
+                    String::from_utf8_lossy(&stdout),
+                    String::from_utf8_lossy(&stderr)
+                ))
+            } else {
+                Ok(String::from_utf8_lossy(&stdout).into_owned())
+            }
+        }
+        Err(e) => Err(format!("Running \"{} {:?}\" failed: {:?}", cmd, args, e)),
+    }
+}
+
+/// Name of the volume holding an image of an environment. This never parses
+/// as a job ID, such that images are not mistaken for per-job volumes.
+pub fn image_volume_name(environment_id: Uuid, version: &str) -> String {
+    format!("image-{}-{}", environment_id, version)
+}
+
+/// Current time in seconds since the UNIX epoch, used to record when a volume
+/// was retained.
+pub fn unix_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Percentage of the capacity of the file system holding `path` in use.
+pub fn statvfs_usage_percent(path: &Path) -> Result<u8, String> {
+    let stat = nix::sys::statvfs::statvfs(path)
+        .map_err(|e| format!("Failed to query file system usage of {:?}: {:?}", path, e))?;
+
+    // Same as df(1), disregarding blocks reserved for the superuser:
+    let used = stat.blocks().saturating_sub(stat.blocks_free());
+    let usable = used + stat.blocks_available();
+    if usable == 0 {
+        return Ok(0);
+    }
+    Ok((used * 100).div_ceil(usable) as u8)
+}
@@ -0,0 +1,396 @@
+//! Root file systems as overlayfs mounts over shared, read-only base
+//! directories.
+//!
+//! Every job receives a directory `<state_base>/<job_id>`, holding the
+//! overlay's upper and work directories under `storage` (optionally on a
+//! size-capped tmpfs), the merged root file system under `root`, and a
+//! `volume.json` recording the environment, image and retention state.
+//!
+//! Imported images are plain directories in `<state_base>`, used as the
+//! single lower directory of the jobs based on them.
+
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use treadmill_rs::api::coord_runner::sse as sse_api;
+use treadmill_rs::rollback::Rollback;
+
+use super::{
+    command_output, image_volume_name, unix_timestamp, AllocatedRootFs, ImageVolume,
+    RootFsProvider, RootFsVolume,
+};
+use crate::{has_mountpoint_below, is_mountpoint};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NspawnRunnerEnvironmentOverlayRootConfig {
+    /// Read-only lower directories, from the top-most to the bottom-most
+    /// layer. These are shared between all jobs not based on an imported
+    /// image.
+    #[serde(default)]
+    lower: Vec<PathBuf>,
+    /// Directory to create per-job upper, work and root directories in.
+    state_base: PathBuf,
+    /// If set, place each job's upper and work directories on a tmpfs of
+    /// this size (e.g., "2G"), instead of the file system of `state_base`.
+    /// The job's data is then discarded when it stops, regardless of the
+    /// requested retention.
+    #[serde(default)]
+    tmpfs_size: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct OverlayVolumeMetadata {
+    environment_id: Uuid,
+    /// Image used as the lower directory, which must be kept as long as
+    /// this volume exists.
+    #[serde(default)]
+    image: Option<String>,
+    retained: Option<u64>,
+}
+
+pub struct OverlayRootFsProvider<'a> {
+    environment_id: Uuid,
+    config: &'a NspawnRunnerEnvironmentOverlayRootConfig,
+}
+
+impl<'a> OverlayRootFsProvider<'a> {
+    pub fn new(environment_id: Uuid, config: &'a NspawnRunnerEnvironmentOverlayRootConfig) -> Self {
+        OverlayRootFsProvider {
+            environment_id,
+            config,
+        }
+    }
+
+    async fn write_metadata(
+        &self,
+        job_dir: &Path,
+        image: Option<String>,
+        retained: Option<u64>,
+    ) -> Result<(), String> {
+        let path = job_dir.join("volume.json");
+        let tmp_path = path.with_extension("tmp");
+        let res: std::io::Result<()> = async {
+            let serialized = serde_json::to_vec(&OverlayVolumeMetadata {
+                environment_id: self.environment_id,
+                image,
+                retained,
+            })
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            tokio::fs::write(&tmp_path, serialized).await?;
+            tokio::fs::rename(&tmp_path, &path).await
+        }
+        .await;
+
+        res.map_err(|e| format!("Failed to write volume metadata {:?}: {:?}", path, e))
+    }
+}
+
+async fn read_metadata(job_dir: &Path) -> Option<OverlayVolumeMetadata> {
+    let serialized = tokio::fs::read(job_dir.join("volume.json")).await.ok()?;
+    serde_json::from_slice(&serialized).ok()
+}
+
+async fn unmount(mountpoint: &Path) -> Result<(), String> {
+    command_output("umount", &[&mountpoint.to_string_lossy()])
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("Unmounting {:?} failed: {}", mountpoint, e))
+}
+
+/// Unmount the merged root file system and the job's tmpfs, if mounted.
+/// Returns whether anything was unmounted.
+async fn unmount_job_dir(job_dir: &Path) -> Result<bool, String> {
+    let mut unmounted = false;
+    for mountpoint in [job_dir.join("root"), job_dir.join("storage")] {
+        if is_mountpoint(&mountpoint).map_err(|e| format!("Failed to read mount table: {:?}", e))? {
+            unmount(&mountpoint).await?;
+            unmounted = true;
+        }
+    }
+    Ok(unmounted)
+}
+
+/// Remove a job's directory along with all of its data. Refuses to do so
+/// while any file system is mounted below it, which would remove the data of
+/// the mounted file system (e.g., the image of an overlay) as well.
+async fn remove_job_dir(job_dir: &Path) -> Result<(), String> {
+    if has_mountpoint_below(job_dir).map_err(|e| format!("Failed to read mount table: {:?}", e))? {
+        return Err(format!(
+            "Refusing to remove overlay directory {:?}, file systems are still mounted below it",
+            job_dir
+        ));
+    }
+
+    match tokio::fs::remove_dir_all(job_dir).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(format!(
+            "Failed to remove overlay directory {:?}: {:?}",
+            job_dir, e
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[async_trait]
+impl RootFsProvider for OverlayRootFsProvider<'_> {
+    async fn allocate(
+        &self,
+        job_id: Uuid,
+        image: Option<&str>,
+        rollback: &mut Rollback,
+    ) -> Result<AllocatedRootFs, String> {
+        let lower = match image {
+            Some(image) => vec![PathBuf::from(image)],
+            None => self.config.lower.clone(),
+        };
+        if lower.is_empty() {
+            return Err("No lower directories configured for overlay root.".to_string());
+        }
+
+        let job_dir = self.config.state_base.join(job_id.to_string());
+        let storage_dir = job_dir.join("storage");
+        let root_dir = job_dir.join("root");
+
+        let create_res = tokio::fs::create_dir_all(&storage_dir)
+            .await
+            .map_err(|e| format!("Failed to create overlay directory {:?}: {:?}", job_dir, e));
+        rollback.check(create_res).await?;
+
+        // Removing the job's directory discards all of its data. This is only
+        // executed after the overlay and tmpfs have been unmounted, as the
+        // rollback stops at a failed unmount:
+        let undo_job_dir = job_dir.clone();
+        rollback.push(
+            format!("create overlay directory {:?}", job_dir),
+            async move { remove_job_dir(&undo_job_dir).await },
+        );
+
+        if let Some(ref tmpfs_size) = self.config.tmpfs_size {
+            let mount_res = command_output(
+                "mount",
+                &[
+                    "-t",
+                    "tmpfs",
+                    "-o",
+                    &format!("size={},mode=0700", tmpfs_size),
+                    "tmpfs",
+                    &storage_dir.to_string_lossy(),
+                ],
+            )
+            .await
+            .map_err(|e| format!("Mounting overlay tmpfs failed: {}", e));
+            rollback.check(mount_res).await?;
+
+            let undo_storage_dir = storage_dir.clone();
+            rollback.push(format!("mount tmpfs at {:?}", storage_dir), async move {
+                unmount(&undo_storage_dir).await
+            });
+        }
+
+        let upper_dir = storage_dir.join("upper");
+        let work_dir = storage_dir.join("work");
+        let mut create_res = Ok(());
+        for dir in [&upper_dir, &work_dir, &root_dir] {
+            if let Err(e) = tokio::fs::create_dir_all(dir).await {
+                create_res = Err(format!(
+                    "Failed to create overlay directory {:?}: {:?}",
+                    dir, e
+                ));
+                break;
+            }
+        }
+        rollback.check(create_res).await?;
+
+        // Record the environment, to apply its retention policy:
+        let metadata_res = self
+            .write_metadata(&job_dir, image.map(str::to_string), None)
+            .await;
+        rollback.check(metadata_res).await?;
+
+        // Colons separate lower directories, and commas mount options:
+        let mut lower_dirs = vec![];
+        for lower in lower.iter() {
+            let lower = lower.to_string_lossy();
+            if lower.contains([':', ',']) {
+                return rollback
+                    .check(Err(format!(
+                        "Unsupported overlay lower directory {:?}",
+                        lower
+                    )))
+                    .await;
+            }
+            lower_dirs.push(lower);
+        }
+
+        let mount_res = command_output(
+            "mount",
+            &[
+                "-t",
+                "overlay",
+                "-o",
+                &format!(
+                    "lowerdir={},upperdir={},workdir={}",
+                    lower_dirs.join(":"),
+                    upper_dir.to_string_lossy(),
+                    work_dir.to_string_lossy()
+                ),
+                "overlay",
+                &root_dir.to_string_lossy(),
+            ],
+        )
+        .await
+        .map_err(|e| format!("Mounting overlay root filesystem failed: {}", e));
+        rollback.check(mount_res).await?;
+
+        let undo_root_dir = root_dir.clone();
+        rollback.push(format!("mount overlay at {:?}", root_dir), async move {
+            unmount(&undo_root_dir).await
+        });
+
+        Ok(AllocatedRootFs {
+            mountpoint: root_dir,
+            volume: job_dir.to_string_lossy().into_owned(),
+        })
+    }
+
+    async fn release(
+        &self,
+        _mountpoint: &Path,
+        volume: &str,
+        retention: &sse_api::JobDataRetention,
+    ) -> Result<(), String> {
+        let job_dir = Path::new(volume);
+        unmount_job_dir(job_dir).await?;
+
+        // Data on a tmpfs is gone once it is unmounted:
+        if *retention == sse_api::JobDataRetention::Delete || self.config.tmpfs_size.is_some() {
+            return remove_job_dir(job_dir).await;
+        }
+
+        // The upper directory already holds the final state of the job's
+        // root file system, there is nothing more to snapshot.
+        if *retention == sse_api::JobDataRetention::Snapshot {
+            warn!(
+                "Overlay root {:?} is retained as is, snapshots are not supported",
+                job_dir
+            );
+        }
+
+        // Hand the volume over to the retention reaper:
+        let image = read_metadata(job_dir).await.and_then(|m| m.image);
+        self.write_metadata(job_dir, image, Some(unix_timestamp()))
+            .await
+    }
+
+    async fn destroy(&self, volume: &str) -> Result<(), String> {
+        let job_dir = Path::new(volume);
+        unmount_job_dir(job_dir).await?;
+        remove_job_dir(job_dir).await
+    }
+
+    async fn unmount(&self, volume: &str) -> Result<bool, String> {
+        unmount_job_dir(Path::new(volume)).await
+    }
+
+    async fn volumes(&self) -> Result<Vec<RootFsVolume>, String> {
+        let mut dir_entries = match tokio::fs::read_dir(&self.config.state_base).await {
+            Ok(dir_entries) => dir_entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => {
+                return Err(format!(
+                    "Failed to read overlay state base {:?}: {:?}",
+                    self.config.state_base, e
+                ))
+            }
+        };
+
+        let mut volumes = vec![];
+        while let Some(dir_entry) = dir_entries.next_entry().await.map_err(|e| {
+            format!(
+                "Failed to read overlay state base {:?}: {:?}",
+                self.config.state_base, e
+            )
+        })? {
+            let Some(job_id) = dir_entry
+                .file_name()
+                .to_str()
+                .and_then(|name| Uuid::parse_str(name).ok())
+            else {
+                continue;
+            };
+
+            let job_dir = dir_entry.path();
+            let metadata = read_metadata(&job_dir).await;
+            volumes.push(RootFsVolume {
+                job_id,
+                volume: job_dir.to_string_lossy().into_owned(),
+                environment_id: metadata.as_ref().map(|m| m.environment_id),
+                retained: metadata.and_then(|m| m.retained),
+            });
+        }
+
+        Ok(volumes)
+    }
+
+    async fn create_image(
+        &self,
+        version: &str,
+        rollback: &mut Rollback,
+    ) -> Result<ImageVolume, String> {
+        let image_dir = self
+            .config
+            .state_base
+            .join(image_volume_name(self.environment_id, version));
+
+        let create_res = tokio::fs::create_dir(&image_dir)
+            .await
+            .map_err(|e| format!("Failed to create image directory {:?}: {:?}", image_dir, e));
+        rollback.check(create_res).await?;
+
+        let undo_image_dir = image_dir.clone();
+        rollback.push(
+            format!("create image directory {:?}", image_dir),
+            async move { remove_job_dir(&undo_image_dir).await },
+        );
+
+        Ok(ImageVolume {
+            image: image_dir.to_string_lossy().into_owned(),
+            path: image_dir,
+        })
+    }
+
+    async fn seal_image(&self, image: &ImageVolume) -> Result<(), String> {
+        // Colons separate lower directories, and commas mount options:
+        if image.image.contains([':', ',']) {
+            return Err(format!("Unsupported overlay image path {:?}", image.image));
+        }
+
+        // Lower directories are never written to through the overlay:
+        Ok(())
+    }
+
+    async fn destroy_image(&self, image: &str) -> Result<bool, String> {
+        // The upper directories of existing volumes are only meaningful on top
+        // of the image they were created with:
+        for volume in self.volumes().await? {
+            let metadata = read_metadata(Path::new(&volume.volume)).await;
+            if metadata.and_then(|m| m.image).as_deref() == Some(image) {
+                return Ok(false);
+            }
+        }
+
+        remove_job_dir(Path::new(image)).await?;
+        Ok(true)
+    }
+
+    async fn usage_percent(&self) -> Result<u8, String> {
+        super::statvfs_usage_percent(&self.config.state_base)
+    }
+
+    fn mount_base(&self) -> Option<&Path> {
+        None
+    }
+}
@@ -0,0 +1,395 @@
+//! Root file systems as ZFS datasets, created or cloned per job.
+
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use treadmill_rs::api::coord_runner::sse as sse_api;
+use treadmill_rs::rollback::Rollback;
+
+use super::{
+    command_output, image_volume_name, unix_timestamp, AllocatedRootFs, ImageVolume,
+    RootFsProvider, RootFsVolume,
+};
+use crate::is_mountpoint;
+
+/// ZFS user property recording the environment a dataset was created for.
+const ZFS_ENVIRONMENT_PROPERTY: &str = "treadmill:environment";
+
+/// Snapshot of an image dataset that job root file systems are cloned from.
+const ZFS_IMAGE_SNAPSHOT: &str = "base";
+
+/// ZFS user property recording when a dataset was retained after its job had
+/// stopped, in seconds since the UNIX epoch.
+const ZFS_RETAINED_PROPERTY: &str = "treadmill:retained";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NspawnRunnerEnvironmentZfsRootConfig {
+    // Should contain full filesystem snapshot spec
+    clone_from: Option<String>,
+    parent: String,
+    mount_base: String,
+    quota: Option<String>,
+}
+
+pub struct ZfsRootFsProvider<'a> {
+    environment_id: Uuid,
+    config: &'a NspawnRunnerEnvironmentZfsRootConfig,
+}
+
+impl<'a> ZfsRootFsProvider<'a> {
+    pub fn new(environment_id: Uuid, config: &'a NspawnRunnerEnvironmentZfsRootConfig) -> Self {
+        ZfsRootFsProvider {
+            environment_id,
+            config,
+        }
+    }
+}
+
+async fn unmount(mountpoint: &Path) -> Result<(), String> {
+    command_output("umount", &[&mountpoint.to_string_lossy()])
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("Unmounting root filesystem failed: {}", e))
+}
+
+async fn destroy_dataset(dataset: &str) -> Result<(), String> {
+    command_output("zfs", &["destroy", "-r", "-v", dataset])
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("Destroying ZFS root filesystem failed: {}", e))
+}
+
+#[async_trait]
+impl RootFsProvider for ZfsRootFsProvider<'_> {
+    async fn allocate(
+        &self,
+        job_id: Uuid,
+        image: Option<&str>,
+        rollback: &mut Rollback,
+    ) -> Result<AllocatedRootFs, String> {
+        let clone_from = image.or(self.config.clone_from.as_deref());
+        let environment_property = format!("{}={}", ZFS_ENVIRONMENT_PROPERTY, self.environment_id);
+        let mut zfs_create_cmd = vec![
+            if clone_from.is_some() {
+                "clone"
+            } else {
+                "create"
+            },
+            "-o",
+            "mountpoint=legacy",
+            // Record the environment, to apply its retention policy:
+            "-o",
+            &environment_property,
+        ];
+
+        let quota_property = self
+            .config
+            .quota
+            .as_ref()
+            .map(|quota| format!("quota={}", quota));
+        if let Some(ref quota_property) = quota_property {
+            zfs_create_cmd.push("-o");
+            zfs_create_cmd.push(quota_property);
+        }
+
+        if let Some(source_fs) = clone_from {
+            zfs_create_cmd.push(source_fs);
+        }
+
+        let zfs_fs = format!("{}/{}", self.config.parent, job_id);
+        zfs_create_cmd.push(&zfs_fs);
+
+        // Create the file system:
+        let create_res = command_output("zfs", &zfs_create_cmd)
+            .await
+            .map_err(|e| format!("Creating ZFS root filesystem failed: {}", e));
+        rollback.check(create_res).await?;
+
+        // This file system was created for this job only, and does not hold
+        // any data yet. Destroy it if we fail to start the job:
+        let undo_zfs_fs = zfs_fs.clone();
+        rollback.push(format!("create ZFS dataset {:?}", zfs_fs), async move {
+            destroy_dataset(&undo_zfs_fs).await
+        });
+
+        // Mount the file system at the indicated path. First, create this path:
+        let mountpoint = PathBuf::from(format!("{}/{}", self.config.mount_base, job_id));
+        let create_dir_res = tokio::fs::create_dir_all(&mountpoint).await.map_err(|e| {
+            format!(
+                "Failed to create root mountpoint {:?}: {:?}",
+                &mountpoint, e,
+            )
+        });
+        rollback.check(create_dir_res).await?;
+
+        let undo_mountpoint = mountpoint.clone();
+        rollback.push(format!("create mountpoint {:?}", mountpoint), async move {
+            tokio::fs::remove_dir(&undo_mountpoint)
+                .await
+                .map_err(|e| format!("{:?}", e))
+        });
+
+        // Now, attempt to mount it:
+        let mount_res = command_output(
+            "mount",
+            &["-t", "zfs", &zfs_fs, &mountpoint.to_string_lossy()],
+        )
+        .await
+        .map_err(|e| format!("Mounting ZFS root filesystem failed: {}", e));
+        rollback.check(mount_res).await?;
+
+        let undo_mountpoint = mountpoint.clone();
+        rollback.push(
+            format!("mount root filesystem at {:?}", mountpoint),
+            async move { unmount(&undo_mountpoint).await },
+        );
+
+        Ok(AllocatedRootFs {
+            mountpoint,
+            volume: zfs_fs,
+        })
+    }
+
+    async fn release(
+        &self,
+        mountpoint: &Path,
+        volume: &str,
+        retention: &sse_api::JobDataRetention,
+    ) -> Result<(), String> {
+        // Unmount the container's root file system. It may not be mounted
+        // anymore when cleaning up a job after a reboot:
+        let is_mounted = is_mountpoint(mountpoint)
+            .map_err(|e| format!("Failed to read mount table: {:?}", e))?;
+        if is_mounted {
+            unmount(mountpoint).await?;
+        }
+
+        // The dataset uses a legacy mountpoint, so the mountpoint directory
+        // remains regardless of whether the dataset is retained:
+        match tokio::fs::remove_dir(mountpoint).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(format!(
+                    "Failed to remove root mountpoint {:?}: {:?}",
+                    mountpoint, e
+                ));
+            }
+            _ => (),
+        }
+
+        if *retention == sse_api::JobDataRetention::Delete {
+            return destroy_dataset(volume).await;
+        }
+
+        if *retention == sse_api::JobDataRetention::Snapshot {
+            command_output("zfs", &["snapshot", &format!("{}@final", volume)])
+                .await
+                .map_err(|e| format!("Snapshotting ZFS root filesystem failed: {}", e))?;
+        }
+
+        // Hand the dataset over to the retention reaper:
+        command_output(
+            "zfs",
+            &[
+                "set",
+                &format!("{}={}", ZFS_RETAINED_PROPERTY, unix_timestamp()),
+                volume,
+            ],
+        )
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("Marking ZFS root filesystem as retained failed: {}", e))
+    }
+
+    async fn destroy(&self, volume: &str) -> Result<(), String> {
+        destroy_dataset(volume).await
+    }
+
+    async fn volumes(&self) -> Result<Vec<RootFsVolume>, String> {
+        let properties = format!(
+            "name,{},{}",
+            ZFS_ENVIRONMENT_PROPERTY, ZFS_RETAINED_PROPERTY
+        );
+        let output = command_output(
+            "zfs",
+            &[
+                "list",
+                "-H",
+                "-o",
+                &properties,
+                "-t",
+                "filesystem",
+                "-d",
+                "1",
+                &self.config.parent,
+            ],
+        )
+        .await?;
+
+        Ok(output
+            .lines()
+            .filter_map(|line| {
+                let [name, environment_id, retained] = line.split('\t').collect::<Vec<_>>()[..]
+                else {
+                    return None;
+                };
+
+                let job_id = name
+                    .strip_prefix(self.config.parent.as_str())
+                    .and_then(|name| name.strip_prefix('/'))
+                    .and_then(|name| Uuid::parse_str(name).ok())?;
+
+                // Unset user properties are reported as "-", and fail to parse:
+                Some(RootFsVolume {
+                    job_id,
+                    volume: name.to_string(),
+                    environment_id: Uuid::parse_str(environment_id).ok(),
+                    retained: retained.parse().ok(),
+                })
+            })
+            .collect())
+    }
+
+    async fn used_bytes(&self, volume: &str) -> Result<Option<u64>, String> {
+        let output =
+            command_output("zfs", &["get", "-H", "-p", "-o", "value", "used", volume]).await?;
+        output
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|e| format!("Failed to parse space used by {:?}: {:?}", volume, e))
+    }
+
+    async fn create_image(
+        &self,
+        version: &str,
+        rollback: &mut Rollback,
+    ) -> Result<ImageVolume, String> {
+        let name = image_volume_name(self.environment_id, version);
+        let zfs_fs = format!("{}/{}", self.config.parent, name);
+        let environment_property = format!("{}={}", ZFS_ENVIRONMENT_PROPERTY, self.environment_id);
+
+        let create_res = command_output(
+            "zfs",
+            &[
+                "create",
+                "-o",
+                "mountpoint=legacy",
+                "-o",
+                &environment_property,
+                &zfs_fs,
+            ],
+        )
+        .await
+        .map_err(|e| format!("Creating ZFS image filesystem failed: {}", e));
+        rollback.check(create_res).await?;
+
+        let undo_zfs_fs = zfs_fs.clone();
+        rollback.push(format!("create ZFS dataset {:?}", zfs_fs), async move {
+            destroy_dataset(&undo_zfs_fs).await
+        });
+
+        // Populate the image through a temporary mount. Its name never parses
+        // as a job ID, and is thus ignored by the garbage collector:
+        let mountpoint = Path::new(&self.config.mount_base).join(&name);
+        let create_dir_res = tokio::fs::create_dir_all(&mountpoint).await.map_err(|e| {
+            format!(
+                "Failed to create image mountpoint {:?}: {:?}",
+                &mountpoint, e,
+            )
+        });
+        rollback.check(create_dir_res).await?;
+
+        let undo_mountpoint = mountpoint.clone();
+        rollback.push(format!("create mountpoint {:?}", mountpoint), async move {
+            match tokio::fs::remove_dir(&undo_mountpoint).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(format!("{:?}", e)),
+                _ => Ok(()),
+            }
+        });
+
+        let mount_res = command_output(
+            "mount",
+            &["-t", "zfs", &zfs_fs, &mountpoint.to_string_lossy()],
+        )
+        .await
+        .map_err(|e| format!("Mounting ZFS image filesystem failed: {}", e));
+        rollback.check(mount_res).await?;
+
+        let undo_mountpoint = mountpoint.clone();
+        rollback.push(
+            format!("mount image filesystem at {:?}", mountpoint),
+            async move {
+                if is_mountpoint(&undo_mountpoint).map_err(|e| format!("{:?}", e))? {
+                    unmount(&undo_mountpoint).await?;
+                }
+                Ok(())
+            },
+        );
+
+        Ok(ImageVolume {
+            image: format!("{}@{}", zfs_fs, ZFS_IMAGE_SNAPSHOT),
+            path: mountpoint,
+        })
+    }
+
+    async fn seal_image(&self, image: &ImageVolume) -> Result<(), String> {
+        let (zfs_fs, _) = image
+            .image
+            .split_once('@')
+            .ok_or_else(|| format!("Invalid ZFS image {:?}", image.image))?;
+
+        unmount(&image.path).await?;
+        tokio::fs::remove_dir(&image.path).await.map_err(|e| {
+            format!(
+                "Failed to remove image mountpoint {:?}: {:?}",
+                image.path, e
+            )
+        })?;
+
+        command_output("zfs", &["set", "readonly=on", zfs_fs])
+            .await
+            .map_err(|e| format!("Marking ZFS image filesystem read-only failed: {}", e))?;
+        command_output("zfs", &["snapshot", &image.image])
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("Snapshotting ZFS image filesystem failed: {}", e))
+    }
+
+    async fn destroy_image(&self, image: &str) -> Result<bool, String> {
+        let (zfs_fs, _) = image
+            .split_once('@')
+            .ok_or_else(|| format!("Invalid ZFS image {:?}", image))?;
+
+        // Retained job datasets are clones of the image's snapshot, and
+        // prevent it from being destroyed:
+        let clones = command_output("zfs", &["list", "-H", "-o", "clones", image]).await?;
+        if !matches!(clones.trim(), "" | "-") {
+            return Ok(false);
+        }
+
+        destroy_dataset(zfs_fs).await?;
+        Ok(true)
+    }
+
+    async fn usage_percent(&self) -> Result<u8, String> {
+        let pool = self
+            .config
+            .parent
+            .split('/')
+            .next()
+            .unwrap_or(&self.config.parent);
+        let output = command_output("zpool", &["list", "-H", "-p", "-o", "capacity", pool]).await?;
+        output
+            .trim()
+            .trim_end_matches('%')
+            .parse()
+            .map_err(|e| format!("Failed to parse capacity of pool {:?}: {:?}", pool, e))
+    }
+
+    fn mount_base(&self) -> Option<&Path> {
+        Some(Path::new(&self.config.mount_base))
+    }
+}
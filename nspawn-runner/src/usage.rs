@@ -0,0 +1,216 @@
+//! Resource usage accounting of jobs.
+//!
+//! The usage of a job's container is read from the cgroup of its scope unit
+//! (`cpu.stat`, `memory.peak` and `io.stat`), the space occupied by its root
+//! file system from the [`RootFsProvider`], and the packets dropped by its
+//! egress policy from the host's firewall. Usage is reported periodically
+//! while the job runs, and as part of the job's final state. As the cgroup
+//! disappears once the container has exited, the last sample taken is
+//! retained.
+//!
+//! [`RootFsProvider`]: crate::rootfs::RootFsProvider
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, warn};
+use tokio::process::Command;
+
+use treadmill_rs::api::coord_runner::rest as rest_api;
+use treadmill_rs::executor::JobDriver;
+
+use crate::{NspawnRunner, NspawnRunnerJob, NspawnRunnerJobMetadata};
+
+/// Resource usage of a job, sampled from its container's cgroup.
+#[derive(Default)]
+pub struct UsageTracker {
+    cgroup_dir: std::sync::Mutex<Option<PathBuf>>,
+    last: std::sync::Mutex<rest_api::JobResourceUsage>,
+}
+
+/// Directory of the cgroup v2 of a systemd unit. Returns `None` if the unit
+/// is not loaded (yet).
+async fn unit_cgroup_dir(unit_name: &str) -> Result<Option<PathBuf>, String> {
+    let output = Command::new("systemctl")
+        .args(["show", "--property", "ControlGroup", "--value", unit_name])
+        .output()
+        .await
+        .map_err(|e| format!("Running \"systemctl\" failed: {:?}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "systemctl show failed with exit-status {:?}. Stderr: {}",
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    let path = String::from_utf8_lossy(&output.stdout);
+    let path = path.trim();
+    Ok((!path.is_empty()).then(|| Path::new("/sys/fs/cgroup").join(path.trim_start_matches('/'))))
+}
+
+/// Parse a file of `key value` lines, such as `cpu.stat`.
+fn flat_keyed_value(contents: &str, key: &str) -> Option<u64> {
+    contents.lines().find_map(|line| {
+        let (line_key, value) = line.split_once(' ')?;
+        (line_key == key).then(|| value.trim().parse().ok())?
+    })
+}
+
+/// Sum up the `rbytes` and `wbytes` of all devices in `io.stat`.
+fn io_bytes(io_stat: &str) -> (u64, u64) {
+    let mut read = 0;
+    let mut written = 0;
+    for field in io_stat.split_whitespace() {
+        if let Some(bytes) = field.strip_prefix("rbytes=") {
+            read += bytes.parse::<u64>().unwrap_or(0);
+        } else if let Some(bytes) = field.strip_prefix("wbytes=") {
+            written += bytes.parse::<u64>().unwrap_or(0);
+        }
+    }
+    (read, written)
+}
+
+/// Read the usage of a cgroup. Returns `None` if the cgroup no longer exists.
+async fn read_cgroup_usage(cgroup_dir: &Path) -> Option<rest_api::JobResourceUsage> {
+    let cpu_stat = tokio::fs::read_to_string(cgroup_dir.join("cpu.stat"))
+        .await
+        .ok()?;
+
+    // Only reported by Linux 5.19 and newer:
+    let memory_peak_bytes = tokio::fs::read_to_string(cgroup_dir.join("memory.peak"))
+        .await
+        .ok()
+        .and_then(|peak| peak.trim().parse().ok());
+
+    let io = tokio::fs::read_to_string(cgroup_dir.join("io.stat"))
+        .await
+        .ok()
+        .map(|io_stat| io_bytes(&io_stat));
+
+    Some(rest_api::JobResourceUsage {
+        cpu_usage_usec: flat_keyed_value(&cpu_stat, "usage_usec"),
+        cpu_user_usec: flat_keyed_value(&cpu_stat, "user_usec"),
+        cpu_system_usec: flat_keyed_value(&cpu_stat, "system_usec"),
+        memory_peak_bytes,
+        io_read_bytes: io.map(|(read, _)| read),
+        io_write_bytes: io.map(|(_, written)| written),
+        disk_used_bytes: None,
+        egress_denied_packets: None,
+    })
+}
+
+impl UsageTracker {
+    /// Locate the cgroup of the job's scope unit, unless already known. The
+    /// container's processes are spread across child cgroups of the scope,
+    /// which the scope's usage includes.
+    async fn attach(&self, unit_name: &str) {
+        if self.cgroup_dir.lock().unwrap().is_some() {
+            return;
+        }
+        match unit_cgroup_dir(unit_name).await {
+            Ok(dir) => *self.cgroup_dir.lock().unwrap() = dir,
+            Err(e) => warn!("Failed to determine cgroup of unit {:?}: {}", unit_name, e),
+        }
+    }
+
+    /// Sample the current usage of a job, falling back to the last sample of
+    /// its cgroup if it has exited.
+    pub async fn sample(&self, metadata: &NspawnRunnerJobMetadata) -> rest_api::JobResourceUsage {
+        // The scope unit may not have been registered yet when the container
+        // was started:
+        if let Some(ref unit_name) = metadata.unit_name {
+            self.attach(unit_name).await;
+        }
+
+        let cgroup_dir = self.cgroup_dir.lock().unwrap().clone();
+        if let Some(cgroup_dir) = cgroup_dir {
+            if let Some(usage) = read_cgroup_usage(&cgroup_dir).await {
+                // Never report a lower peak than previously sampled:
+                let mut last = self.last.lock().unwrap();
+                let memory_peak_bytes = usage.memory_peak_bytes.max(last.memory_peak_bytes);
+                *last = rest_api::JobResourceUsage {
+                    memory_peak_bytes,
+                    egress_denied_packets: last.egress_denied_packets,
+                    ..usage
+                };
+            }
+        }
+
+        let mut usage = self.last.lock().unwrap().clone();
+        usage.disk_used_bytes = match NspawnRunner::root_fs_provider(
+            metadata.environment_id,
+            &metadata.environment_config,
+        ) {
+            Ok(root_fs_provider) => root_fs_provider
+                .used_bytes(&metadata.root_fs_volume)
+                .await
+                .unwrap_or_else(|e| {
+                    warn!("{}", e);
+                    None
+                }),
+            Err(e) => {
+                warn!("{}", e);
+                None
+            }
+        };
+
+        // The counter is removed along with the job's firewall rules:
+        if let (Some(host_network_cfg), Some(_)) =
+            (&metadata.environment_config.host_network, &metadata.network)
+        {
+            match host_network_cfg
+                .egress_denied_packets(metadata.job_id)
+                .await
+            {
+                Ok(Some(packets)) => {
+                    self.last.lock().unwrap().egress_denied_packets = Some(packets);
+                    usage.egress_denied_packets = Some(packets);
+                }
+                Ok(None) => (),
+                Err(e) => warn!("{}", e),
+            }
+        }
+
+        usage
+    }
+}
+
+impl NspawnRunner {
+    /// Periodically report the resource usage of a running job.
+    pub fn spawn_usage_reporter(
+        &self,
+        driver: &Arc<JobDriver<Self>>,
+        job: &NspawnRunnerJob,
+    ) -> tokio::task::JoinHandle<()> {
+        let driver = driver.clone();
+        let usage = job.usage.clone();
+        let metadata = job.metadata.clone();
+        let interval = Duration::from_secs(self.config.usage_report_interval);
+        tokio::spawn(async move {
+            // Locate the cgroup right away, such that the usage of jobs
+            // exiting before the first report is still accounted for:
+            if let Some(ref unit_name) = metadata.unit_name {
+                usage.attach(unit_name).await;
+            }
+
+            loop {
+                tokio::time::sleep(interval).await;
+
+                let sample = usage.sample(&metadata).await;
+                debug!("Resource usage of job {:?}: {:?}", metadata.job_id, sample);
+                if let Err(e) = driver
+                    .connector()
+                    .post_job_usage(metadata.job_id, sample)
+                    .await
+                {
+                    warn!(
+                        "Failed to report resource usage of job {:?}: {:?}",
+                        metadata.job_id, e
+                    );
+                }
+            }
+        })
+    }
+}
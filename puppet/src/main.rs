@@ -12,10 +12,21 @@ use tokio::sync::Mutex;
 use tokio_seqpacket::UnixSeqpacket;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
+mod user_accounts;
+
 use treadmill_rs::api::runner_puppet::{
-    NetworkConfig, PuppetEvent, PuppetMsg, PuppetReq, RunnerMsg, RunnerResp,
+    NetworkConfig, PuppetEvent, PuppetMsg, PuppetReq, RunnerEvent, RunnerMsg, RunnerResp,
+    SSHHostKey, SSHUserCAConfig, UserAccount,
 };
 
+/// Request ID counter and map of outstanding requests to their (eventual)
+/// responses, shared between a control socket client and its receive task.
+type RequestResponses = Arc<Mutex<(u64, HashMap<u64, Option<RunnerResp>>)>>;
+
+/// Events received from the runner, forwarded by a control socket client's
+/// receive task.
+type RunnerEvents = tokio::sync::mpsc::UnboundedSender<RunnerEvent>;
+
 enum UnixSeqpacketControlSocketClientTaskCmd {
     Shutdown,
 }
@@ -23,7 +34,7 @@ enum UnixSeqpacketControlSocketClientTaskCmd {
 struct UnixSeqpacketControlSocketClient {
     socket: Arc<UnixSeqpacket>,
     puppet_event_cnt: Mutex<u64>,
-    request_responses: Arc<Mutex<(u64, HashMap<u64, Option<RunnerResp>>)>>,
+    request_responses: RequestResponses,
     task_cmd_tx: tokio::sync::mpsc::Sender<UnixSeqpacketControlSocketClientTaskCmd>,
     task_notify: Arc<tokio::sync::Notify>,
     task_join_handle: tokio::task::JoinHandle<()>,
@@ -32,6 +43,7 @@ struct UnixSeqpacketControlSocketClient {
 impl UnixSeqpacketControlSocketClient {
     async fn new<P: AsRef<Path>>(
         unix_seqpacket_control_socket: P,
+        events: RunnerEvents,
     ) -> Result<UnixSeqpacketControlSocketClient> {
         let socket = Arc::new(
             UnixSeqpacket::connect(&unix_seqpacket_control_socket)
@@ -58,6 +70,7 @@ impl UnixSeqpacketControlSocketClient {
                 task_request_responses,
                 task_cmd_rx,
                 task_notify_task,
+                events,
             )
             .await
         });
@@ -83,9 +96,10 @@ impl UnixSeqpacketControlSocketClient {
 
     async fn task(
         socket: Arc<UnixSeqpacket>,
-        request_responses: Arc<Mutex<(u64, HashMap<u64, Option<RunnerResp>>)>>,
+        request_responses: RequestResponses,
         mut cmd_rx: tokio::sync::mpsc::Receiver<UnixSeqpacketControlSocketClientTaskCmd>,
         notify: Arc<tokio::sync::Notify>,
+        events: RunnerEvents,
     ) {
         let mut recv_buf = vec![0; 1024 * 1024];
 
@@ -136,8 +150,12 @@ impl UnixSeqpacketControlSocketClient {
                     runner_event_id,
                     event,
                 }) => {
-                    warn!("Received unhandled runner event with id {}: {:?}",
-                      runner_event_id, event);
+                    debug!("Received runner event with id {}: {:?}",
+                       runner_event_id, event);
+                    if let Err(e) = events.send(event) {
+                    warn!("Dropping runner event with id {}: {:?}",
+                          runner_event_id, e.0);
+                    }
                 }
 
                 Ok(RunnerMsg::Error {
@@ -232,14 +250,17 @@ enum TcpControlSocketClientTaskCmd {
 
 struct TcpControlSocketClient {
     puppet_event_cnt: Mutex<u64>,
-    request_responses: Arc<Mutex<(u64, HashMap<u64, Option<RunnerResp>>)>>,
+    request_responses: RequestResponses,
     task_cmd_tx: tokio::sync::mpsc::Sender<TcpControlSocketClientTaskCmd>,
     task_notify: Arc<tokio::sync::Notify>,
     task_join_handle: tokio::task::JoinHandle<()>,
 }
 
 impl TcpControlSocketClient {
-    async fn new(addr: std::net::SocketAddr) -> Result<TcpControlSocketClient> {
+    async fn new(
+        addr: std::net::SocketAddr,
+        events: RunnerEvents,
+    ) -> Result<TcpControlSocketClient> {
         let socket = TcpStream::connect(addr)
             .await
             .with_context(|| format!("Opening TCP control socket connection at {:?}", addr,))?;
@@ -257,6 +278,7 @@ impl TcpControlSocketClient {
                 task_request_responses,
                 task_cmd_rx,
                 task_notify_task,
+                events,
             )
             .await
         });
@@ -281,9 +303,10 @@ impl TcpControlSocketClient {
 
     async fn task(
         socket: TcpStream,
-        request_responses: Arc<Mutex<(u64, HashMap<u64, Option<RunnerResp>>)>>,
+        request_responses: RequestResponses,
         mut cmd_rx: tokio::sync::mpsc::Receiver<TcpControlSocketClientTaskCmd>,
         notify: Arc<tokio::sync::Notify>,
+        events: RunnerEvents,
     ) {
         use futures::SinkExt;
         use tokio_stream::StreamExt;
@@ -353,8 +376,12 @@ impl TcpControlSocketClient {
 			    runner_event_id,
 			    event,
 			}) => {
-			    warn!("Received unhandled runner event with id {}: {:?}",
-				  runner_event_id, event);
+			    debug!("Received runner event with id {}: {:?}",
+				   runner_event_id, event);
+			    if let Err(e) = events.send(event) {
+				warn!("Dropping runner event with id {}: {:?}",
+				      runner_event_id, e.0);
+			    }
 			}
 
 			Ok(RunnerMsg::Error {
@@ -490,6 +517,42 @@ impl ControlSocketClient {
         }
     }
 
+    pub async fn get_ssh_host_keys(&self) -> Vec<SSHHostKey> {
+        let resp = self.request(PuppetReq::SSHHostKeys).await;
+        match resp {
+            RunnerResp::SSHHostKeysResp { host_keys } => host_keys,
+            _ => {
+                panic!(
+                    "Invalid runner response to SSH host keys request: {:?}",
+                    resp
+                );
+            }
+        }
+    }
+
+    pub async fn get_ssh_user_ca(&self) -> SSHUserCAConfig {
+        let resp = self.request(PuppetReq::SSHUserCA).await;
+        match resp {
+            RunnerResp::SSHUserCA(ca) => ca,
+            _ => {
+                panic!("Invalid runner response to SSH user CA request: {:?}", resp);
+            }
+        }
+    }
+
+    pub async fn get_user_accounts(&self) -> Vec<UserAccount> {
+        let resp = self.request(PuppetReq::UserAccounts).await;
+        match resp {
+            RunnerResp::UserAccountsResp { user_accounts } => user_accounts,
+            _ => {
+                panic!(
+                    "Invalid runner response to user accounts request: {:?}",
+                    resp
+                );
+            }
+        }
+    }
+
     pub async fn get_network_config(&self) -> NetworkConfig {
         let resp = self.request(PuppetReq::NetworkConfig).await;
         match resp {
@@ -531,6 +594,96 @@ struct PuppetArgs {
 
     #[arg(long)]
     network_config_script: Option<PathBuf>,
+
+    /// Directory to install the job's SSH host keys into (e.g., /etc/ssh).
+    #[arg(long)]
+    ssh_host_keys_dir: Option<PathBuf>,
+
+    /// File to write the SSH user CA keys to, as referenced by the SSH
+    /// server's `TrustedUserCAKeys` option.
+    #[arg(long, requires = "authorized_principals_file")]
+    trusted_user_ca_keys_file: Option<PathBuf>,
+
+    /// File to write the principals accepted in SSH user certificates to, as
+    /// referenced by the SSH server's `AuthorizedPrincipalsFile` option.
+    #[arg(long, requires = "trusted_user_ca_keys_file")]
+    authorized_principals_file: Option<PathBuf>,
+
+    /// Create and update the job's personal user accounts.
+    #[arg(long)]
+    user_accounts: bool,
+
+    /// File recording the user accounts managed by the puppet.
+    #[arg(long, default_value = "/var/lib/treadmill-puppet/user-accounts")]
+    user_accounts_state_file: PathBuf,
+}
+
+/// Request the SSH keys and replace the authorized keys file with them. The
+/// file is replaced atomically, such that the SSH server never observes a
+/// partially written file when the keys are updated while the job runs.
+async fn install_authorized_keys(
+    client: &ControlSocketClient,
+    authorized_keys_file: &Path,
+) -> Result<()> {
+    let ssh_keys = client.get_ssh_keys().await;
+
+    // Create the authorized keys file's parent directories (if they don't
+    // exist) and dump the keys to a temporary file next to it:
+    if let Some(parent) = authorized_keys_file.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("Creating {:?}", parent))?;
+    }
+    user_accounts::replace_file(
+        authorized_keys_file,
+        ssh_keys.join("\n").as_bytes(),
+        0o600,
+        None,
+    )
+    .await?;
+
+    info!("Installed {} authorized SSH keys.", ssh_keys.len());
+    Ok(())
+}
+
+/// Request the user accounts and create or update them.
+async fn install_user_accounts(client: &ControlSocketClient, state_file: &Path) {
+    let user_accounts = client.get_user_accounts().await;
+    match user_accounts::sync_user_accounts(&user_accounts, state_file).await {
+        Ok(()) => info!("Installed {} user accounts.", user_accounts.len()),
+        Err(e) => error!("Failed to install user accounts: {:?}", e),
+    }
+}
+
+/// Request the SSH host keys and install them, replacing any existing keys of
+/// the same algorithms. Each key is written to a temporary file created with
+/// its final mode and renamed into place, such that the private keys are
+/// never readable by others than their owner.
+async fn install_ssh_host_keys(client: &ControlSocketClient, ssh_host_keys_dir: &Path) {
+    let host_keys = client.get_ssh_host_keys().await;
+
+    let res: Result<()> = async {
+        tokio::fs::create_dir_all(ssh_host_keys_dir)
+            .await
+            .with_context(|| format!("Creating {:?}", ssh_host_keys_dir))?;
+        for host_key in host_keys.iter() {
+            let key_path = ssh_host_keys_dir.join(format!("ssh_host_{}_key", host_key.algorithm));
+            let pub_key_path = key_path.with_extension("pub");
+            for (path, contents, mode) in [
+                (&key_path, &host_key.private_key, 0o600),
+                (&pub_key_path, &host_key.public_key, 0o644),
+            ] {
+                user_accounts::replace_file(path, contents.as_bytes(), mode, None).await?;
+            }
+        }
+        Ok(())
+    }
+    .await;
+
+    match res {
+        Ok(()) => info!("Installed {} SSH host keys.", host_keys.len()),
+        Err(e) => error!("Failed to install SSH host keys: {:?}", e),
+    }
 }
 
 #[tokio::main]
@@ -548,29 +701,59 @@ async fn main() -> Result<()> {
 
     let args = PuppetArgs::parse();
 
+    let (events_tx, mut events_rx) = tokio::sync::mpsc::unbounded_channel();
+
     let client = match args.transport {
         PuppetControlSocketTransport::UnixSeqpacket => ControlSocketClient::UnixSeqpacket(
-            UnixSeqpacketControlSocketClient::new(&args.unix_seqpacket_control_socket.unwrap())
-                .await?,
+            UnixSeqpacketControlSocketClient::new(
+                &args.unix_seqpacket_control_socket.unwrap(),
+                events_tx,
+            )
+            .await?,
         ),
 
         PuppetControlSocketTransport::Tcp => ControlSocketClient::Tcp(
-            TcpControlSocketClient::new(args.tcp_control_socket_addr.unwrap()).await?,
+            TcpControlSocketClient::new(args.tcp_control_socket_addr.unwrap(), events_tx).await?,
         ),
     };
 
     if let Some(ref authorized_keys_file) = args.authorized_keys_file {
-        // Request the SSH keys:
-        let ssh_keys = client.get_ssh_keys().await;
+        if let Err(e) = install_authorized_keys(&client, authorized_keys_file).await {
+            error!("Failed to install authorized SSH keys: {:?}", e);
+        }
+    }
 
-        // Create the authorized keys file's parent directories (if they
-        // don't exist) and dump the keys to the file:
-        tokio::fs::create_dir_all(authorized_keys_file.parent().unwrap())
-            .await
-            .unwrap();
-        tokio::fs::write(authorized_keys_file, ssh_keys.join("\n").as_bytes())
-            .await
-            .unwrap();
+    if args.user_accounts {
+        install_user_accounts(&client, &args.user_accounts_state_file).await;
+    }
+
+    if let Some(ref ssh_host_keys_dir) = args.ssh_host_keys_dir {
+        install_ssh_host_keys(&client, ssh_host_keys_dir).await;
+    }
+
+    if let (Some(ca_keys_file), Some(principals_file)) = (
+        &args.trusted_user_ca_keys_file,
+        &args.authorized_principals_file,
+    ) {
+        // Request the trusted CAs and the principals that certificates must be
+        // issued for. Both files are always written, such that a stale CA
+        // configuration is removed if the runner no longer provides one:
+        let ca = client.get_ssh_user_ca().await;
+        if ca.trusted_user_ca_keys.is_empty() {
+            info!("Runner provided no SSH user CA, certificates are not accepted.");
+        }
+
+        for (path, lines) in [
+            (ca_keys_file, &ca.trusted_user_ca_keys),
+            (principals_file, &ca.authorized_principals),
+        ] {
+            tokio::fs::create_dir_all(path.parent().unwrap())
+                .await
+                .unwrap();
+            let mut contents = lines.join("\n");
+            contents.push('\n');
+            tokio::fs::write(path, contents.as_bytes()).await.unwrap();
+        }
     }
 
     // Request the network configuration, dump it into environment variables and
@@ -598,7 +781,7 @@ async fn main() -> Result<()> {
                 .map(|addr| format!("{}", addr))
                 // This is much cleaner with the nightly-only .intersperse
                 .fold(String::new(), |acc, nameserver| {
-                    let sep = if acc.len() != 0 { "|" } else { "" };
+                    let sep = if !acc.is_empty() { "|" } else { "" };
                     acc + sep + &nameserver
                 });
             cmd.env("IPV4_NAMESERVERS", nameserver_str);
@@ -616,7 +799,7 @@ async fn main() -> Result<()> {
                 .map(|addr| format!("{}", addr))
                 // This is much cleaner with the nightly-only .intersperse
                 .fold(String::new(), |acc, nameserver| {
-                    let sep = if acc.len() != 0 { "|" } else { "" };
+                    let sep = if !acc.is_empty() { "|" } else { "" };
                     acc + sep + &nameserver
                 });
             cmd.env("IPV6_NAMESERVERS", nameserver_str);
@@ -652,13 +835,38 @@ async fn main() -> Result<()> {
     client.report_ready().await;
 
     info!("Puppet started, waiting for CTRL+C");
-    match tokio::signal::ctrl_c().await {
-        Ok(()) => {
-            warn!("Received CTRL+C, shutting down!");
-        }
-        Err(err) => {
-            error!("Unable to listen for shutdown signal: {}", err);
-            // we also shut down in case of error
+    loop {
+        tokio::select! {
+            ctrl_c_res = tokio::signal::ctrl_c() => {
+                match ctrl_c_res {
+                    Ok(()) => {
+                        warn!("Received CTRL+C, shutting down!");
+                    }
+                    Err(err) => {
+                        error!("Unable to listen for shutdown signal: {}", err);
+                        // we also shut down in case of error
+                    }
+                }
+                break;
+            }
+
+            Some(event) = events_rx.recv() => match event {
+                RunnerEvent::SSHKeysUpdatedEvent { event_id } => {
+                    info!("SSH keys updated (event {}), re-installing them.", event_id);
+                    if let Some(ref authorized_keys_file) = args.authorized_keys_file {
+                        if let Err(e) = install_authorized_keys(&client, authorized_keys_file).await {
+                            error!("Failed to install authorized SSH keys: {:?}", e);
+                        }
+                    }
+                    if args.user_accounts {
+                        install_user_accounts(&client, &args.user_accounts_state_file).await;
+                    }
+                }
+
+                _ => {
+                    warn!("Received unhandled runner event: {:?}", event);
+                }
+            },
         }
     }
 
@@ -0,0 +1,375 @@
+//! Personal user accounts within the job's environment.
+//!
+//! Accounts are created and updated with the shadow utilities (`useradd`,
+//! `usermod`, `groupadd`), their SSH keys are installed into their home
+//! directories, and sudo rights are granted through a file in
+//! `/etc/sudoers.d`. Accounts that the runner no longer lists are not deleted,
+//! to retain their files for auditing, but expired and stripped of their keys
+//! and sudo rights. The accounts managed by the puppet are recorded in a state
+//! file, such that accounts of the environment's image are never touched:
+//! existing accounts which are not recorded, or are system accounts, are
+//! refused, as is membership in existing system groups.
+
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, error, info};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use treadmill_rs::api::runner_puppet::UserAccount;
+
+const SUDOERS_DIR: &str = "/etc/sudoers.d";
+const LOGIN_DEFS: &str = "/etc/login.defs";
+
+/// Lowest UID of regular user accounts, unless configured otherwise in
+/// `/etc/login.defs`.
+const DEFAULT_UID_MIN: u32 = 1000;
+
+/// Lowest GID of regular groups, unless configured otherwise in
+/// `/etc/login.defs`.
+const DEFAULT_GID_MIN: u32 = 1000;
+
+/// Entry of an account in the password database.
+struct Passwd {
+    uid: u32,
+    gid: u32,
+    home: PathBuf,
+}
+
+/// Restrict user and group names to the portable subset accepted by the
+/// shadow utilities, which is also safe to use in a sudoers file.
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 32
+        && name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+    if !valid {
+        bail!("Invalid user or group name {:?}", name);
+    }
+    Ok(())
+}
+
+/// Run a command, returning its standard output if it succeeded, and `None`
+/// if it exited with a non-zero status.
+async fn run(program: &str, args: &[&str]) -> Result<Option<String>> {
+    debug!("Executing {:?} with arguments {:?}", program, args);
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .with_context(|| format!("Running {:?}", program))?;
+    if output.status.success() {
+        Ok(Some(String::from_utf8_lossy(&output.stdout).to_string()))
+    } else {
+        debug!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        Ok(None)
+    }
+}
+
+/// Run a command which must succeed.
+async fn run_checked(program: &str, args: &[&str]) -> Result<()> {
+    match run(program, args).await? {
+        Some(_) => Ok(()),
+        None => bail!("{} {} failed", program, args.join(" ")),
+    }
+}
+
+/// Lowest ID of regular user accounts or groups (`UID_MIN` or `GID_MIN`), as
+/// configured in `/etc/login.defs`.
+async fn login_defs_id_min(key: &str, default: u32) -> u32 {
+    let login_defs = match tokio::fs::read_to_string(LOGIN_DEFS).await {
+        Ok(login_defs) => login_defs,
+        Err(e) => {
+            debug!("Failed to read {:?}: {:?}", LOGIN_DEFS, e);
+            return default;
+        }
+    };
+    login_defs
+        .lines()
+        .find_map(|line| {
+            let mut fields = line.split_whitespace();
+            (fields.next() == Some(key)).then(|| fields.next()?.parse().ok())?
+        })
+        .unwrap_or(default)
+}
+
+async fn passwd(username: &str) -> Result<Option<Passwd>> {
+    let Some(entry) = run("getent", &["passwd", username]).await? else {
+        return Ok(None);
+    };
+
+    // name:password:uid:gid:gecos:home:shell
+    let fields: Vec<&str> = entry.trim().split(':').collect();
+    if fields.len() < 7 {
+        bail!("Malformed passwd entry for {:?}: {:?}", username, entry);
+    }
+    Ok(Some(Passwd {
+        uid: fields[2].parse().context("Parsing UID")?,
+        gid: fields[3].parse().context("Parsing GID")?,
+        home: PathBuf::from(fields[5]),
+    }))
+}
+
+/// Replace a file with the given contents. The file is replaced atomically
+/// and never written through symlinks, as it may be placed in a directory
+/// controlled by another user.
+pub async fn replace_file(
+    path: &Path,
+    contents: &[u8],
+    mode: u32,
+    owner: Option<(u32, u32)>,
+) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    match tokio::fs::remove_file(&tmp_path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("Removing {:?}", tmp_path))
+        }
+        _ => (),
+    }
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&tmp_path)
+        .await
+        .with_context(|| format!("Creating {:?}", tmp_path))?;
+    file.write_all(contents)
+        .await
+        .with_context(|| format!("Writing {:?}", tmp_path))?;
+    if let Some((uid, gid)) = owner {
+        std::os::unix::fs::fchown(&file.into_std().await, Some(uid), Some(gid))
+            .with_context(|| format!("Changing owner of {:?}", tmp_path))?;
+    }
+
+    tokio::fs::rename(&tmp_path, path)
+        .await
+        .with_context(|| format!("Renaming {:?} to {:?}", tmp_path, path))
+}
+
+async fn remove_file(path: &Path) -> Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Removing {:?}", path))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Directory containing the account's authorized keys file, created if it
+/// doesn't exist. Fails if it is not a directory owned by the account (e.g.,
+/// a symlink).
+async fn ssh_dir(passwd: &Passwd) -> Result<PathBuf> {
+    let ssh_dir = passwd.home.join(".ssh");
+    if let Err(e) = tokio::fs::create_dir(&ssh_dir).await {
+        if e.kind() != std::io::ErrorKind::AlreadyExists {
+            return Err(e).with_context(|| format!("Creating {:?}", ssh_dir));
+        }
+    } else {
+        tokio::fs::set_permissions(&ssh_dir, std::fs::Permissions::from_mode(0o700))
+            .await
+            .with_context(|| format!("Restricting {:?}", ssh_dir))?;
+        std::os::unix::fs::lchown(&ssh_dir, Some(passwd.uid), Some(passwd.gid))
+            .with_context(|| format!("Changing owner of {:?}", ssh_dir))?;
+    }
+
+    let metadata = tokio::fs::symlink_metadata(&ssh_dir)
+        .await
+        .with_context(|| format!("Inspecting {:?}", ssh_dir))?;
+    if !metadata.is_dir() || metadata.uid() != passwd.uid {
+        bail!("{:?} is not a directory owned by the account", ssh_dir);
+    }
+    Ok(ssh_dir)
+}
+
+fn sudoers_file(username: &str) -> PathBuf {
+    Path::new(SUDOERS_DIR).join(format!("treadmill-{}", username))
+}
+
+/// Create the account, or update its groups if the puppet created it
+/// before. Once this succeeded, the account is managed by the puppet.
+async fn ensure_account(
+    account: &UserAccount,
+    previous: &[String],
+    uid_min: u32,
+    gid_min: u32,
+) -> Result<Passwd> {
+    validate_name(&account.username)?;
+
+    // Never take over accounts that the puppet didn't create, such as
+    // system accounts or regular accounts of the environment's image:
+    if let Some(passwd) = passwd(&account.username).await? {
+        if !previous.contains(&account.username) {
+            bail!(
+                "User account {:?} already exists, but is not managed by the puppet",
+                account.username
+            );
+        }
+        if passwd.uid < uid_min {
+            bail!(
+                "User account {:?} has UID {}, below the minimum of {} for user accounts",
+                account.username,
+                passwd.uid,
+                uid_min
+            );
+        }
+    }
+
+    // Likewise, never grant membership in system groups (e.g., "wheel"):
+    for group in account.groups.iter() {
+        validate_name(group)?;
+        match run("getent", &["group", group]).await? {
+            Some(entry) => {
+                // name:password:gid:members
+                let gid: u32 = entry
+                    .trim()
+                    .split(':')
+                    .nth(2)
+                    .with_context(|| format!("Malformed group entry for {:?}", group))?
+                    .parse()
+                    .context("Parsing GID")?;
+                if gid < gid_min {
+                    bail!(
+                        "Group {:?} has GID {}, below the minimum of {} for regular groups",
+                        group,
+                        gid,
+                        gid_min
+                    );
+                }
+            }
+            None => {
+                info!("Creating group {:?}", group);
+                run_checked("groupadd", &[group]).await?;
+            }
+        }
+    }
+    let groups = account.groups.join(",");
+
+    if passwd(&account.username).await?.is_none() {
+        info!("Creating user account {:?}", account.username);
+        // Uses the image's default shell:
+        let mut args = vec!["--create-home"];
+        if !groups.is_empty() {
+            args.extend(["--groups", &groups]);
+        }
+        args.push(&account.username);
+        run_checked("useradd", &args).await?;
+    } else {
+        // Also lifts the expiry of previously disabled accounts:
+        run_checked(
+            "usermod",
+            &["--groups", &groups, "--expiredate", "", &account.username],
+        )
+        .await?;
+    }
+    passwd(&account.username)
+        .await?
+        .with_context(|| format!("User account {:?} not found", account.username))
+}
+
+/// Install the account's SSH keys and grant or revoke its sudo rights.
+async fn configure_account(account: &UserAccount, passwd: &Passwd) -> Result<()> {
+    let mut keys = account.ssh_keys.join("\n");
+    keys.push('\n');
+    replace_file(
+        &ssh_dir(passwd).await?.join("authorized_keys"),
+        keys.as_bytes(),
+        0o600,
+        Some((passwd.uid, passwd.gid)),
+    )
+    .await?;
+
+    let sudoers_file = sudoers_file(&account.username);
+    if account.sudo {
+        replace_file(
+            &sudoers_file,
+            format!("{} ALL=(ALL) NOPASSWD: ALL\n", account.username).as_bytes(),
+            0o440,
+            None,
+        )
+        .await?;
+    } else {
+        remove_file(&sudoers_file).await?;
+    }
+
+    Ok(())
+}
+
+/// Revoke access to an account that is no longer listed, retaining its files.
+async fn disable_account(username: &str) -> Result<()> {
+    info!("Disabling user account {:?}", username);
+    remove_file(&sudoers_file(username)).await?;
+    run_checked("usermod", &["--expiredate", "1", username]).await?;
+    if let Some(passwd) = passwd(username).await? {
+        remove_file(&passwd.home.join(".ssh").join("authorized_keys")).await?;
+    }
+    Ok(())
+}
+
+/// Create and update the given accounts, and disable all accounts that were
+/// previously managed but are no longer listed. Failures are logged per
+/// account, such that one invalid account doesn't lock out all others.
+pub async fn sync_user_accounts(user_accounts: &[UserAccount], state_file: &Path) -> Result<()> {
+    let previous: Vec<String> = match tokio::fs::read_to_string(state_file).await {
+        Ok(contents) => contents.lines().map(|line| line.to_string()).collect(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e).with_context(|| format!("Reading {:?}", state_file)),
+    };
+
+    // Accounts which fail to be updated or disabled remain recorded, such
+    // that they're retried on the next update:
+    let uid_min = login_defs_id_min("UID_MIN", DEFAULT_UID_MIN).await;
+    let gid_min = login_defs_id_min("GID_MIN", DEFAULT_GID_MIN).await;
+    let mut managed = Vec::with_capacity(user_accounts.len());
+    for account in user_accounts {
+        let res = match ensure_account(account, &previous, uid_min, gid_min).await {
+            Ok(passwd) => {
+                managed.push(account.username.clone());
+                configure_account(account, &passwd).await
+            }
+            Err(e) => {
+                if previous.contains(&account.username) {
+                    managed.push(account.username.clone());
+                }
+                Err(e)
+            }
+        };
+        if let Err(e) = res {
+            error!(
+                "Failed to set up user account {:?}: {:?}",
+                account.username, e
+            );
+        }
+    }
+
+    for username in previous.iter() {
+        let listed = user_accounts
+            .iter()
+            .any(|account| &account.username == username);
+        if listed || validate_name(username).is_err() {
+            continue;
+        }
+        if let Err(e) = disable_account(username).await {
+            error!("Failed to disable user account {:?}: {:?}", username, e);
+            managed.push(username.clone());
+        }
+    }
+
+    if let Some(parent) = state_file.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("Creating {:?}", parent))?;
+    }
+    let mut contents = managed.join("\n");
+    contents.push('\n');
+    replace_file(state_file, contents.as_bytes(), 0o600, None).await
+}
@@ -50,6 +50,14 @@ enum RendezvousConnectionCmd {
     Shutdown,
 }
 
+type RendezvousConnectionMap = HashMap<
+    u64,
+    (
+        tokio::sync::mpsc::Sender<RendezvousConnectionCmd>,
+        tokio::task::JoinHandle<Result<(), RendezvousProxyError>>,
+    ),
+>;
+
 pub struct RendezvousProxyState {
     client_id: Uuid,
     server_base_url: String,
@@ -57,16 +65,7 @@ pub struct RendezvousProxyState {
     auth_token: String,
     sse_keepalive_timeout: Duration,
     sse_reconnect_wait: Duration,
-    connections: Mutex<(
-        u64,
-        HashMap<
-            u64,
-            (
-                tokio::sync::mpsc::Sender<RendezvousConnectionCmd>,
-                tokio::task::JoinHandle<Result<(), RendezvousProxyError>>,
-            ),
-        >,
-    )>,
+    connections: Mutex<(u64, RendezvousConnectionMap)>,
     public_addr: Mutex<Option<(String, u16)>>,
     public_addr_notify: tokio::sync::Notify,
 }
@@ -146,10 +146,10 @@ impl RendezvousServer {
     pub async fn accept(&self, handle: &ListenHandle) -> Result<(ConnectingHandle, SocketAddr)> {
         let listener = {
             let state = self.state.lock().await;
-            let (_, _, ref listener_ref) = state.listeners.get(&handle.0).expect(&format!(
-                "Listener entry for handle {:?} not found.",
-                handle.0
-            ));
+            let (_, _, ref listener_ref) = state
+                .listeners
+                .get(&handle.0)
+                .unwrap_or_else(|| panic!("Listener entry for handle {:?} not found.", handle.0));
             listener_ref.clone()
         };
 
@@ -183,10 +183,10 @@ impl RendezvousServer {
 
     pub async fn build_listening_resp(&self, handle: &ListenHandle) -> listener_sse_api::Listening {
         let state = self.state.lock().await;
-        let (_, ref port, _) = state.listeners.get(&handle.0).expect(&format!(
-            "Listener entry for handle {:?} not found.",
-            handle.0
-        ));
+        let (_, ref port, _) = state
+            .listeners
+            .get(&handle.0)
+            .unwrap_or_else(|| panic!("Listener entry for handle {:?} not found.", handle.0));
 
         listener_sse_api::Listening {
             public_hostname: self.public_hostname.clone(),
@@ -199,10 +199,13 @@ impl RendezvousServer {
         handle: &ConnectingHandle,
     ) -> listener_sse_api::NewConnection {
         let state = self.state.lock().await;
-        let (ref token, ref tcp_stream) = state.connecting_streams.get(&handle.0).expect(&format!(
-            "Connecting stream entry for handle {:?} not found.",
-            handle.0
-        ));
+        let (ref token, ref tcp_stream) =
+            state.connecting_streams.get(&handle.0).unwrap_or_else(|| {
+                panic!(
+                    "Connecting stream entry for handle {:?} not found.",
+                    handle.0
+                )
+            });
 
         let local_addr = tcp_stream
             .local_addr()
@@ -270,7 +273,7 @@ async fn accept(
         message = ws.next() => {
             match message {
             Some(Ok(ws::Message::Binary(vec))) => {
-                tcp_stream.write(&vec).await.unwrap();
+                tcp_stream.write_all(&vec).await.unwrap();
             }
 
             None => {
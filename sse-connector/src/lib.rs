@@ -1,12 +1,21 @@
 use async_trait::async_trait;
 use eventsource_client::{Client, Event, SSE};
 use futures::TryStreamExt;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Weak};
 use std::time::{Duration, Instant};
 use uuid::Uuid;
 
 use treadmill_rs::api::coord_runner::{rest as rest_api, sse as sse_api};
-use treadmill_rs::connector::{Runner, RunnerConnector};
+use treadmill_rs::connector::{Runner, RunnerConnector, RunnerConnectorError};
+
+pub mod outbox;
+pub use outbox::JobStateOutbox;
+
+const OUTBOX_RETRY_MIN_BACKOFF: Duration = Duration::from_secs(1);
+const OUTBOX_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(60);
+const CONSOLE_LOG_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const JOB_STATE_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub struct SSERunnerConnector<R: Runner> {
     coord_url: String,
@@ -15,6 +24,8 @@ pub struct SSERunnerConnector<R: Runner> {
     reconnect_wait: Duration,
     runner: Weak<R>,
     client: reqwest::Client,
+    outbox: JobStateOutbox,
+    state_report_requested: AtomicBool,
 }
 
 impl<R: Runner> SSERunnerConnector<R> {
@@ -24,6 +35,7 @@ impl<R: Runner> SSERunnerConnector<R> {
         runner: Weak<R>,
         keepalive_timeout: Duration,
         reconnect_wait: Duration,
+        outbox: JobStateOutbox,
     ) -> Self {
         SSERunnerConnector {
             coord_url,
@@ -32,48 +44,144 @@ impl<R: Runner> SSERunnerConnector<R> {
             reconnect_wait,
             runner,
             client: reqwest::Client::new(),
+            outbox,
+            state_report_requested: AtomicBool::new(false),
         }
     }
 
-    async fn handle_sse_event(&self, ev: Event, runner: &Arc<R>) {
-        use sse_api::SSEMessage;
+    async fn put_job_state(
+        &self,
+        job_id: Uuid,
+        job_state: &rest_api::JobState,
+    ) -> Result<(), RunnerConnectorError> {
+        let resp = self
+            .client
+            .put(format!(
+                "{}/api/runner/v0/jobs/{}/state",
+                self.coord_url, job_id
+            ))
+            .json(job_state)
+            // A hung request would block all subsequent updates. Time out,
+            // such that it's retried like any other transport error:
+            .timeout(JOB_STATE_REQUEST_TIMEOUT)
+            .send()
+            .await
+            .map_err(|e| RunnerConnectorError::RequestError(format!("{:?}", e)))?;
 
-        match ev.event_type.as_str() {
-            "message" => {
-                match serde_json::from_str::<SSEMessage>(&ev.data) {
-                    Ok(SSEMessage::UpdateState) => {
-                        // runner.send_state_update().await;
-                    }
+        let status = resp.status();
+        // Timeouts and rate limiting are transient, and retried like
+        // transport errors:
+        let transient = status == reqwest::StatusCode::REQUEST_TIMEOUT
+            || status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+        if status.is_client_error() && !transient {
+            Err(RunnerConnectorError::RejectedError(status.as_u16()))
+        } else if !status.is_success() {
+            Err(RunnerConnectorError::RequestError(format!(
+                "unexpected response status {}",
+                status
+            )))
+        } else {
+            Ok(())
+        }
+    }
 
-                    Ok(SSEMessage::StartJob(msg)) => {
-                        R::start_job(runner, msg).await;
-                    }
+    /// Report the runner's full state to the coordinator, such that it can
+    /// reconcile its view of the board with what is actually running on it.
+    async fn report_runner_state(&self, runner: &Arc<R>) {
+        let runner_state = R::report_state(runner).await;
+
+        let res = self
+            .client
+            .put(format!(
+                "{}/api/runner/v0/boards/{}/state",
+                self.coord_url, self.board_id
+            ))
+            .json(&runner_state)
+            .send()
+            .await;
+
+        match res {
+            Ok(resp) if resp.status().is_success() => (),
+            Ok(resp) => println!(
+                "Coordinator rejected runner state report with status {}",
+                resp.status()
+            ),
+            Err(e) => println!("Failed to report runner state: {:?}", e),
+        }
+    }
+
+    /// Report the runner's full state once all job state updates queued so
+    /// far have been delivered. Reporting it any earlier could have the
+    /// coordinator apply those updates on top of a newer state snapshot.
+    fn request_runner_state_report(&self) {
+        self.state_report_requested.store(true, Ordering::Relaxed);
+        self.outbox.wake();
+    }
+
+    /// Deliver queued job state updates to the coordinator, in order. Failed
+    /// deliveries are retried with an exponential backoff, or as soon as the
+    /// outbox is woken up, for instance after reconnecting to the coordinator.
+    async fn flush_outbox(&self) {
+        let mut backoff = OUTBOX_RETRY_MIN_BACKOFF;
 
-                    Ok(SSEMessage::StopJob(msg)) => {
-                        R::stop_job(runner, msg).await;
+        loop {
+            let (seq, record) = match self.outbox.front().await {
+                Some(entry) => entry,
+                None => {
+                    if self.state_report_requested.swap(false, Ordering::Relaxed) {
+                        if let Some(runner) = self.runner.upgrade() {
+                            self.report_runner_state(&runner).await;
+                        }
+                    } else {
+                        self.outbox.notified().await;
                     }
+                    continue;
+                }
+            };
 
-                    Err(e) => {
-                        println!("Unable to parse SSE message \"{}\": {:?}", ev.data, e);
+            match self.put_job_state(record.job_id, &record.job_state).await {
+                Ok(()) => {
+                    backoff = OUTBOX_RETRY_MIN_BACKOFF;
+                }
+
+                Err(RunnerConnectorError::RejectedError(status)) => {
+                    // Retrying this update won't help, and would block all
+                    // subsequent updates. Drop it:
+                    log::error!(
+                        "Coordinator rejected state update for job {} with status {}, \
+                         discarding: {:?}",
+                        record.job_id,
+                        status,
+                        record.job_state
+                    );
+                }
+
+                Err(e) => {
+                    println!(
+                        "Failed to deliver state update for job {}, retrying in {} sec: {:?}",
+                        record.job_id,
+                        backoff.as_secs(),
+                        e
+                    );
+
+                    #[rustfmt::skip]
+                    tokio::select! {
+                        _ = tokio::time::sleep(backoff) => {},
+                        _ = self.outbox.notified() => {},
                     }
+
+                    backoff = std::cmp::min(backoff * 2, OUTBOX_RETRY_MAX_BACKOFF);
+                    continue;
                 }
             }
 
-            "close" => {
-                println!(
-                    "Server closed connection, last will and testament: {}",
-                    ev.data
-                );
+            if let Err(e) = self.outbox.remove(seq).await {
+                println!("Failed to remove delivered outbox entry {}: {:?}", seq, e);
             }
-
-            _ => println!("Unknown event type {}!", ev.event_type),
         }
     }
-}
 
-#[async_trait]
-impl<R: Runner> RunnerConnector for SSERunnerConnector<R> {
-    async fn run(&self) {
+    async fn sse_loop(&self) {
         // Acquire a "strong" Arc<> reference to the runner. Not holding onto a
         // strong reference beyond invocations of "run" will ensure that the
         // contained runner can be deallocated properly.
@@ -86,8 +194,7 @@ impl<R: Runner> RunnerConnector for SSERunnerConnector<R> {
             // .header("Authorization", "Basic username:password")?
             let client = eventsource_client::ClientBuilder::for_url(&format!(
                 "{}/api/runner/v0/boards/{}/sse",
-                self.coord_url,
-                self.board_id.to_string()
+                self.coord_url, self.board_id
             ))
             .unwrap()
             .build();
@@ -96,6 +203,13 @@ impl<R: Runner> RunnerConnector for SSERunnerConnector<R> {
             // keepalive message in an `Instant`:
             let mut last_message = Instant::now();
 
+            // Whether we've received any message over this connection yet.
+            // Upon the first message, we know that the coordinator is
+            // reachable again. We retry delivering queued job state updates
+            // and report our full state, as the coordinator may have missed
+            // updates while we were disconnected:
+            let mut connected = false;
+
             let mut stream = Box::pin(client.stream());
 
             loop {
@@ -104,12 +218,20 @@ impl<R: Runner> RunnerConnector for SSERunnerConnector<R> {
                     sse_stream_element = stream.try_next() => {
                         match sse_stream_element {
                             Ok(Some(SSE::Event(ev))) => {
+                                if !connected {
+                                    connected = true;
+                                    self.request_runner_state_report();
+                                }
                                 self.handle_sse_event(ev, &runner).await;
                                 last_message = Instant::now();
                             }
 
                             Ok(Some(SSE::Comment(_))) => {
                                 // Do nothing. We use comments for keep-alive messages only.
+                                if !connected {
+                                    connected = true;
+                                    self.request_runner_state_report();
+                                }
                                 last_message = Instant::now();
                             }
 
@@ -145,17 +267,82 @@ impl<R: Runner> RunnerConnector for SSERunnerConnector<R> {
         }
     }
 
-    async fn post_job_state(&self, job_id: Uuid, job_state: rest_api::JobState) {
-        self.client
-            .put(&format!(
-                "{}/api/runner/v0/jobs/{}/state",
-                self.coord_url,
-                job_id.to_string()
+    async fn handle_sse_event(&self, ev: Event, runner: &Arc<R>) {
+        use sse_api::SSEMessage;
+
+        match ev.event_type.as_str() {
+            "message" => match serde_json::from_str::<SSEMessage>(&ev.data) {
+                Ok(SSEMessage::UpdateState) => {
+                    self.request_runner_state_report();
+                }
+
+                Ok(SSEMessage::StartJob(msg)) => {
+                    R::start_job(runner, msg).await;
+                }
+
+                Ok(SSEMessage::StopJob(msg)) => {
+                    R::stop_job(runner, msg).await;
+                }
+
+                Ok(SSEMessage::UpdateSSHKeys(msg)) => {
+                    R::update_ssh_keys(runner, msg).await;
+                }
+
+                Err(e) => {
+                    println!("Unable to parse SSE message \"{}\": {:?}", ev.data, e);
+                }
+            },
+
+            "close" => {
+                println!(
+                    "Server closed connection, last will and testament: {}",
+                    ev.data
+                );
+            }
+
+            _ => println!("Unknown event type {}!", ev.event_type),
+        }
+    }
+}
+
+#[async_trait]
+impl<R: Runner> RunnerConnector for SSERunnerConnector<R> {
+    async fn run(&self) {
+        // Deliver job state updates concurrently to processing SSE events:
+        tokio::join!(self.sse_loop(), self.flush_outbox());
+    }
+
+    async fn post_job_state(
+        &self,
+        job_id: Uuid,
+        job_state: rest_api::JobState,
+    ) -> Result<(), RunnerConnectorError> {
+        // Persist the update, it will be delivered asynchronously:
+        self.outbox.push(job_id, job_state).await?;
+        Ok(())
+    }
+
+    async fn post_job_usage(
+        &self,
+        job_id: Uuid,
+        usage: rest_api::JobResourceUsage,
+    ) -> Result<(), RunnerConnectorError> {
+        let resp = self
+            .client
+            .put(format!(
+                "{}/api/runner/v0/jobs/{}/usage",
+                self.coord_url, job_id
             ))
-            .json(&job_state)
+            .json(&usage)
             .send()
             .await
-            .unwrap();
+            .map_err(|e| RunnerConnectorError::RequestError(format!("{:?}", e)))?;
+
+        if resp.status().is_success() {
+            Ok(())
+        } else {
+            Err(RunnerConnectorError::RejectedError(resp.status().as_u16()))
+        }
     }
 
     async fn send_job_console_log(
@@ -165,12 +352,12 @@ impl<R: Runner> RunnerConnector for SSERunnerConnector<R> {
         next: usize,
         stdio_map: &[(rest_api::StdioFd, usize)],
         console_bytes: Vec<u8>,
-    ) {
-        self.client
-            .put(&format!(
+    ) -> Result<usize, RunnerConnectorError> {
+        let resp = self
+            .client
+            .put(format!(
                 "{}/api/runner/v0/jobs/{}/console",
-                self.coord_url,
-                job_id.to_string()
+                self.coord_url, job_id
             ))
             .header("X-Treadmill-Console-Offset", format!("{}", offset))
             .header("X-Treadmill-Console-Next", format!("{}", next))
@@ -179,9 +366,23 @@ impl<R: Runner> RunnerConnector for SSERunnerConnector<R> {
                 serde_json::to_string(&stdio_map).unwrap(),
             )
             .header("Content-Type", "application/octet-stream")
+            .timeout(CONSOLE_LOG_REQUEST_TIMEOUT)
             .body(console_bytes)
             .send()
             .await
-            .unwrap();
+            .map_err(|e| RunnerConnectorError::RequestError(format!("{:?}", e)))?;
+
+        if resp.status().is_success() {
+            // Coordinators which do not report an acknowledged offset accept
+            // the entire chunk:
+            Ok(resp
+                .headers()
+                .get("X-Treadmill-Console-Acked")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(next))
+        } else {
+            Err(RunnerConnectorError::RejectedError(resp.status().as_u16()))
+        }
     }
 }
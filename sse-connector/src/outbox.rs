@@ -0,0 +1,225 @@
+//! Durable, ordered queue of job state updates.
+//!
+//! Every job state update is persisted to a file in the outbox directory
+//! before it is acknowledged to the runner, and only removed once the
+//! coordinator has accepted it. Updates are delivered strictly in the order
+//! they were posted, such that the coordinator eventually converges to the
+//! latest state reported by the runner, even across runner restarts.
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, Notify};
+use uuid::Uuid;
+
+use treadmill_rs::api::coord_runner::rest as rest_api;
+
+const OUTBOX_ENTRY_EXTENSION: &str = "json";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutboxRecord {
+    pub job_id: Uuid,
+    pub job_state: rest_api::JobState,
+}
+
+struct OutboxState {
+    next_seq: u64,
+    queue: VecDeque<(u64, OutboxRecord)>,
+}
+
+pub struct JobStateOutbox {
+    dir: PathBuf,
+    state: Mutex<OutboxState>,
+    notify: Notify,
+}
+
+impl JobStateOutbox {
+    /// Open the outbox stored in `dir`, creating the directory if it does not
+    /// exist yet. Updates left over from a previous run are queued for
+    /// delivery.
+    pub fn open(dir: PathBuf) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+
+        let mut queue = Vec::new();
+        for dir_entry in std::fs::read_dir(&dir)? {
+            let path = dir_entry?.path();
+
+            // Skip partially written entries, and any other unrelated files:
+            let seq = match Self::entry_seq(&path) {
+                Some(seq) => seq,
+                None => continue,
+            };
+
+            let record = serde_json::from_slice(&std::fs::read(&path)?).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Invalid outbox entry {:?}: {:?}", path, e),
+                )
+            })?;
+
+            queue.push((seq, record));
+        }
+        queue.sort_by_key(|(seq, _)| *seq);
+
+        let next_seq = queue.last().map(|(seq, _)| seq + 1).unwrap_or(0);
+
+        Ok(JobStateOutbox {
+            dir,
+            state: Mutex::new(OutboxState {
+                next_seq,
+                queue: queue.into(),
+            }),
+            notify: Notify::new(),
+        })
+    }
+
+    fn entry_seq(path: &Path) -> Option<u64> {
+        if path.extension()? != OUTBOX_ENTRY_EXTENSION {
+            return None;
+        }
+        path.file_stem()?.to_str()?.parse().ok()
+    }
+
+    fn entry_path(&self, seq: u64) -> PathBuf {
+        self.dir
+            .join(format!("{:020}.{}", seq, OUTBOX_ENTRY_EXTENSION))
+    }
+
+    /// Persist a job state update and queue it for delivery.
+    pub async fn push(&self, job_id: Uuid, job_state: rest_api::JobState) -> std::io::Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let record = OutboxRecord { job_id, job_state };
+        let serialized = serde_json::to_vec(&record)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+        // Hold the lock while writing the entry, such that sequence numbers
+        // are assigned in the order entries become visible in the queue:
+        let mut state = self.state.lock().await;
+        let seq = state.next_seq;
+
+        // Write the entry to a temporary file and atomically move it into
+        // place, so that a crash never leaves a truncated entry behind:
+        let path = self.entry_path(seq);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        file.write_all(&serialized).await?;
+        file.sync_all().await?;
+        std::mem::drop(file);
+        tokio::fs::rename(&tmp_path, &path).await?;
+        tokio::fs::File::open(&self.dir).await?.sync_all().await?;
+
+        state.next_seq += 1;
+        state.queue.push_back((seq, record));
+        std::mem::drop(state);
+
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Oldest update which has not yet been accepted by the coordinator.
+    pub async fn front(&self) -> Option<(u64, OutboxRecord)> {
+        self.state.lock().await.queue.front().cloned()
+    }
+
+    /// Remove a delivered update from the outbox.
+    pub async fn remove(&self, seq: u64) -> std::io::Result<()> {
+        let mut state = self.state.lock().await;
+        if let Some(idx) = state.queue.iter().position(|(s, _)| *s == seq) {
+            state.queue.remove(idx);
+        }
+        tokio::fs::remove_file(self.entry_path(seq)).await
+    }
+
+    /// Wait until new updates are queued, or another delivery attempt is
+    /// requested through [`JobStateOutbox::wake`].
+    pub async fn notified(&self) {
+        self.notify.notified().await
+    }
+
+    /// Request another delivery attempt, e.g. after the connection to the
+    /// coordinator has been re-established.
+    pub fn wake(&self) {
+        self.notify.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job_state(status_message: &str) -> rest_api::JobState {
+        rest_api::JobState::Stopping {
+            status_message: Some(status_message.to_string()),
+        }
+    }
+
+    fn status_message(record: &OutboxRecord) -> Option<&str> {
+        match record.job_state {
+            rest_api::JobState::Stopping { ref status_message } => status_message.as_deref(),
+            _ => None,
+        }
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "treadmill-outbox-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[tokio::test]
+    async fn delivers_in_order() {
+        let dir = test_dir("order");
+        let outbox = JobStateOutbox::open(dir.clone()).unwrap();
+        assert!(outbox.front().await.is_none());
+
+        let job_id = Uuid::nil();
+        for msg in ["a", "b", "c"] {
+            outbox.push(job_id, job_state(msg)).await.unwrap();
+        }
+
+        let mut delivered = vec![];
+        while let Some((seq, record)) = outbox.front().await {
+            assert_eq!(record.job_id, job_id);
+            delivered.push(status_message(&record).unwrap().to_string());
+            outbox.remove(seq).await.unwrap();
+        }
+        assert_eq!(delivered, ["a", "b", "c"]);
+
+        // Delivered updates are removed from disk as well:
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn persists_across_reopen() {
+        let dir = test_dir("reopen");
+        let outbox = JobStateOutbox::open(dir.clone()).unwrap();
+        for msg in ["a", "b", "c"] {
+            outbox.push(Uuid::nil(), job_state(msg)).await.unwrap();
+        }
+        let (seq, _) = outbox.front().await.unwrap();
+        outbox.remove(seq).await.unwrap();
+        std::mem::drop(outbox);
+
+        // Partially written entries of a crashed runner are ignored:
+        std::fs::write(dir.join("00000000000000000042.tmp"), b"{").unwrap();
+
+        let outbox = JobStateOutbox::open(dir.clone()).unwrap();
+        outbox.push(Uuid::nil(), job_state("d")).await.unwrap();
+
+        let mut delivered = vec![];
+        while let Some((seq, record)) = outbox.front().await {
+            delivered.push(status_message(&record).unwrap().to_string());
+            outbox.remove(seq).await.unwrap();
+        }
+        assert_eq!(delivered, ["b", "c", "d"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,16 +1,17 @@
 use anyhow::{Context, Result};
-use log::{debug, error, info, warn};
+use log::{debug, info, warn};
 use std::sync::Arc;
 use tokio::net::TcpListener;
 use tokio::task::JoinHandle;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
 use uuid::Uuid;
 
-use treadmill_rs::api::runner_puppet::{PuppetMsg, PuppetReq, RunnerMsg, RunnerResp};
-use treadmill_rs::control_socket::Runner;
+use treadmill_rs::api::runner_puppet::{PuppetMsg, PuppetReq, RunnerEvent, RunnerMsg, RunnerResp};
+use treadmill_rs::control_socket::{ControlSocket, Runner};
 
 #[derive(Debug, Clone)]
 enum ControlSocketTaskCommand {
+    SendEvent(RunnerEvent),
     Shutdown,
 }
 
@@ -21,7 +22,9 @@ enum ControlSocketTaskCommand {
 pub struct TcpControlSocket<R: Runner> {
     _job_id: Uuid,
     task_handle: JoinHandle<Result<()>>,
-    task_cmd_chan: tokio::sync::mpsc::Sender<ControlSocketTaskCommand>,
+    // Unbounded, such that sending an event never waits for the task, which
+    // may itself be waiting for the runner to serve a request:
+    task_cmd_chan: tokio::sync::mpsc::UnboundedSender<ControlSocketTaskCommand>,
     // state: Arc<RwLock<ControlSocketState>>,
     _runner: Arc<R>,
 }
@@ -38,7 +41,32 @@ impl<R: Runner> TcpControlSocket<R> {
             PuppetReq::Ping => RunnerResp::PingResp,
 
             PuppetReq::SSHKeys => RunnerResp::SSHKeysResp {
-                ssh_keys: runner.ssh_keys(job_id).await.unwrap_or_else(|| vec![]),
+                ssh_keys: runner
+                    .ssh_keys(job_id)
+                    .await
+                    .unwrap_or_else(std::vec::Vec::new),
+            },
+
+            PuppetReq::SSHHostKeys => RunnerResp::SSHHostKeysResp {
+                host_keys: runner
+                    .ssh_host_keys(job_id)
+                    .await
+                    .unwrap_or_else(std::vec::Vec::new),
+            },
+
+            PuppetReq::SSHUserCA => {
+                if let Some(ca) = runner.ssh_user_ca(job_id).await {
+                    RunnerResp::SSHUserCA(ca)
+                } else {
+                    RunnerResp::JobNotFound
+                }
+            }
+
+            PuppetReq::UserAccounts => RunnerResp::UserAccountsResp {
+                user_accounts: runner
+                    .user_accounts(job_id)
+                    .await
+                    .unwrap_or_else(std::vec::Vec::new),
             },
 
             PuppetReq::NetworkConfig => {
@@ -66,7 +94,7 @@ impl<R: Runner> TcpControlSocket<R> {
 
         // let state = Arc::new(RwLock::new(ControlSocketState { client: None }));
 
-        let (task_cmd_chan_tx, mut task_cmd_chan_rx) = tokio::sync::mpsc::channel(1);
+        let (task_cmd_chan_tx, mut task_cmd_chan_rx) = tokio::sync::mpsc::unbounded_channel();
 
         // let task_state = state.clone();
         let task_runner = runner.clone();
@@ -79,16 +107,22 @@ impl<R: Runner> TcpControlSocket<R> {
             let runner = task_runner;
 
             let mut shutdown_requested = false;
+            let mut runner_event_cnt: u64 = 0;
 
             while !shutdown_requested {
                 // Accept new connections. We only handle one
                 // connection at any point in time:
                 #[rustfmt::skip]
                 let socket_res = tokio::select! {
-                    accept_res = server_socket.accept() => Ok(
-                        accept_res
-                            .context("Accepting new control socket connection.").unwrap()
-                    ),
+                    accept_res = server_socket.accept() => match accept_res {
+                        Ok(socket) => Ok(socket),
+                        Err(e) => {
+                            // Accepting may fail transiently (e.g. when
+                            // running out of file descriptors):
+                            warn!("Error accepting new control socket connection: {:?}", e);
+                            continue;
+                        },
+                    },
 
                     cmd_res = task_cmd_chan_rx.recv() => match cmd_res {
                         Some(cmd) => Err(cmd),
@@ -100,6 +134,10 @@ impl<R: Runner> TcpControlSocket<R> {
 
                 let socket = match socket_res {
                     Ok((socket, _client_addr)) => socket,
+                    Err(ControlSocketTaskCommand::SendEvent(event)) => {
+                        debug!("No puppet connected, dropping event {:?}", event);
+                        continue;
+                    }
                     Err(ControlSocketTaskCommand::Shutdown) => {
                         shutdown_requested = true;
                         continue;
@@ -122,7 +160,6 @@ impl<R: Runner> TcpControlSocket<R> {
                         }
 
                         cmd_res = task_cmd_chan_rx.recv() => {
-                            error!("Received task command inner!");
                             match cmd_res {
                                 Some(cmd) => Err(cmd),
                                 None => {
@@ -188,6 +225,22 @@ impl<R: Runner> TcpControlSocket<R> {
                                 }
                             }
                         }
+                        Err(ControlSocketTaskCommand::SendEvent(event)) => {
+                            use bytes::BufMut;
+
+                            let msg = RunnerMsg::Event {
+                                runner_event_id: runner_event_cnt,
+                                event,
+                            };
+                            runner_event_cnt += 1;
+
+                            let mut bytes = bytes::BytesMut::new().writer();
+                            serde_json::to_writer(&mut bytes, &msg)
+                                .expect("Failed to encode control socket event as JSON");
+                            if let Err(e) = transport.send(bytes.into_inner().freeze()).await {
+                                warn!("Error while sending event to puppet, ignoring: {:?}", e);
+                            }
+                        }
                         Err(ControlSocketTaskCommand::Shutdown) => {
                             shutdown_requested = true;
                             break;
@@ -213,13 +266,14 @@ impl<R: Runner> TcpControlSocket<R> {
         // First, request shutdown of the task:
         self.task_cmd_chan
             .send(ControlSocketTaskCommand::Shutdown)
-            .await
             .with_context(|| {
-                format!("Requesting shutdown of the control socket request handler")
+                "Requesting shutdown of the control socket request handler".to_string()
             })?;
 
         // Then, try to join it:
-        self.task_handle.await.unwrap().unwrap();
+        self.task_handle
+            .await
+            .context("Joining the control socket request handler")??;
 
         // // Finally, do some cleanup.
         // let mut state = self.state.write().await;
@@ -240,3 +294,18 @@ impl<R: Runner> TcpControlSocket<R> {
         Ok(())
     }
 }
+
+#[async_trait::async_trait]
+impl<R: Runner> ControlSocket for TcpControlSocket<R> {
+    type Error = anyhow::Error;
+
+    fn send_event(&self, event: RunnerEvent) -> Result<()> {
+        self.task_cmd_chan
+            .send(ControlSocketTaskCommand::SendEvent(event))
+            .context("Requesting the control socket request handler to send an event")
+    }
+
+    async fn shutdown(self) -> Result<()> {
+        TcpControlSocket::shutdown(self).await
+    }
+}
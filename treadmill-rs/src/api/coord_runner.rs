@@ -1,16 +1,18 @@
 pub mod sse {
     use std::collections::HashMap;
 
-    use serde::Deserialize;
+    use serde::{Deserialize, Serialize};
     use uuid::Uuid;
 
+    pub use crate::api::runner_puppet::UserAccount;
+
     #[derive(Deserialize, Debug, Clone)]
     pub struct ParameterValue {
         pub value: String,
         pub secret: bool,
     }
 
-    #[derive(Deserialize, Debug, Clone)]
+    #[derive(Serialize, Deserialize, Debug, Clone)]
     #[serde(rename_all = "snake_case")]
     pub struct RendezvousServerSpec {
         pub client_id: Uuid,
@@ -24,6 +26,10 @@ pub mod sse {
         pub job_id: Uuid,
         pub environment_id: Uuid,
         pub ssh_keys: Vec<String>,
+        /// Personal accounts to create for the users of the job, in addition
+        /// to authorizing `ssh_keys`.
+        #[serde(default)]
+        pub user_accounts: Vec<UserAccount>,
         pub ssh_rendezvous_servers: Vec<RendezvousServerSpec>,
         pub job_parameters: HashMap<String, ParameterValue>,
         pub board_parameters: HashMap<String, ParameterValue>,
@@ -31,26 +37,59 @@ pub mod sse {
         pub board_environment_parameters: HashMap<String, ParameterValue>,
     }
 
+    /// What to do with a job's data (e.g., its root file system) once it has
+    /// been stopped.
+    #[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum JobDataRetention {
+        /// Delete the job's data immediately.
+        Delete,
+        /// Keep the job's data, subject to the runner's retention policy.
+        #[default]
+        Keep,
+        /// Keep the job's data and additionally snapshot its final state,
+        /// subject to the runner's retention policy.
+        Snapshot,
+    }
+
     #[derive(Deserialize, Debug, Clone)]
     #[serde(rename_all = "snake_case")]
     pub struct StopJobMessage {
         pub job_id: Uuid,
+        #[serde(default)]
+        pub retention: JobDataRetention,
+    }
+
+    /// Replace the SSH keys authorized to access a running job.
+    #[derive(Deserialize, Debug, Clone)]
+    #[serde(rename_all = "snake_case")]
+    pub struct UpdateSSHKeysMessage {
+        pub job_id: Uuid,
+        pub ssh_keys: Vec<String>,
+        /// Replaces the job's user accounts as well, if provided.
+        #[serde(default)]
+        pub user_accounts: Option<Vec<UserAccount>>,
     }
 
     #[derive(Deserialize, Debug, Clone)]
     #[serde(rename_all = "snake_case")]
     #[serde(tag = "type")]
+    #[allow(clippy::large_enum_variant)]
     pub enum SSEMessage {
         UpdateState,
         StartJob(StartJobMessage),
         StopJob(StopJobMessage),
+        // The snake_case conversion would split the acronym into "s_s_h":
+        #[serde(rename = "update_ssh_keys")]
+        UpdateSSHKeys(UpdateSSHKeysMessage),
     }
 }
 
 pub mod rest {
-    use serde::Serialize;
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
 
-    #[derive(Serialize, Debug, Clone)]
+    #[derive(Serialize, Deserialize, Debug, Clone)]
     #[serde(rename_all = "snake_case")]
     pub enum JobStartingStage {
         /// Acquiring resources, such as the root file system, to launch the
@@ -66,7 +105,7 @@ pub mod rest {
         Booting,
     }
 
-    #[derive(Serialize, Debug, Clone)]
+    #[derive(Serialize, Deserialize, Debug, Clone)]
     #[serde(rename_all = "snake_case")]
     pub enum JobSessionConnectionInfo {
         #[serde(rename = "direct_ssh")]
@@ -83,7 +122,7 @@ pub mod rest {
         },
     }
 
-    #[derive(Serialize, Debug, Clone)]
+    #[derive(Serialize, Deserialize, Debug, Clone)]
     #[serde(tag = "state")]
     #[serde(rename_all = "snake_case")]
     pub enum JobState {
@@ -100,16 +139,107 @@ pub mod rest {
         },
         Finished {
             status_message: Option<String>,
+            /// Resources consumed by the job over its entire lifetime.
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            resource_usage: Option<JobResourceUsage>,
+            /// How the job's environment exited, if known.
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            exit_status: Option<JobExitStatus>,
         },
         Failed {
             status_message: Option<String>,
+            /// Resources consumed by the job, if it failed after running.
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            resource_usage: Option<JobResourceUsage>,
+            /// How the job's environment exited, if it failed after running
+            /// and the status is known.
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            exit_status: Option<JobExitStatus>,
         },
     }
 
-    #[derive(Serialize, Debug, Copy, Clone)]
+    /// Resources consumed by a job, for accounting purposes. Fields are unset
+    /// if the runner cannot determine them.
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub struct JobResourceUsage {
+        pub cpu_usage_usec: Option<u64>,
+        pub cpu_user_usec: Option<u64>,
+        pub cpu_system_usec: Option<u64>,
+        pub memory_peak_bytes: Option<u64>,
+        pub io_read_bytes: Option<u64>,
+        pub io_write_bytes: Option<u64>,
+        /// Space occupied by the job's root file system.
+        pub disk_used_bytes: Option<u64>,
+        /// Packets of the job dropped by its network egress policy.
+        #[serde(default)]
+        pub egress_denied_packets: Option<u64>,
+    }
+
+    /// Exit status of a job's environment (e.g., of its container or of the
+    /// container's init process). A process terminated by a signal has no
+    /// exit code.
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub struct JobExitStatus {
+        pub code: Option<i32>,
+        pub signal: Option<i32>,
+    }
+
+    impl JobExitStatus {
+        pub fn success(&self) -> bool {
+            self.code == Some(0) && self.signal.is_none()
+        }
+    }
+
+    impl std::fmt::Display for JobExitStatus {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match (self.code, self.signal) {
+                (_, Some(signal)) => write!(f, "terminated by signal {}", signal),
+                (Some(code), None) => write!(f, "exited with code {}", code),
+                (None, None) => write!(f, "exited with an unknown status"),
+            }
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
     #[serde(rename_all = "snake_case")]
     pub enum StdioFd {
         Stdout,
         Stderr,
     }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[serde(tag = "health")]
+    #[serde(rename_all = "snake_case")]
+    pub enum RunnerHealth {
+        Healthy,
+
+        /// The runner is operational, but requires attention (for instance,
+        /// because resources of a previous job could not be released).
+        Degraded {
+            status_message: String,
+        },
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[serde(rename_all = "snake_case")]
+    pub struct RunnerJobState {
+        pub job_id: Uuid,
+        pub job_state: JobState,
+
+        /// Offset up to which the coordinator has acknowledged the job's
+        /// console log, if known.
+        pub console_offset: Option<usize>,
+    }
+
+    /// Full state of a runner, used to reconcile the coordinator's view of a
+    /// board with what is actually executing on it.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[serde(rename_all = "snake_case")]
+    pub struct RunnerState {
+        /// The job currently occupying the board, if any.
+        pub current_job: Option<RunnerJobState>,
+        pub health: RunnerHealth,
+    }
 }
@@ -7,6 +7,9 @@ use serde::{Deserialize, Serialize};
 pub enum PuppetReq {
     Ping,
     SSHKeys,
+    SSHHostKeys,
+    SSHUserCA,
+    UserAccounts,
     NetworkConfig,
 }
 
@@ -68,6 +71,42 @@ pub struct NetworkConfig {
     pub ipv6: Option<Ipv6NetworkConfig>,
 }
 
+/// An SSH host key generated for a job, to be installed as
+/// `ssh_host_<algorithm>_key` along with its `.pub` counterpart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub struct SSHHostKey {
+    pub algorithm: String,
+    pub private_key: String,
+    pub public_key: String,
+}
+
+/// Certificate authorities trusted to sign SSH user certificates for a job,
+/// and the principals such certificates must be issued for. Empty if the job
+/// is only accessible through its SSH keys.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub struct SSHUserCAConfig {
+    pub trusted_user_ca_keys: Vec<String>,
+    pub authorized_principals: Vec<String>,
+}
+
+/// A personal account to create within a job's environment, such that access
+/// by the individual users of a shared job can be told apart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct UserAccount {
+    pub username: String,
+    #[serde(default)]
+    pub ssh_keys: Vec<String>,
+    /// Supplementary groups of the account, created if they don't exist.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Whether the account may run any command as root through `sudo`.
+    #[serde(default)]
+    pub sudo: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 #[serde(tag = "type")]
@@ -76,6 +115,9 @@ pub enum RunnerResp {
     // Request reponses:
     PingResp,
     SSHKeysResp { ssh_keys: Vec<String> },
+    SSHHostKeysResp { host_keys: Vec<SSHHostKey> },
+    SSHUserCA(SSHUserCAConfig),
+    UserAccountsResp { user_accounts: Vec<UserAccount> },
     NetworkConfig(NetworkConfig),
 
     // Error responses:
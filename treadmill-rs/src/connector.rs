@@ -1,18 +1,66 @@
 use crate::api::coord_runner::{rest, sse};
 use async_trait::async_trait;
 use std::sync::Arc;
+use thiserror::Error;
 use uuid::Uuid;
 
 #[async_trait]
 pub trait Runner: Send + Sync + 'static {
     async fn start_job(this: &Arc<Self>, msg: sse::StartJobMessage);
     async fn stop_job(this: &Arc<Self>, msg: sse::StopJobMessage);
+
+    /// Replace the SSH keys of a running job, e.g. to grant another user
+    /// access to it, without restarting it.
+    async fn update_ssh_keys(this: &Arc<Self>, msg: sse::UpdateSSHKeysMessage);
+
+    /// Report the runner's current state. Connectors send this report to the
+    /// coordinator whenever it may be out of sync with the runner, e.g. after
+    /// reconnecting. Must not block on ongoing job state transitions.
+    async fn report_state(this: &Arc<Self>) -> rest::RunnerState;
+}
+
+#[derive(Error, Debug)]
+pub enum RunnerConnectorError {
+    #[error("persisting the message for later delivery failed")]
+    StorageError(#[from] std::io::Error),
+
+    #[error("the request to the coordinator failed: {0}")]
+    RequestError(String),
+
+    #[error("the coordinator rejected the request with status {0}")]
+    RejectedError(u16),
 }
 
 #[async_trait]
 pub trait RunnerConnector: Send + Sync + 'static {
     async fn run(&self);
-    async fn post_job_state(&self, job_id: Uuid, job_state: rest::JobState);
+
+    /// Report a new state of a job to the coordinator.
+    ///
+    /// Connectors may queue the update for delivery in the background. An
+    /// error indicates that the update could not be delivered or queued, and
+    /// will thus never reach the coordinator.
+    async fn post_job_state(
+        &self,
+        job_id: Uuid,
+        job_state: rest::JobState,
+    ) -> Result<(), RunnerConnectorError>;
+
+    /// Report the resources consumed by a running job so far. These reports
+    /// are informational and not retried, as the total usage is part of the
+    /// job's final state.
+    async fn post_job_usage(
+        &self,
+        job_id: Uuid,
+        usage: rest::JobResourceUsage,
+    ) -> Result<(), RunnerConnectorError>;
+
+    /// Send a chunk of a job's console log, spanning the byte offsets
+    /// `offset` up to (excluding) `next`, to the coordinator.
+    ///
+    /// Returns the offset up to which the coordinator has acknowledged the
+    /// console log. This can be lower than `next`, in which case the runner
+    /// should retransmit from the returned offset.
     async fn send_job_console_log(
         &self,
         job_id: Uuid,
@@ -20,5 +68,5 @@ pub trait RunnerConnector: Send + Sync + 'static {
         next: usize,
         stdio_map: &[(rest::StdioFd, usize)],
         console_bytes: Vec<u8>,
-    );
+    ) -> Result<usize, RunnerConnectorError>;
 }
@@ -0,0 +1,730 @@
+//! Console log streaming to the coordinator.
+//!
+//! Console output of a job is appended to a disk-backed spool, from which it
+//! is sent to the coordinator in batches. Data is only released from the
+//! spool once the coordinator has acknowledged it, and retransmitted from the
+//! acknowledged offset otherwise. Offsets are byte offsets into the job's
+//! console log, across all of its output streams.
+//!
+//! The spool persists a [`ConsoleCursor`] of the acknowledged output, such
+//! that a restarted runner can resume streaming the console of a job which
+//! kept running in the meantime.
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+use tokio::sync::{Mutex, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::api::coord_runner::rest as rest_api;
+use crate::connector::RunnerConnector;
+
+/// Runs of consecutive bytes originating from the same output stream.
+pub type StdioMap = Vec<(rest_api::StdioFd, usize)>;
+
+const RETRY_MIN_BACKOFF: Duration = Duration::from_millis(500);
+const RETRY_MAX_BACKOFF: Duration = Duration::from_secs(30);
+const READ_BUF_SIZE: usize = 64 * 1024;
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(250);
+const CURSOR_FILE_NAME: &str = "cursor.json";
+
+/// Position in a job's console log, along with the corresponding positions
+/// in each of its output streams.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConsoleCursor {
+    pub offset: usize,
+    pub stdout: usize,
+    pub stderr: usize,
+}
+
+impl ConsoleCursor {
+    fn advance(&mut self, stdio_fd: rest_api::StdioFd, len: usize) {
+        self.offset += len;
+        match stdio_fd {
+            rest_api::StdioFd::Stdout => self.stdout += len,
+            rest_api::StdioFd::Stderr => self.stderr += len,
+        }
+    }
+
+    /// Position within the given output stream.
+    pub fn stream_position(&self, stdio_fd: rest_api::StdioFd) -> usize {
+        match stdio_fd {
+            rest_api::StdioFd::Stdout => self.stdout,
+            rest_api::StdioFd::Stderr => self.stderr,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConsoleStreamerConfig {
+    /// Maximum number of bytes sent to the coordinator in a single request.
+    /// Reaching this many unsent bytes triggers a flush.
+    pub max_batch_bytes: usize,
+
+    /// Maximum amount of time to buffer console output before it is sent to
+    /// the coordinator.
+    pub flush_interval: Duration,
+
+    /// Upper bound on the size of the spool. When exceeded, the oldest
+    /// unacknowledged console output is discarded.
+    pub spool_max_bytes: usize,
+
+    /// Maximum amount of time to wait for the coordinator to acknowledge all
+    /// remaining output when shutting down.
+    pub final_flush_timeout: Duration,
+}
+
+impl Default for ConsoleStreamerConfig {
+    fn default() -> Self {
+        ConsoleStreamerConfig {
+            max_batch_bytes: 64 * 1024,
+            flush_interval: Duration::from_millis(500),
+            spool_max_bytes: 64 * 1024 * 1024,
+            final_flush_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+struct SpoolSegment {
+    start: usize,
+    len: usize,
+    runs: Vec<(rest_api::StdioFd, usize)>,
+    path: PathBuf,
+    file: std::fs::File,
+}
+
+/// Append-only spool of console output, split into segment files which are
+/// deleted once all of their contents have been released.
+pub struct ConsoleSpool {
+    dir: PathBuf,
+    max_bytes: usize,
+    segment_bytes: usize,
+    segments: VecDeque<SpoolSegment>,
+    start: usize,
+    start_cursor: ConsoleCursor,
+    end: usize,
+}
+
+impl ConsoleSpool {
+    /// Open the spool in `dir`. If the directory holds the cursor of a
+    /// previous spool, the new spool continues at that cursor. Unreleased
+    /// data of the previous spool is discarded, it must be appended again.
+    pub fn open(dir: PathBuf, max_bytes: usize) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+
+        let cursor = match std::fs::read(dir.join(CURSOR_FILE_NAME)) {
+            Ok(serialized) => serde_json::from_slice(&serialized).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Invalid console cursor in {:?}: {:?}", dir, e),
+                )
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ConsoleCursor::default(),
+            Err(e) => return Err(e),
+        };
+
+        for dir_entry in std::fs::read_dir(&dir)? {
+            let path = dir_entry?.path();
+            if path.extension().is_some_and(|ext| ext == "seg") {
+                std::fs::remove_file(path)?;
+            }
+        }
+
+        Ok(ConsoleSpool {
+            dir,
+            max_bytes,
+            // Keep a couple of segments, such that we can drop old data in
+            // reasonably sized chunks when exceeding the size limit:
+            segment_bytes: std::cmp::max(max_bytes / 8, 1),
+            segments: VecDeque::new(),
+            start: cursor.offset,
+            start_cursor: cursor,
+            end: cursor.offset,
+        })
+    }
+
+    /// Cursor pointing to the first byte retained in the spool.
+    pub fn start_cursor(&self) -> ConsoleCursor {
+        self.start_cursor
+    }
+
+    fn cursor_at(&self, offset: usize) -> ConsoleCursor {
+        let mut cursor = self.start_cursor;
+        for seg in self.segments.iter() {
+            let mut run_start = seg.start;
+            for (stdio_fd, run_len) in seg.runs.iter() {
+                let run_end = run_start + run_len;
+                let advance_start = std::cmp::max(run_start, self.start);
+                let advance_end = std::cmp::min(run_end, offset);
+                if advance_start < advance_end {
+                    cursor.advance(*stdio_fd, advance_end - advance_start);
+                }
+                run_start = run_end;
+            }
+        }
+        cursor
+    }
+
+    fn set_start(&mut self, start: usize) -> std::io::Result<()> {
+        if start == self.start {
+            return Ok(());
+        }
+
+        self.start_cursor = self.cursor_at(start);
+        self.start = start;
+
+        // Persist the new cursor, atomically replacing the old one:
+        let serialized = serde_json::to_vec(&self.start_cursor)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let path = self.dir.join(CURSOR_FILE_NAME);
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serialized)?;
+        std::fs::rename(&tmp_path, &path)
+    }
+
+    /// Offset of the first byte retained in the spool.
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Offset of the next byte to be appended to the spool.
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// Append console output to the spool. Returns the number of bytes
+    /// discarded from the front of the spool to stay within its size limit.
+    pub fn append(&mut self, stdio_fd: rest_api::StdioFd, data: &[u8]) -> std::io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        if self
+            .segments
+            .back()
+            .is_none_or(|seg| seg.len >= self.segment_bytes)
+        {
+            let path = self.dir.join(format!("{:020}.seg", self.end));
+            let file = std::fs::OpenOptions::new()
+                .create_new(true)
+                .read(true)
+                .append(true)
+                .open(&path)?;
+            self.segments.push_back(SpoolSegment {
+                start: self.end,
+                len: 0,
+                runs: Vec::new(),
+                path,
+                file,
+            });
+        }
+
+        let seg = self.segments.back_mut().unwrap();
+        seg.file.write_all(data)?;
+        seg.len += data.len();
+        match seg.runs.last_mut() {
+            Some((fd, len)) if *fd == stdio_fd => *len += data.len(),
+            _ => seg.runs.push((stdio_fd, data.len())),
+        }
+        self.end += data.len();
+
+        // Enforce the size limit, but always retain the current segment:
+        let mut discarded = 0;
+        while self.end - self.start > self.max_bytes && self.segments.len() > 1 {
+            let next_start = self.segments[1].start;
+            discarded += next_start - self.start;
+            self.set_start(next_start)?;
+            let seg = self.segments.pop_front().unwrap();
+            std::fs::remove_file(&seg.path)?;
+        }
+
+        Ok(discarded)
+    }
+
+    /// Read up to `max_len` bytes starting at offset `from`, along with the
+    /// corresponding map of output streams.
+    pub fn read(&self, from: usize, max_len: usize) -> std::io::Result<(Vec<u8>, StdioMap)> {
+        let from = std::cmp::max(from, self.start);
+        let to = std::cmp::min(from.saturating_add(max_len), self.end);
+
+        let mut bytes = Vec::with_capacity(to.saturating_sub(from));
+        let mut stdio_map: StdioMap = Vec::new();
+
+        for seg in self.segments.iter() {
+            let seg_end = seg.start + seg.len;
+            if seg_end <= from || seg.start >= to {
+                continue;
+            }
+
+            let read_start = std::cmp::max(from, seg.start);
+            let read_end = std::cmp::min(to, seg_end);
+            let buf_start = bytes.len();
+            bytes.resize(buf_start + (read_end - read_start), 0);
+            seg.file
+                .read_exact_at(&mut bytes[buf_start..], (read_start - seg.start) as u64)?;
+
+            // Intersect the segment's runs with the range read:
+            let mut run_start = seg.start;
+            for (stdio_fd, run_len) in seg.runs.iter() {
+                let run_end = run_start + run_len;
+                let overlap_start = std::cmp::max(run_start, read_start);
+                let overlap_end = std::cmp::min(run_end, read_end);
+                if overlap_start < overlap_end {
+                    let overlap = overlap_end - overlap_start;
+                    match stdio_map.last_mut() {
+                        Some((fd, len)) if fd == stdio_fd => *len += overlap,
+                        _ => stdio_map.push((*stdio_fd, overlap)),
+                    }
+                }
+                run_start = run_end;
+            }
+        }
+
+        Ok((bytes, stdio_map))
+    }
+
+    /// Release all data up to offset `upto`, deleting segments which no
+    /// longer hold any retained data.
+    pub fn release(&mut self, upto: usize) -> std::io::Result<()> {
+        self.set_start(std::cmp::min(std::cmp::max(self.start, upto), self.end))?;
+
+        while let Some(seg) = self.segments.front() {
+            if seg.start + seg.len > self.start {
+                break;
+            }
+            let seg = self.segments.pop_front().unwrap();
+            std::fs::remove_file(&seg.path)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn destroy(self) -> std::io::Result<()> {
+        let dir = self.dir.clone();
+        std::mem::drop(self);
+        std::fs::remove_dir_all(dir)
+    }
+}
+
+struct ConsoleStreamerShared {
+    spool: Mutex<ConsoleSpool>,
+    notify: Notify,
+    acked: AtomicUsize,
+}
+
+/// Handle to append console output to a [`ConsoleStreamer`].
+#[derive(Clone)]
+pub struct ConsoleSink {
+    shared: Arc<ConsoleStreamerShared>,
+}
+
+impl ConsoleSink {
+    pub async fn write(&self, stdio_fd: rest_api::StdioFd, data: &[u8]) -> std::io::Result<()> {
+        let discarded = self.shared.spool.lock().await.append(stdio_fd, data)?;
+        if discarded != 0 {
+            warn!(
+                "Console spool is full, discarded {} bytes of unacknowledged output.",
+                discarded
+            );
+        }
+        self.shared.notify.notify_one();
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ConsoleStreamerCommand {
+    Shutdown,
+}
+
+pub struct ConsoleStreamer {
+    shared: Arc<ConsoleStreamerShared>,
+    resume_cursor: ConsoleCursor,
+    task_cmd_chan: tokio::sync::mpsc::Sender<ConsoleStreamerCommand>,
+    task_handle: JoinHandle<()>,
+}
+
+/// Task appending everything written to a file by another process to the
+/// console log.
+pub struct ConsoleFileFollower {
+    stop_chan: tokio::sync::mpsc::Sender<()>,
+    task_handle: JoinHandle<std::io::Result<()>>,
+}
+
+impl ConsoleFileFollower {
+    /// Read all remaining data of the file and stop following it.
+    pub async fn stop(self) -> std::io::Result<()> {
+        // The task may have already quit because of an error:
+        let _ = self.stop_chan.send(()).await;
+        self.task_handle.await.map_err(std::io::Error::other)?
+    }
+}
+
+impl ConsoleStreamer {
+    pub fn start(
+        job_id: Uuid,
+        connector: Arc<dyn RunnerConnector>,
+        spool_dir: PathBuf,
+        config: ConsoleStreamerConfig,
+    ) -> std::io::Result<Self> {
+        let spool = ConsoleSpool::open(spool_dir, config.spool_max_bytes)?;
+        let resume_cursor = spool.start_cursor();
+        let shared = Arc::new(ConsoleStreamerShared {
+            spool: Mutex::new(spool),
+            notify: Notify::new(),
+            acked: AtomicUsize::new(resume_cursor.offset),
+        });
+
+        let (task_cmd_chan_tx, task_cmd_chan_rx) = tokio::sync::mpsc::channel(1);
+        let task_shared = shared.clone();
+        let task_handle = tokio::spawn(async move {
+            Self::task(job_id, connector, task_shared, config, task_cmd_chan_rx).await
+        });
+
+        Ok(ConsoleStreamer {
+            shared,
+            resume_cursor,
+            task_cmd_chan: task_cmd_chan_tx,
+            task_handle,
+        })
+    }
+
+    pub fn sink(&self) -> ConsoleSink {
+        ConsoleSink {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Spawn a task copying all data from `reader` into the console log. The
+    /// returned task completes once the reader reaches end of file.
+    pub fn attach_reader<R: AsyncRead + Unpin + Send + 'static>(
+        &self,
+        stdio_fd: rest_api::StdioFd,
+        mut reader: R,
+    ) -> JoinHandle<std::io::Result<()>> {
+        let sink = self.sink();
+        tokio::spawn(async move {
+            let mut buf = vec![0; READ_BUF_SIZE];
+            loop {
+                match reader.read(&mut buf).await? {
+                    0 => return Ok(()),
+                    read_len => sink.write(stdio_fd, &buf[..read_len]).await?,
+                }
+            }
+        })
+    }
+
+    /// Cursor at which this streamer continues the console log. When resuming
+    /// a previous spool, all output beyond the cursor must be written again.
+    pub fn resume_cursor(&self) -> ConsoleCursor {
+        self.resume_cursor
+    }
+
+    /// Follow a file which another process appends output to, starting at
+    /// byte `position`.
+    pub async fn follow_file(
+        &self,
+        stdio_fd: rest_api::StdioFd,
+        path: &Path,
+        position: usize,
+    ) -> std::io::Result<ConsoleFileFollower> {
+        let mut file = tokio::fs::File::open(path).await?;
+        file.seek(std::io::SeekFrom::Start(position as u64)).await?;
+
+        let sink = self.sink();
+        let (stop_chan_tx, mut stop_chan_rx) = tokio::sync::mpsc::channel(1);
+        let task_handle = tokio::spawn(async move {
+            let mut buf = vec![0; READ_BUF_SIZE];
+            let mut stopping = false;
+            loop {
+                match file.read(&mut buf).await? {
+                    0 if stopping => return Ok(()),
+                    0 => {
+                        #[rustfmt::skip]
+                        tokio::select! {
+                            _ = tokio::time::sleep(FOLLOW_POLL_INTERVAL) => (),
+                            _ = stop_chan_rx.recv() => stopping = true,
+                        }
+                    }
+                    read_len => sink.write(stdio_fd, &buf[..read_len]).await?,
+                }
+            }
+        });
+
+        Ok(ConsoleFileFollower {
+            stop_chan: stop_chan_tx,
+            task_handle,
+        })
+    }
+
+    /// Offset up to which the coordinator has acknowledged the console log.
+    pub fn acked_offset(&self) -> usize {
+        self.shared.acked.load(Ordering::Relaxed)
+    }
+
+    /// Flush all remaining console output to the coordinator (bounded by the
+    /// configured final flush timeout) and remove the spool.
+    pub async fn shutdown(self) {
+        let ConsoleStreamer {
+            shared,
+            task_cmd_chan,
+            task_handle,
+            ..
+        } = self;
+
+        // The task can only remove the spool once it holds the last
+        // reference to it:
+        std::mem::drop(shared);
+
+        debug!("Requesting console streamer to shut down.");
+        if task_cmd_chan
+            .send(ConsoleStreamerCommand::Shutdown)
+            .await
+            .is_err()
+        {
+            error!("Console streamer task has quit before receiving shutdown signal!");
+        }
+        match task_handle.await {
+            Ok(()) => debug!("Console streamer has shut down."),
+            Err(e) => error!("Console streamer task failed: {:?}", e),
+        }
+    }
+
+    /// Send a single batch starting at the acknowledged offset, and update it
+    /// according to the coordinator's response.
+    async fn send_batch(
+        job_id: Uuid,
+        connector: &Arc<dyn RunnerConnector>,
+        shared: &ConsoleStreamerShared,
+        config: &ConsoleStreamerConfig,
+        acked: &mut usize,
+    ) -> Result<(), crate::connector::RunnerConnectorError> {
+        let (bytes, stdio_map) = shared
+            .spool
+            .lock()
+            .await
+            .read(*acked, config.max_batch_bytes)?;
+        if bytes.is_empty() {
+            return Ok(());
+        }
+
+        let offset = *acked;
+        let next = offset + bytes.len();
+        let coord_acked = connector
+            .send_job_console_log(job_id, offset, next, &stdio_map, bytes)
+            .await?;
+
+        // The coordinator may acknowledge less than what we've sent, in which
+        // case we retransmit from its acknowledged offset (as far as we still
+        // retain the data):
+        let mut spool = shared.spool.lock().await;
+        *acked = std::cmp::max(std::cmp::min(coord_acked, next), spool.start());
+        spool.release(*acked)?;
+        shared.acked.store(*acked, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    async fn task(
+        job_id: Uuid,
+        connector: Arc<dyn RunnerConnector>,
+        shared: Arc<ConsoleStreamerShared>,
+        config: ConsoleStreamerConfig,
+        mut cmd_rx: tokio::sync::mpsc::Receiver<ConsoleStreamerCommand>,
+    ) {
+        // Offset up to which the coordinator has acknowledged all output:
+        let mut acked = shared.spool.lock().await.start();
+
+        // Time at which the first unsent output of the current batch was
+        // observed, and the time of the next retransmission attempt:
+        let mut batch_started: Option<Instant> = None;
+        let mut retry_at: Option<Instant> = None;
+        let mut backoff = RETRY_MIN_BACKOFF;
+
+        loop {
+            let (spool_start, spool_end) = {
+                let spool = shared.spool.lock().await;
+                (spool.start(), spool.end())
+            };
+            if acked < spool_start {
+                // Output has been discarded before it was acknowledged:
+                acked = spool_start;
+            }
+            let pending = spool_end - acked;
+
+            let flush_at = if pending == 0 {
+                batch_started = None;
+                None
+            } else if let Some(retry_at) = retry_at {
+                Some(retry_at)
+            } else if pending >= config.max_batch_bytes {
+                Some(Instant::now())
+            } else {
+                Some(*batch_started.get_or_insert_with(Instant::now) + config.flush_interval)
+            };
+
+            let flush = match flush_at {
+                Some(at) if at <= Instant::now() => true,
+                _ => {
+                    let flush_timer = async {
+                        match flush_at {
+                            Some(at) => tokio::time::sleep_until(at).await,
+                            None => std::future::pending().await,
+                        }
+                    };
+
+                    #[rustfmt::skip]
+                    tokio::select! {
+                        _ = shared.notify.notified() => false,
+                        _ = flush_timer => true,
+                        cmd_opt = cmd_rx.recv() => match cmd_opt {
+                            // Dropping the streamer shuts it down as well:
+                            Some(ConsoleStreamerCommand::Shutdown) | None => break,
+                        },
+                    }
+                }
+            };
+
+            if !flush {
+                continue;
+            }
+
+            match Self::send_batch(job_id, &connector, &shared, &config, &mut acked).await {
+                Ok(()) => {
+                    retry_at = None;
+                    backoff = RETRY_MIN_BACKOFF;
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to send console log of job {:?}, retrying in {:?}: {:?}",
+                        job_id, backoff, e
+                    );
+                    retry_at = Some(Instant::now() + backoff);
+                    backoff = std::cmp::min(backoff * 2, RETRY_MAX_BACKOFF);
+                }
+            }
+        }
+
+        // Asked to shut down. Do one last flush to the coordinator:
+        let deadline = Instant::now() + config.final_flush_timeout;
+        let mut backoff = RETRY_MIN_BACKOFF;
+        loop {
+            if acked >= shared.spool.lock().await.end() || Instant::now() >= deadline {
+                break;
+            }
+
+            match tokio::time::timeout_at(
+                deadline,
+                Self::send_batch(job_id, &connector, &shared, &config, &mut acked),
+            )
+            .await
+            {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => {
+                    warn!("Failed to flush console log of job {:?}: {:?}", job_id, e);
+                    tokio::time::sleep_until(std::cmp::min(Instant::now() + backoff, deadline))
+                        .await;
+                    backoff = std::cmp::min(backoff * 2, RETRY_MAX_BACKOFF);
+                }
+                Err(_) => break,
+            }
+        }
+
+        let spool = Arc::try_unwrap(shared)
+            .ok()
+            .map(|shared| shared.spool.into_inner());
+        match spool {
+            Some(spool) => {
+                if acked < spool.end() {
+                    warn!(
+                        "Discarding {} bytes of console output of job {:?} not \
+                         acknowledged by the coordinator.",
+                        spool.end() - acked,
+                        job_id
+                    );
+                }
+                if let Err(e) = spool.destroy() {
+                    warn!("Failed to remove console spool: {:?}", e);
+                }
+            }
+            None => {
+                warn!(
+                    "Console sinks of job {:?} are still alive, not removing spool.",
+                    job_id
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct AckingConnector;
+
+    #[async_trait::async_trait]
+    impl RunnerConnector for AckingConnector {
+        async fn run(&self) {}
+
+        async fn post_job_state(
+            &self,
+            _job_id: Uuid,
+            _job_state: rest_api::JobState,
+        ) -> Result<(), crate::connector::RunnerConnectorError> {
+            Ok(())
+        }
+
+        async fn post_job_usage(
+            &self,
+            _job_id: Uuid,
+            _usage: rest_api::JobResourceUsage,
+        ) -> Result<(), crate::connector::RunnerConnectorError> {
+            Ok(())
+        }
+
+        async fn send_job_console_log(
+            &self,
+            _job_id: Uuid,
+            _offset: usize,
+            next: usize,
+            _stdio_map: &[(rest_api::StdioFd, usize)],
+            _console_bytes: Vec<u8>,
+        ) -> Result<usize, crate::connector::RunnerConnectorError> {
+            Ok(next)
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_removes_spool() {
+        let spool_dir =
+            std::env::temp_dir().join(format!("treadmill-console-test-{}", std::process::id()));
+        let streamer = ConsoleStreamer::start(
+            Uuid::nil(),
+            Arc::new(AckingConnector),
+            spool_dir.clone(),
+            ConsoleStreamerConfig::default(),
+        )
+        .unwrap();
+
+        streamer
+            .sink()
+            .write(rest_api::StdioFd::Stdout, b"hello\n")
+            .await
+            .unwrap();
+        assert!(spool_dir.exists());
+
+        streamer.shutdown().await;
+        assert!(!spool_dir.exists());
+    }
+}
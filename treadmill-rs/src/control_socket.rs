@@ -5,5 +5,26 @@ use uuid::Uuid;
 #[async_trait]
 pub trait Runner: Send + Sync + 'static {
     async fn ssh_keys(&self, job_id: Uuid) -> Option<Vec<String>>;
+    async fn ssh_host_keys(&self, job_id: Uuid) -> Option<Vec<runner_puppet::SSHHostKey>>;
+    async fn ssh_user_ca(&self, job_id: Uuid) -> Option<runner_puppet::SSHUserCAConfig>;
+    async fn user_accounts(&self, job_id: Uuid) -> Option<Vec<runner_puppet::UserAccount>>;
     async fn network_config(&self, job_id: Uuid) -> Option<runner_puppet::NetworkConfig>;
 }
+
+/// A running control socket server, serving requests of a single job's
+/// puppet. Implemented by the various control socket transports.
+#[async_trait]
+pub trait ControlSocket: Send + Sync + 'static {
+    type Error: std::fmt::Debug + Send;
+
+    /// Queue an event to be sent to the puppet. Events are dropped while no
+    /// puppet is connected.
+    ///
+    /// This must not wait for the event to be sent, as it is invoked while
+    /// the runner's job lock is held, which requests of the puppet acquire as
+    /// well.
+    fn send_event(&self, event: runner_puppet::RunnerEvent) -> Result<(), Self::Error>;
+
+    /// Stop serving requests and close all connections.
+    async fn shutdown(self) -> Result<(), Self::Error>;
+}
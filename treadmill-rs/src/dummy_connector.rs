@@ -6,7 +6,7 @@ use log::{error, info, warn};
 use uuid::Uuid;
 
 use crate::api::coord_runner::{rest as rest_api, sse as sse_api};
-use crate::connector::{Runner, RunnerConnector};
+use crate::connector::{Runner, RunnerConnector, RunnerConnectorError};
 
 pub struct DummyRunnerConnector<R: Runner> {
     environment_id: Uuid,
@@ -47,6 +47,7 @@ impl<R: Runner> RunnerConnector for DummyRunnerConnector<R> {
                 job_id,
                 environment_id: self.environment_id,
                 ssh_keys,
+                user_accounts: vec![],
                 ssh_rendezvous_servers: vec![],
                 job_parameters: HashMap::new(),
                 board_parameters: HashMap::new(),
@@ -69,17 +70,42 @@ impl<R: Runner> RunnerConnector for DummyRunnerConnector<R> {
         }
 
         info!("Requesting job {} to stop...", job_id);
-        R::stop_job(&runner, sse_api::StopJobMessage { job_id }).await;
+        R::stop_job(
+            &runner,
+            sse_api::StopJobMessage {
+                job_id,
+                retention: sse_api::JobDataRetention::default(),
+            },
+        )
+        .await;
 
         info!("Job has stopped, exiting DummyRunnerConnector::run. Goodbye!");
     }
 
-    async fn post_job_state(&self, job_id: Uuid, job_state: rest_api::JobState) {
+    async fn post_job_state(
+        &self,
+        job_id: Uuid,
+        job_state: rest_api::JobState,
+    ) -> Result<(), RunnerConnectorError> {
         log::info!(
             "Runner provides job state for job {}: {:?}",
             job_id,
             job_state
         );
+        Ok(())
+    }
+
+    async fn post_job_usage(
+        &self,
+        job_id: Uuid,
+        usage: rest_api::JobResourceUsage,
+    ) -> Result<(), RunnerConnectorError> {
+        log::info!(
+            "Runner provides resource usage for job {}: {:?}",
+            job_id,
+            usage
+        );
+        Ok(())
     }
 
     async fn send_job_console_log(
@@ -89,7 +115,7 @@ impl<R: Runner> RunnerConnector for DummyRunnerConnector<R> {
         next: usize,
         _stdio_map: &[(rest_api::StdioFd, usize)],
         console_bytes: Vec<u8>,
-    ) {
+    ) -> Result<usize, RunnerConnectorError> {
         log::debug!(
             "Runner provides console log: job {}, offset {}, next: {}, length: {}, message: {:?}",
             job_id,
@@ -98,5 +124,6 @@ impl<R: Runner> RunnerConnector for DummyRunnerConnector<R> {
             console_bytes.len(),
             String::from_utf8_lossy(&console_bytes)
         );
+        Ok(next)
     }
 }
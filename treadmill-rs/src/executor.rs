@@ -0,0 +1,1071 @@
+//! Generic job lifecycle driver, shared between runner implementations.
+//!
+//! Runners differ in how they allocate, boot and tear down the environment of
+//! a job (e.g., a systemd-nspawn container or a network-booted board), but
+//! they all share the same job lifecycle: only one job may execute at any
+//! time, the job transitions through a fixed set of states that are reported
+//! to the coordinator, and every job has a control socket and a set of SSH
+//! rendezvous proxies. The [`JobDriver`] implements this lifecycle once, and
+//! defers all backend-specific actions to a [`JobExecutor`].
+
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::api::coord_runner::{rest as rest_api, sse as sse_api};
+use crate::api::runner_puppet;
+use crate::connector::{self, RunnerConnector};
+use crate::control_socket::{self, ControlSocket};
+use crate::ssh_host_keys::JobSSHHostKey;
+
+const RENDEZVOUS_PROXY_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(60);
+const RENDEZVOUS_PROXY_RECONNECT_WAIT: Duration = Duration::from_secs(10);
+const RENDEZVOUS_PROXY_PUBLIC_ADDR_TIMEOUT: Duration = Duration::from_secs(5);
+const DIRECT_SSH_PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+fn default_direct_ssh_probe_timeout() -> u64 {
+    10
+}
+
+/// Advertise direct SSH connections to a job's SSH server, in addition to
+/// those through rendezvous proxies.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DirectSSHConfig {
+    /// Hostname to advertise instead of the SSH server's address, e.g. of a
+    /// host forwarding a port to it.
+    #[serde(default)]
+    pub public_hostname: Option<String>,
+    /// Port to advertise instead of the SSH server's port.
+    #[serde(default)]
+    pub public_port: Option<u16>,
+    /// Seconds to wait for the SSH server to become reachable, after which
+    /// only rendezvous connections are advertised.
+    #[serde(default = "default_direct_ssh_probe_timeout")]
+    pub probe_timeout: u64,
+}
+
+/// Whether an SSH server is accepting connections at the given address,
+/// determined by reading its protocol version banner.
+async fn probe_ssh(addr: SocketAddr) -> bool {
+    use tokio::io::AsyncReadExt;
+
+    let Ok(mut stream) = tokio::net::TcpStream::connect(addr).await else {
+        return false;
+    };
+    let mut banner = [0; 4];
+    stream.read_exact(&mut banner).await.is_ok() && &banner == b"SSH-"
+}
+
+/// Grant access to jobs through SSH user certificates, in addition to the
+/// SSH keys of the job. Certificates are signed outside of the runner (e.g.,
+/// by the coordinator), and must be issued for the job's ID as a principal,
+/// such that they can't be used to access any other job. Their validity
+/// should be limited to the job's lifetime, so that access expires with it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SSHUserCAConfig {
+    /// Public keys of the trusted certificate authorities, in OpenSSH format.
+    pub trusted_user_ca_keys: Vec<String>,
+    /// Principals accepted in addition to the job's ID.
+    #[serde(default)]
+    pub principals: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SSHPreferredIPVersion {
+    #[default]
+    Unspecified,
+    V4,
+    V6,
+}
+
+/// Select the socket address of a job's SSH server, based on the configured
+/// port, the preferred IP version and the available addresses.
+pub fn ssh_socket_addr(
+    port: Option<u16>,
+    preferred_ip_version: &SSHPreferredIPVersion,
+    ipv4_addr: Option<std::net::Ipv4Addr>,
+    ipv6_addr: Option<std::net::Ipv6Addr>,
+) -> Option<SocketAddr> {
+    match (port, preferred_ip_version, ipv4_addr, ipv6_addr) {
+        (None, _, _, _) => None,
+        (Some(port), SSHPreferredIPVersion::V4, Some(ipv4_addr), _) => {
+            Some(SocketAddr::V4(SocketAddrV4::new(ipv4_addr, port)))
+        }
+        (Some(port), SSHPreferredIPVersion::V6, _, Some(ipv6_addr)) => {
+            Some(SocketAddr::V6(SocketAddrV6::new(ipv6_addr, port, 0, 0)))
+        }
+        (Some(port), _, Some(ipv4_addr), _) => {
+            Some(SocketAddr::V4(SocketAddrV4::new(ipv4_addr, port)))
+        }
+        (Some(port), _, _, Some(ipv6_addr)) => {
+            Some(SocketAddr::V6(SocketAddrV6::new(ipv6_addr, port, 0, 0)))
+        }
+        _ => None,
+    }
+}
+
+/// Default hostname assigned to a job's environment.
+pub fn job_hostname(job_id: Uuid) -> String {
+    format!("job-{}", format!("{}", job_id).split_at(10).0)
+}
+
+/// Backend-specific hooks invoked by the [`JobDriver`] over the course of a
+/// job's lifecycle.
+///
+/// Hooks report errors as human-readable messages, which the driver forwards
+/// to the coordinator as part of a [`rest_api::JobState::Failed`] state.
+#[async_trait]
+pub trait JobExecutor: Send + Sync + Sized + 'static {
+    /// Per-environment configuration of this executor.
+    type EnvironmentConfig: Clone + Send + Sync + 'static;
+
+    /// Backend-specific state of a job, holding all resources allocated for
+    /// it.
+    type Job: Send + Sync + 'static;
+
+    /// Control socket transport used to serve requests of the job's puppet.
+    type ControlSocket: ControlSocket;
+
+    fn board_id(&self) -> Uuid;
+
+    fn environment_config(&self, environment_id: Uuid) -> Option<&Self::EnvironmentConfig>;
+
+    /// Acquire all resources required to launch the job's environment, such
+    /// as its root file system.
+    async fn allocate(
+        &self,
+        msg: &sse_api::StartJobMessage,
+        environment_config: &Self::EnvironmentConfig,
+    ) -> Result<Self::Job, String>;
+
+    /// Prepare the job's environment before it is booted, e.g., by installing
+    /// files into its root file system. Progress is reported through
+    /// [`JobDriver::report_provisioning`].
+    async fn provision(
+        &self,
+        _driver: &Arc<JobDriver<Self>>,
+        _msg: &sse_api::StartJobMessage,
+        _job: &mut Self::Job,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Start the control socket server for this job.
+    async fn control_socket(
+        &self,
+        driver: &Arc<JobDriver<Self>>,
+        job: &Self::Job,
+    ) -> Result<Self::ControlSocket, String>;
+
+    /// Socket address of the job's SSH server, if it is reachable by the
+    /// runner. SSH rendezvous proxies are only started when this is provided.
+    fn ssh_socket_addr(&self, job: &Self::Job) -> Option<SocketAddr>;
+
+    /// Whether, and under which address, to advertise direct SSH connections
+    /// to the job's SSH server at [`JobExecutor::ssh_socket_addr`].
+    fn direct_ssh_config(&self, _job: &Self::Job) -> Option<DirectSSHConfig> {
+        None
+    }
+
+    /// SSH host keys generated for the job (see [`crate::ssh_host_keys`]).
+    /// They are served to the puppet, and their fingerprints are published
+    /// with the job's connection information.
+    fn ssh_host_keys<'a>(&self, _job: &'a Self::Job) -> &'a [JobSSHHostKey] {
+        &[]
+    }
+
+    /// Certificate authorities trusted to sign SSH user certificates for the
+    /// job, served to the puppet.
+    fn ssh_user_ca_config(&self, _job: &Self::Job) -> Option<SSHUserCAConfig> {
+        None
+    }
+
+    /// Boot the job's environment.
+    ///
+    /// The provided driver reference may be retained to stop the job when
+    /// the environment exits on its own.
+    async fn boot(&self, driver: &Arc<JobDriver<Self>>, job: &mut Self::Job) -> Result<(), String>;
+
+    /// Stop the job's environment. Invoked before the control socket and
+    /// rendezvous proxies are shut down.
+    async fn shutdown(&self, job: &mut Self::Job) -> Result<(), String>;
+
+    /// Resources consumed by the job. Invoked after the job was shut down
+    /// and before its resources are released, to report its total usage.
+    async fn resource_usage(&self, _job: &Self::Job) -> Option<rest_api::JobResourceUsage> {
+        None
+    }
+
+    /// Exit status of the job's environment, if known. Invoked after the job
+    /// was shut down. If the environment exited on its own (as reported
+    /// through [`JobDriver::environment_exited`]), an unsuccessful exit status
+    /// fails the job.
+    fn exit_status(&self, _job: &Self::Job) -> Option<rest_api::JobExitStatus> {
+        None
+    }
+
+    /// Release all resources held by this job. Invoked after the job was shut
+    /// down, or when starting the job failed after its resources have been
+    /// allocated. The job's data is retained according to `retention`.
+    async fn cleanup(
+        &self,
+        job: Self::Job,
+        retention: &sse_api::JobDataRetention,
+    ) -> Result<(), String>;
+
+    fn network_config(&self, job: &Self::Job) -> Option<runner_puppet::NetworkConfig>;
+
+    /// Record updated SSH keys and user accounts of a running job, e.g. such
+    /// that they're retained when reattaching to it. The puppet is notified
+    /// of the update by the driver.
+    async fn update_ssh_keys(
+        &self,
+        _job: &mut Self::Job,
+        _ssh_keys: &[String],
+        _user_accounts: &[runner_puppet::UserAccount],
+    ) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Status message to report once the job's resources have been
+    /// allocated, e.g., describing how they were obtained.
+    fn allocation_message(&self, _job: &Self::Job) -> Option<String> {
+        None
+    }
+
+    /// Offset up to which the coordinator has acknowledged the job's console
+    /// log, if the executor streams one.
+    fn console_offset(&self, _job: &Self::Job) -> Option<usize> {
+        None
+    }
+
+    /// Discover jobs left behind by a previous instance of this runner, for
+    /// instance because it crashed or was upgraded while a job was running.
+    async fn recover(&self) -> Result<Vec<RecoveredJob<Self::Job>>, String> {
+        Ok(vec![])
+    }
+
+    /// Resume observing a recovered job whose environment is still running.
+    /// This is the counterpart to [`JobExecutor::boot`] for recovered jobs.
+    async fn reattach(
+        &self,
+        _driver: &Arc<JobDriver<Self>>,
+        _job: &mut Self::Job,
+    ) -> Result<(), String> {
+        Err("Reattaching to running jobs is not supported by this runner.".to_string())
+    }
+}
+
+/// A job of a previous runner instance, as discovered by
+/// [`JobExecutor::recover`].
+pub struct RecoveredJob<J> {
+    pub job_id: Uuid,
+    pub ssh_keys: Vec<String>,
+    pub user_accounts: Vec<runner_puppet::UserAccount>,
+    pub ssh_rendezvous_servers: Vec<sse_api::RendezvousServerSpec>,
+
+    /// Whether the job's environment is still running, such that it can be
+    /// reattached to. Otherwise, only its resources are released.
+    pub running: bool,
+
+    pub job: J,
+}
+
+struct DriverJob<E: JobExecutor> {
+    job_id: Uuid,
+    ssh_keys: Vec<String>,
+    user_accounts: Vec<runner_puppet::UserAccount>,
+    // Number of times the SSH keys have been updated while the job runs:
+    ssh_keys_updates: u64,
+    control_socket: E::ControlSocket,
+    ssh_rendezvous_proxies: Vec<rendezvous_proxy::RendezvousProxy>,
+    job: E::Job,
+}
+
+/// Runner implementation driving jobs through their lifecycle by means of a
+/// [`JobExecutor`].
+pub struct JobDriver<E: JobExecutor> {
+    connector: Arc<dyn RunnerConnector>,
+    executor: E,
+    current_job: Mutex<Option<DriverJob<E>>>,
+
+    // The job occupying the board and the latest state reported for it. In
+    // contrast to `current_job`, this is also set while the job is starting
+    // or stopping, and never held across state transitions:
+    board_job: std::sync::Mutex<Option<(Uuid, rest_api::JobState)>>,
+
+    // Error encountered while releasing the resources of the most recent
+    // job, which may have left resources behind on the board:
+    cleanup_error: std::sync::Mutex<Option<String>>,
+}
+
+impl<E: JobExecutor> JobDriver<E> {
+    pub fn new(connector: Arc<dyn RunnerConnector>, executor: E) -> Self {
+        JobDriver {
+            connector,
+            executor,
+            current_job: Mutex::new(None),
+            board_job: std::sync::Mutex::new(None),
+            cleanup_error: std::sync::Mutex::new(None),
+        }
+    }
+
+    pub fn executor(&self) -> &E {
+        &self.executor
+    }
+
+    pub fn connector(&self) -> &Arc<dyn RunnerConnector> {
+        &self.connector
+    }
+
+    /// Report a job's state to the coordinator. Errors are logged, as there
+    /// is no way to recover from them here.
+    async fn post_job_state(&self, job_id: Uuid, job_state: rest_api::JobState) {
+        {
+            let mut board_job = self.board_job.lock().unwrap();
+            if board_job.as_ref().map(|(id, _)| *id) == Some(job_id) {
+                *board_job = match job_state {
+                    rest_api::JobState::Finished { .. } | rest_api::JobState::Failed { .. } => None,
+                    _ => Some((job_id, job_state.clone())),
+                };
+            }
+        }
+
+        if let Err(e) = self.connector.post_job_state(job_id, job_state).await {
+            error!(
+                "Failed to report state of job {:?} to the coordinator: {:?}",
+                job_id, e
+            );
+        }
+    }
+
+    /// Report progress of provisioning a job's environment, moving the job
+    /// into the [`rest_api::JobStartingStage::Provisioning`] stage.
+    pub async fn report_provisioning(&self, job_id: Uuid, status_message: String) {
+        self.post_job_state(
+            job_id,
+            rest_api::JobState::Starting {
+                stage: rest_api::JobStartingStage::Provisioning,
+                status_message: Some(status_message),
+            },
+        )
+        .await;
+    }
+
+    async fn post_failed(&self, job_id: Uuid, status_message: String) {
+        self.post_job_state(
+            job_id,
+            rest_api::JobState::Failed {
+                status_message: Some(status_message),
+                resource_usage: None,
+                exit_status: None,
+            },
+        )
+        .await;
+    }
+
+    /// Release the resources of a job whose startup failed and report the
+    /// original error to the coordinator.
+    async fn abort_start(&self, job_id: Uuid, job: E::Job, emsg: String) {
+        // Keep the job's data for debugging. It's removed according to the
+        // runner's retention policy:
+        let cleanup_res = self
+            .executor
+            .cleanup(job, &sse_api::JobDataRetention::Keep)
+            .await;
+        if let Err(ref cleanup_emsg) = cleanup_res {
+            warn!(
+                "Failed to clean up resources of job {:?}: {}",
+                job_id, cleanup_emsg
+            );
+        }
+        self.record_cleanup(job_id, &cleanup_res);
+
+        self.post_failed(job_id, emsg).await;
+    }
+
+    /// Spawn rendezvous proxy clients for SSH connections to the job, if the
+    /// executor provides an address that we can reach.
+    async fn start_rendezvous_proxies(
+        &self,
+        job: &E::Job,
+        server_specs: &[sse_api::RendezvousServerSpec],
+    ) -> Vec<rendezvous_proxy::RendezvousProxy> {
+        let mut ssh_rendezvous_proxies = Vec::with_capacity(server_specs.len());
+        if let Some(sa) = self.executor.ssh_socket_addr(job) {
+            for server_spec in server_specs {
+                ssh_rendezvous_proxies.push(
+                    rendezvous_proxy::RendezvousProxy::start(
+                        server_spec.client_id,
+                        server_spec.server_base_url.clone(),
+                        sa,
+                        server_spec.auth_token.clone(),
+                        RENDEZVOUS_PROXY_KEEPALIVE_TIMEOUT,
+                        RENDEZVOUS_PROXY_RECONNECT_WAIT,
+                    )
+                    .await,
+                );
+            }
+        }
+        ssh_rendezvous_proxies
+    }
+
+    fn host_key_fingerprints(&self, job: &E::Job) -> Vec<String> {
+        self.executor
+            .ssh_host_keys(job)
+            .iter()
+            .map(|host_key| host_key.fingerprint.clone())
+            .collect()
+    }
+
+    /// Direct SSH connection to a job, along with the address of its SSH
+    /// server and how long to wait for it to become reachable.
+    fn direct_ssh_connection_info(
+        &self,
+        job: &E::Job,
+    ) -> Option<(SocketAddr, Duration, rest_api::JobSessionConnectionInfo)> {
+        let direct_ssh_cfg = self.executor.direct_ssh_config(job)?;
+        let ssh_socket_addr = self.executor.ssh_socket_addr(job)?;
+
+        let connection_info = rest_api::JobSessionConnectionInfo::DirectSSH {
+            hostname: direct_ssh_cfg
+                .public_hostname
+                .unwrap_or_else(|| ssh_socket_addr.ip().to_string()),
+            port: direct_ssh_cfg
+                .public_port
+                .unwrap_or_else(|| ssh_socket_addr.port()),
+            host_key_fingerprints: self.host_key_fingerprints(job),
+        };
+        Some((
+            ssh_socket_addr,
+            Duration::from_secs(direct_ssh_cfg.probe_timeout),
+            connection_info,
+        ))
+    }
+
+    /// Collect the public addresses of a job's rendezvous proxies.
+    async fn rendezvous_connection_info(
+        &self,
+        job: &E::Job,
+        ssh_rendezvous_proxies: &[rendezvous_proxy::RendezvousProxy],
+    ) -> Vec<rest_api::JobSessionConnectionInfo> {
+        let mut connection_info = Vec::with_capacity(ssh_rendezvous_proxies.len());
+
+        // TODO: it'd be nice if this didn't have to be
+        // sequential. But using tokio's JoinSet we get lifetime
+        // issues here, as .spawn() requires a 'static borrow of the
+        // rendezvous proxies.
+        for proxy in ssh_rendezvous_proxies.iter() {
+            match proxy
+                .public_addr(RENDEZVOUS_PROXY_PUBLIC_ADDR_TIMEOUT)
+                .await
+            {
+                Some((hostname, port)) => {
+                    connection_info.push(rest_api::JobSessionConnectionInfo::RendezvousSSH {
+                        hostname,
+                        port,
+                        host_key_fingerprints: self.host_key_fingerprints(job),
+                    });
+                }
+                None => {
+                    warn!("Rendezvous proxy did not provide public address before timeout.");
+                }
+            }
+        }
+        connection_info
+    }
+
+    /// Advertise a direct SSH connection to a job once its SSH server has
+    /// been found to be reachable, by reporting the job as `Ready` again. The
+    /// server is probed in the background, such that the job can be stopped
+    /// or updated in the meantime.
+    fn spawn_direct_ssh_probe(
+        this: &Arc<Self>,
+        job_id: Uuid,
+        job: &E::Job,
+        mut connection_info: Vec<rest_api::JobSessionConnectionInfo>,
+    ) {
+        let Some((ssh_socket_addr, probe_timeout, direct_ssh)) =
+            this.direct_ssh_connection_info(job)
+        else {
+            return;
+        };
+
+        let this = this.clone();
+        tokio::spawn(async move {
+            let probe = async {
+                while !probe_ssh(ssh_socket_addr).await {
+                    tokio::time::sleep(DIRECT_SSH_PROBE_INTERVAL).await;
+                }
+            };
+            if tokio::time::timeout(probe_timeout, probe).await.is_err() {
+                warn!(
+                    "SSH server at {} not reachable within {:?}, not advertising direct SSH.",
+                    ssh_socket_addr, probe_timeout
+                );
+                return;
+            }
+
+            // Hold the lock while reporting the job's state, such that this
+            // can't race with the job being stopped:
+            let current_job_lg = this.current_job.lock().await;
+            match *current_job_lg {
+                Some(ref driver_job) if driver_job.job_id == job_id => (),
+                _ => return,
+            }
+
+            connection_info.insert(0, direct_ssh);
+            this.post_job_state(
+                job_id,
+                rest_api::JobState::Ready {
+                    connection_info,
+                    status_message: None,
+                },
+            )
+            .await;
+        });
+    }
+
+    async fn shutdown_job_services(
+        control_socket: E::ControlSocket,
+        ssh_rendezvous_proxies: Vec<rendezvous_proxy::RendezvousProxy>,
+    ) {
+        debug!("Shutting down control socket.");
+        if let Err(e) = control_socket.shutdown().await {
+            warn!("Error while shutting down control socket: {:?}", e);
+        }
+
+        for proxy in ssh_rendezvous_proxies {
+            if let Err(e) = proxy.shutdown().await {
+                warn!("Error while shutting down rendezvous proxy client: {:?}", e);
+            }
+        }
+    }
+
+    /// Recover jobs left behind by a previous instance of this runner. Jobs
+    /// whose environment is still running are reattached to and resume
+    /// normal operation, all others are cleaned up and reported as failed.
+    ///
+    /// This must be invoked before the connector processes any requests.
+    pub async fn recover(this: &Arc<Self>) {
+        let recovered_jobs = match this.executor.recover().await {
+            Ok(recovered_jobs) => recovered_jobs,
+            Err(emsg) => {
+                error!(
+                    "Failed to recover jobs of previous runner instance: {}",
+                    emsg
+                );
+                return;
+            }
+        };
+
+        let mut current_job_lg = this.current_job.lock().await;
+        for RecoveredJob {
+            job_id,
+            ssh_keys,
+            user_accounts,
+            ssh_rendezvous_servers,
+            running,
+            mut job,
+        } in recovered_jobs
+        {
+            if !running {
+                this.abort_start(
+                    job_id,
+                    job,
+                    "Job environment exited while the runner was not running.".to_string(),
+                )
+                .await;
+                continue;
+            }
+
+            if current_job_lg.is_some() {
+                // Only a single job may execute at any time:
+                if let Err(emsg) = this.executor.shutdown(&mut job).await {
+                    warn!("Failed to shut down job {:?}: {}", job_id, emsg);
+                }
+                this.abort_start(
+                    job_id,
+                    job,
+                    "Found multiple running jobs after runner restart.".to_string(),
+                )
+                .await;
+                continue;
+            }
+
+            info!("Reattaching to running job {:?}", job_id);
+
+            // The coordinator has last been informed of the job's state by the
+            // previous runner instance. Track the job as occupying the board,
+            // without reporting a new state until it has been reattached to:
+            *this.board_job.lock().unwrap() = Some((
+                job_id,
+                rest_api::JobState::Starting {
+                    stage: rest_api::JobStartingStage::Booting,
+                    status_message: Some("Reattaching to running job.".to_string()),
+                },
+            ));
+
+            if let Err(emsg) = this.executor.reattach(this, &mut job).await {
+                if let Err(e) = this.executor.shutdown(&mut job).await {
+                    warn!("Failed to shut down job {:?}: {}", job_id, e);
+                }
+                this.abort_start(job_id, job, emsg).await;
+                continue;
+            }
+
+            let control_socket = match this.executor.control_socket(this, &job).await {
+                Ok(control_socket) => control_socket,
+                Err(emsg) => {
+                    if let Err(e) = this.executor.shutdown(&mut job).await {
+                        warn!("Failed to shut down job {:?}: {}", job_id, e);
+                    }
+                    this.abort_start(job_id, job, emsg).await;
+                    continue;
+                }
+            };
+
+            let ssh_rendezvous_proxies = this
+                .start_rendezvous_proxies(&job, &ssh_rendezvous_servers)
+                .await;
+
+            let connection_info = this
+                .rendezvous_connection_info(&job, &ssh_rendezvous_proxies)
+                .await;
+            this.post_job_state(
+                job_id,
+                rest_api::JobState::Ready {
+                    connection_info: connection_info.clone(),
+                    status_message: None,
+                },
+            )
+            .await;
+            Self::spawn_direct_ssh_probe(this, job_id, &job, connection_info);
+
+            *current_job_lg = Some(DriverJob {
+                job_id,
+                ssh_keys,
+                user_accounts,
+                ssh_keys_updates: 0,
+                control_socket,
+                ssh_rendezvous_proxies,
+                job,
+            });
+        }
+    }
+
+    /// Track whether releasing a job's resources succeeded, which determines
+    /// the health reported to the coordinator.
+    fn record_cleanup(&self, job_id: Uuid, cleanup_res: &Result<(), String>) {
+        *self.cleanup_error.lock().unwrap() = cleanup_res
+            .as_ref()
+            .err()
+            .map(|emsg| format!("Failed to release resources of job {:?}: {}", job_id, emsg));
+    }
+
+    /// Stop a job whose environment exited on its own. In contrast to a stop
+    /// requested by the coordinator, the job fails if the environment's exit
+    /// status indicates an error.
+    pub async fn environment_exited(this: &Arc<Self>, job_id: Uuid) {
+        Self::stop_job_inner(
+            this,
+            sse_api::StopJobMessage {
+                job_id,
+                retention: sse_api::JobDataRetention::Keep,
+            },
+            true,
+        )
+        .await
+    }
+
+    async fn stop_job_inner(
+        this: &Arc<Self>,
+        msg: sse_api::StopJobMessage,
+        environment_exited: bool,
+    ) {
+        // First, grab the `current_job` mutex and ensure that the requested job
+        // is running. We take the job object from the option, but to prevent
+        // another task to race with this method, hold the lock guard til the
+        // very end:
+        let mut current_job_lg = this.current_job.lock().await;
+        let mut job = match current_job_lg.take() {
+            Some(job) if job.job_id == msg.job_id => job,
+            other => {
+                *current_job_lg = other;
+                this.post_failed(
+                    msg.job_id,
+                    format!(
+                        "Cannot stop job {:?} on board {:?}, not running!",
+                        msg.job_id,
+                        this.executor.board_id(),
+                    ),
+                )
+                .await;
+                return;
+            }
+        };
+
+        // The requested job is currently running, procede to stop it.
+        // Transition into the shutdown state:
+        this.post_job_state(
+            msg.job_id,
+            rest_api::JobState::Stopping {
+                status_message: None,
+            },
+        )
+        .await;
+
+        let shutdown_res = this.executor.shutdown(&mut job.job).await;
+
+        // The job's environment is stopped. Destroy the control socket and
+        // shut down all rendezvous proxy clients:
+        Self::shutdown_job_services(job.control_socket, job.ssh_rendezvous_proxies).await;
+
+        // Account for the job's resources before releasing them:
+        let resource_usage = this.executor.resource_usage(&job.job).await;
+        let exit_status = this.executor.exit_status(&job.job);
+
+        // Release the job's resources. This is attempted even if the shutdown
+        // failed, but the first error is reported:
+        let cleanup_res = this.executor.cleanup(job.job, &msg.retention).await;
+        this.record_cleanup(msg.job_id, &cleanup_res);
+
+        // Manually drop the lock guard here, to ensure that it stays in scope
+        // til the end of this function:
+        core::mem::drop(current_job_lg);
+
+        // An environment which exited on its own with an error fails the job,
+        // whereas one stopped at the coordinator's request is expected to be
+        // terminated:
+        let exit_res = match exit_status {
+            Some(status) if environment_exited && !status.success() => {
+                Err(format!("Environment {}", status))
+            }
+            _ => Ok(()),
+        };
+
+        match shutdown_res.and(cleanup_res).and(exit_res) {
+            Ok(()) => {
+                // Mark job as finished:
+                this.post_job_state(
+                    msg.job_id,
+                    rest_api::JobState::Finished {
+                        status_message: None,
+                        resource_usage,
+                        exit_status,
+                    },
+                )
+                .await;
+            }
+            Err(emsg) => {
+                this.post_job_state(
+                    msg.job_id,
+                    rest_api::JobState::Failed {
+                        status_message: Some(emsg),
+                        resource_usage,
+                        exit_status,
+                    },
+                )
+                .await;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<E: JobExecutor> connector::Runner for JobDriver<E> {
+    async fn start_job(this: &Arc<Self>, msg: sse_api::StartJobMessage) {
+        // This method must not block for long periods of time. We're provided
+        // an &Arc<Self> to be able to launch async tasks, while returning
+        // immediately. For now, we assume that all actions performed here are
+        // reasonably fast, and we thus only return once the job is started.
+
+        // First, grab the `current_job` mutex. If there is already another job
+        // running, we abort:
+        let mut current_job_lg = this.current_job.lock().await;
+        if let Some(DriverJob {
+            job_id: ref running_job_id,
+            ..
+        }) = *current_job_lg
+        {
+            this.post_failed(
+                msg.job_id,
+                format!(
+                    "Cannot start job {:?} on board {:?}, still executing job {:?}",
+                    msg.job_id,
+                    this.executor.board_id(),
+                    running_job_id
+                ),
+            )
+            .await;
+            return;
+        }
+
+        // Try to get a hold of the requested job environment. Error out if the
+        // environment can't be found:
+        let environment_cfg =
+            if let Some(env_cfg) = this.executor.environment_config(msg.environment_id) {
+                env_cfg
+            } else {
+                this.post_failed(
+                    msg.job_id,
+                    format!(
+                        "Cannot start job {:?} on board {:?}, unknown environment {:?}",
+                        msg.job_id,
+                        this.executor.board_id(),
+                        msg.environment_id
+                    ),
+                )
+                .await;
+                return;
+            };
+
+        // We're not executing any job and acquired the lock, begin allocating
+        // the job's resources. From now on, this job occupies the board:
+        let allocating_state = rest_api::JobState::Starting {
+            stage: rest_api::JobStartingStage::Allocating,
+            status_message: None,
+        };
+        *this.board_job.lock().unwrap() = Some((msg.job_id, allocating_state.clone()));
+        this.post_job_state(msg.job_id, allocating_state).await;
+
+        let mut job = match this.executor.allocate(&msg, environment_cfg).await {
+            Ok(job) => job,
+            Err(emsg) => {
+                this.post_failed(msg.job_id, emsg).await;
+                return;
+            }
+        };
+
+        if let Err(emsg) = this.executor.provision(this, &msg, &mut job).await {
+            this.abort_start(msg.job_id, job, emsg).await;
+            return;
+        }
+
+        // Start the control socket handler:
+        let control_socket = match this.executor.control_socket(this, &job).await {
+            Ok(control_socket) => control_socket,
+            Err(emsg) => {
+                this.abort_start(msg.job_id, job, emsg).await;
+                return;
+            }
+        };
+
+        let ssh_rendezvous_proxies = this
+            .start_rendezvous_proxies(&job, &msg.ssh_rendezvous_servers)
+            .await;
+
+        // All resources have been allocated, mark the job as booting:
+        this.post_job_state(
+            msg.job_id,
+            rest_api::JobState::Starting {
+                stage: rest_api::JobStartingStage::Booting,
+                status_message: this.executor.allocation_message(&job),
+            },
+        )
+        .await;
+
+        if let Err(emsg) = this.executor.boot(this, &mut job).await {
+            Self::shutdown_job_services(control_socket, ssh_rendezvous_proxies).await;
+            this.abort_start(msg.job_id, job, emsg).await;
+            return;
+        }
+
+        // Report the job as ready right away. A direct SSH connection is
+        // advertised separately, once the job's SSH server is reachable:
+        let connection_info = this
+            .rendezvous_connection_info(&job, &ssh_rendezvous_proxies)
+            .await;
+        this.post_job_state(
+            msg.job_id,
+            rest_api::JobState::Ready {
+                connection_info: connection_info.clone(),
+                status_message: None,
+            },
+        )
+        .await;
+        Self::spawn_direct_ssh_probe(this, msg.job_id, &job, connection_info);
+
+        *current_job_lg = Some(DriverJob {
+            job_id: msg.job_id,
+            ssh_keys: msg.ssh_keys,
+            user_accounts: msg.user_accounts,
+            ssh_keys_updates: 0,
+            control_socket,
+            ssh_rendezvous_proxies,
+            job,
+        });
+    }
+
+    async fn stop_job(this: &Arc<Self>, msg: sse_api::StopJobMessage) {
+        Self::stop_job_inner(this, msg, false).await
+    }
+
+    async fn update_ssh_keys(this: &Arc<Self>, msg: sse_api::UpdateSSHKeysMessage) {
+        let mut current_job_lg = this.current_job.lock().await;
+        let driver_job = match *current_job_lg {
+            Some(ref mut driver_job) if driver_job.job_id == msg.job_id => driver_job,
+            _ => {
+                warn!(
+                    "Cannot update SSH keys of job {:?} on board {:?}, not running!",
+                    msg.job_id,
+                    this.executor.board_id(),
+                );
+                return;
+            }
+        };
+
+        let user_accounts = msg
+            .user_accounts
+            .unwrap_or_else(|| driver_job.user_accounts.clone());
+
+        // The keys are served to the puppet regardless of whether the
+        // executor managed to record them:
+        if let Err(emsg) = this
+            .executor
+            .update_ssh_keys(&mut driver_job.job, &msg.ssh_keys, &user_accounts)
+            .await
+        {
+            warn!(
+                "Failed to record updated SSH keys of job {:?}: {}",
+                msg.job_id, emsg
+            );
+        }
+        driver_job.ssh_keys = msg.ssh_keys;
+        driver_job.user_accounts = user_accounts;
+        driver_job.ssh_keys_updates += 1;
+
+        info!(
+            "Updated SSH keys of job {:?}, notifying the puppet.",
+            msg.job_id
+        );
+        if let Err(e) =
+            driver_job
+                .control_socket
+                .send_event(runner_puppet::RunnerEvent::SSHKeysUpdatedEvent {
+                    event_id: driver_job.ssh_keys_updates,
+                })
+        {
+            warn!(
+                "Failed to notify the puppet of job {:?} of updated SSH keys: {:?}",
+                msg.job_id, e
+            );
+        }
+    }
+
+    async fn report_state(this: &Arc<Self>) -> rest_api::RunnerState {
+        let board_job = this.board_job.lock().unwrap().clone();
+
+        let current_job = board_job.map(|(job_id, job_state)| {
+            // The console offset can only be determined while the job isn't
+            // transitioning between states:
+            let console_offset = match this.current_job.try_lock() {
+                Ok(current_job_lg) => match *current_job_lg {
+                    Some(ref driver_job) if driver_job.job_id == job_id => {
+                        this.executor.console_offset(&driver_job.job)
+                    }
+                    _ => None,
+                },
+                Err(_) => None,
+            };
+
+            rest_api::RunnerJobState {
+                job_id,
+                job_state,
+                console_offset,
+            }
+        });
+
+        let health = match this.cleanup_error.lock().unwrap().clone() {
+            Some(status_message) => rest_api::RunnerHealth::Degraded { status_message },
+            None => rest_api::RunnerHealth::Healthy,
+        };
+
+        rest_api::RunnerState {
+            current_job,
+            health,
+        }
+    }
+}
+
+#[async_trait]
+impl<E: JobExecutor> control_socket::Runner for JobDriver<E> {
+    async fn ssh_keys(&self, tgt_job_id: Uuid) -> Option<Vec<String>> {
+        match *self.current_job.lock().await {
+            Some(DriverJob {
+                ref job_id,
+                ref ssh_keys,
+                ..
+            }) if *job_id == tgt_job_id => Some(ssh_keys.clone()),
+            _ => None,
+        }
+    }
+
+    async fn ssh_host_keys(&self, tgt_job_id: Uuid) -> Option<Vec<runner_puppet::SSHHostKey>> {
+        match *self.current_job.lock().await {
+            Some(DriverJob {
+                ref job_id,
+                ref job,
+                ..
+            }) if *job_id == tgt_job_id => Some(
+                self.executor
+                    .ssh_host_keys(job)
+                    .iter()
+                    .map(|host_key| host_key.key.clone())
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    async fn user_accounts(&self, tgt_job_id: Uuid) -> Option<Vec<runner_puppet::UserAccount>> {
+        match *self.current_job.lock().await {
+            Some(DriverJob {
+                ref job_id,
+                ref user_accounts,
+                ..
+            }) if *job_id == tgt_job_id => Some(user_accounts.clone()),
+            _ => None,
+        }
+    }
+
+    async fn ssh_user_ca(&self, tgt_job_id: Uuid) -> Option<runner_puppet::SSHUserCAConfig> {
+        match *self.current_job.lock().await {
+            Some(DriverJob {
+                ref job_id,
+                ref job,
+                ..
+            }) if *job_id == tgt_job_id => Some(match self.executor.ssh_user_ca_config(job) {
+                Some(ca_cfg) => runner_puppet::SSHUserCAConfig {
+                    trusted_user_ca_keys: ca_cfg.trusted_user_ca_keys,
+                    authorized_principals: std::iter::once(job_id.to_string())
+                        .chain(ca_cfg.principals)
+                        .collect(),
+                },
+                None => runner_puppet::SSHUserCAConfig {
+                    trusted_user_ca_keys: vec![],
+                    authorized_principals: vec![],
+                },
+            }),
+            _ => None,
+        }
+    }
+
+    async fn network_config(&self, tgt_job_id: Uuid) -> Option<runner_puppet::NetworkConfig> {
+        match *self.current_job.lock().await {
+            Some(DriverJob {
+                ref job_id,
+                ref job,
+                ..
+            }) if *job_id == tgt_job_id => self.executor.network_config(job),
+            _ => None,
+        }
+    }
+}
@@ -2,5 +2,17 @@ pub mod api;
 pub mod connector;
 pub mod control_socket;
 
+#[cfg(feature = "console")]
+pub mod console;
+
 #[cfg(feature = "dummy_connector")]
 pub mod dummy_connector;
+
+#[cfg(feature = "executor")]
+pub mod executor;
+
+#[cfg(feature = "executor")]
+pub mod rollback;
+
+#[cfg(feature = "executor")]
+pub mod ssh_host_keys;
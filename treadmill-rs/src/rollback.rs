@@ -0,0 +1,199 @@
+//! Transactional allocation of a job's resources.
+//!
+//! Starting a job typically involves a sequence of steps which each acquire
+//! some resource (e.g., create a file system, mount it, bind a socket). When
+//! any step fails, all previously acquired resources must be released again.
+//! A [`Rollback`] records an undo action for each completed step. If a later
+//! step fails, these actions are executed in reverse order. As later steps
+//! typically build upon earlier ones (e.g., a file system mounted in a
+//! created directory), unwinding stops at the first undo action that fails:
+//! undoing earlier steps could otherwise destroy resources still in use.
+//! Once all steps succeeded, the [`Rollback`] is [committed](Rollback::commit), and ownership
+//! of the resources passes on to the job.
+
+use std::future::Future;
+use std::pin::Pin;
+
+use log::{debug, error, warn};
+
+type UndoFuture = Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'static>>;
+
+/// Undo stack of the steps performed while allocating a job's resources.
+///
+/// Undo actions are futures, which are only polled when the rollback is
+/// unwound. If a [`Rollback`] is dropped without being committed or unwound,
+/// it is unwound in a background task on the current Tokio runtime.
+#[must_use = "dropping a Rollback unwinds it in the background"]
+pub struct Rollback {
+    steps: Vec<(String, UndoFuture)>,
+}
+
+impl Rollback {
+    pub fn new() -> Self {
+        Rollback { steps: vec![] }
+    }
+
+    /// Register the undo action of a step that has just succeeded. The
+    /// description is used to log progress and errors when unwinding.
+    pub fn push<F>(&mut self, description: impl Into<String>, undo: F)
+    where
+        F: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.steps.push((description.into(), Box::pin(undo)));
+    }
+
+    /// Check the result of a step. On failure, all previously registered
+    /// steps are undone, and the error is returned. Errors encountered while
+    /// undoing, and the steps skipped because of them, are appended to the
+    /// error message, as they have left resources behind.
+    pub async fn check<T>(&mut self, res: Result<T, String>) -> Result<T, String> {
+        match res {
+            Ok(v) => Ok(v),
+            Err(emsg) => {
+                let undo_errors = Self::unwind_steps(std::mem::take(&mut self.steps)).await;
+                if undo_errors.is_empty() {
+                    Err(emsg)
+                } else {
+                    Err(format!(
+                        "{} Additionally, rolling back failed: {}",
+                        emsg,
+                        undo_errors.join("; ")
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Undo all registered steps, in reverse order, up to the first undo
+    /// action that fails. Returns the error and all steps that were skipped.
+    pub async fn unwind(mut self) -> Vec<String> {
+        Self::unwind_steps(std::mem::take(&mut self.steps)).await
+    }
+
+    /// All steps succeeded. Discard their undo actions, the resources are now
+    /// owned by the caller.
+    pub fn commit(mut self) {
+        self.steps.clear();
+    }
+
+    async fn unwind_steps(steps: Vec<(String, UndoFuture)>) -> Vec<String> {
+        let mut errors = vec![];
+        for (description, undo) in steps.into_iter().rev() {
+            if !errors.is_empty() {
+                warn!(
+                    "Not rolling back \"{}\" after previous failure.",
+                    description
+                );
+                errors.push(format!("{}: skipped", description));
+                continue;
+            }
+
+            debug!("Rolling back: {}", description);
+            if let Err(emsg) = undo.await {
+                warn!("Failed to roll back \"{}\": {}", description, emsg);
+                errors.push(format!("{}: {}", description, emsg));
+            }
+        }
+        errors
+    }
+}
+
+impl Default for Rollback {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Rollback {
+    fn drop(&mut self) {
+        if self.steps.is_empty() {
+            return;
+        }
+
+        let steps = std::mem::take(&mut self.steps);
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                warn!("Rollback dropped without being committed, unwinding in the background.");
+                handle.spawn(Self::unwind_steps(steps));
+            }
+            Err(_) => {
+                error!(
+                    "Rollback dropped outside of a Tokio runtime, leaking {} resources!",
+                    steps.len()
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn push_step(rollback: &mut Rollback, log: &Arc<Mutex<Vec<u32>>>, step: u32, fail: bool) {
+        let log = log.clone();
+        rollback.push(format!("step {}", step), async move {
+            log.lock().unwrap().push(step);
+            if fail {
+                Err("failed".to_string())
+            } else {
+                Ok(())
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn unwinds_in_reverse_order() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let mut rollback = Rollback::new();
+        for step in 0..3 {
+            push_step(&mut rollback, &log, step, false);
+        }
+
+        assert!(rollback.unwind().await.is_empty());
+        assert_eq!(*log.lock().unwrap(), vec![2, 1, 0]);
+    }
+
+    #[tokio::test]
+    async fn skips_steps_after_failure() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let mut rollback = Rollback::new();
+        push_step(&mut rollback, &log, 0, false);
+        push_step(&mut rollback, &log, 1, true);
+        push_step(&mut rollback, &log, 2, false);
+
+        let res: Result<(), String> = rollback.check(Err("step 3 failed".to_string())).await;
+        assert_eq!(
+            res.unwrap_err(),
+            "step 3 failed Additionally, rolling back failed: step 1: failed; step 0: skipped"
+        );
+        assert_eq!(*log.lock().unwrap(), vec![2, 1]);
+
+        // The steps have been consumed:
+        assert!(rollback.unwind().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn check_passes_success_through() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let mut rollback = Rollback::new();
+        push_step(&mut rollback, &log, 0, false);
+
+        assert_eq!(rollback.check(Ok(42)).await, Ok(42));
+        assert!(log.lock().unwrap().is_empty());
+        rollback.commit();
+    }
+
+    #[tokio::test]
+    async fn commit_disarms() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let mut rollback = Rollback::new();
+        push_step(&mut rollback, &log, 0, false);
+        rollback.commit();
+
+        // Give a (wrongly) spawned background unwind a chance to run:
+        tokio::task::yield_now().await;
+        assert!(log.lock().unwrap().is_empty());
+    }
+}
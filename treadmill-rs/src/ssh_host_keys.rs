@@ -0,0 +1,125 @@
+//! Per-job SSH host keys.
+//!
+//! Every job may be assigned freshly generated SSH host keys, which are
+//! installed into its environment either by the runner (e.g., while
+//! provisioning a root file system) or by the puppet, which requests them
+//! through the control socket. Their fingerprints are published alongside
+//! the job's connection information, such that users can verify the host
+//! they connect to, even through a rendezvous proxy.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use uuid::Uuid;
+
+use crate::api::runner_puppet;
+
+/// Algorithms to generate host keys for.
+const ALGORITHMS: &[&str] = &["ed25519", "ecdsa"];
+
+/// An SSH host key of a job, along with its fingerprint.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobSSHHostKey {
+    pub key: runner_puppet::SSHHostKey,
+    /// SHA256 fingerprint of the public key, as printed by `ssh-keygen -l`
+    /// (e.g., "SHA256:...").
+    pub fingerprint: String,
+}
+
+async fn ssh_keygen(args: &[&std::ffi::OsStr]) -> Result<String, String> {
+    let output = Command::new("ssh-keygen")
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| format!("Running \"ssh-keygen\" failed: {:?}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "ssh-keygen failed with exit-status {:?}. Stderr: {}",
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+async fn generate_key(dir: &Path, job_id: Uuid, algorithm: &str) -> Result<JobSSHHostKey, String> {
+    let key_path = dir.join(format!("ssh_host_{}_key", algorithm));
+    let pub_key_path = key_path.with_extension("pub");
+    let comment = format!("treadmill-job-{}", job_id);
+
+    ssh_keygen(&[
+        "-q".as_ref(),
+        "-t".as_ref(),
+        algorithm.as_ref(),
+        "-N".as_ref(),
+        "".as_ref(),
+        "-C".as_ref(),
+        comment.as_ref(),
+        "-f".as_ref(),
+        key_path.as_os_str(),
+    ])
+    .await?;
+
+    // Prints `<bits> SHA256:<hash> <comment> (<type>)`:
+    let fingerprint = ssh_keygen(&[
+        "-l".as_ref(),
+        "-E".as_ref(),
+        "sha256".as_ref(),
+        "-f".as_ref(),
+        pub_key_path.as_os_str(),
+    ])
+    .await?
+    .split_whitespace()
+    .nth(1)
+    .map(|fingerprint| fingerprint.to_string())
+    .ok_or_else(|| format!("Failed to parse fingerprint of {:?}", pub_key_path))?;
+
+    let read = |path: std::path::PathBuf| async move {
+        tokio::fs::read_to_string(&path)
+            .await
+            .map_err(|e| format!("Failed to read {:?}: {:?}", path, e))
+    };
+
+    Ok(JobSSHHostKey {
+        key: runner_puppet::SSHHostKey {
+            algorithm: algorithm.to_string(),
+            private_key: read(key_path).await?,
+            public_key: read(pub_key_path).await?,
+        },
+        fingerprint,
+    })
+}
+
+/// Generate a fresh set of SSH host keys for a job.
+pub async fn generate(job_id: Uuid) -> Result<Vec<JobSSHHostKey>, String> {
+    // The private keys never leave this directory other than through the
+    // returned keys, so restrict it to the runner's user. Its name is unique,
+    // such that a directory left behind by a previous attempt (e.g., when the
+    // runner crashed) is never reused:
+    let dir =
+        std::env::temp_dir().join(format!("treadmill-host-keys-{}-{}", job_id, Uuid::new_v4()));
+    tokio::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .await
+        .map_err(|e| format!("Failed to create directory {:?}: {:?}", dir, e))?;
+
+    let mut keys = Vec::with_capacity(ALGORITHMS.len());
+    let mut res = Ok(());
+    for algorithm in ALGORITHMS {
+        match generate_key(&dir, job_id, algorithm).await {
+            Ok(key) => keys.push(key),
+            Err(e) => {
+                res = Err(e);
+                break;
+            }
+        }
+    }
+
+    if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+        log::warn!("Failed to remove directory {:?}: {:?}", dir, e);
+    }
+
+    res.map(|()| keys)
+}
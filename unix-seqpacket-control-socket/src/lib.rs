@@ -7,11 +7,12 @@ use tokio::task::JoinHandle;
 use tokio_seqpacket::{UnixSeqpacket, UnixSeqpacketListener};
 use uuid::Uuid;
 
-use treadmill_rs::api::runner_puppet::{PuppetMsg, PuppetReq, RunnerMsg, RunnerResp};
-use treadmill_rs::control_socket::Runner;
+use treadmill_rs::api::runner_puppet::{PuppetMsg, PuppetReq, RunnerEvent, RunnerMsg, RunnerResp};
+use treadmill_rs::control_socket::{ControlSocket, Runner};
 
 #[derive(Debug, Clone)]
 enum ControlSocketTaskCommand {
+    SendEvent(RunnerEvent),
     Shutdown,
 }
 
@@ -22,7 +23,9 @@ struct ControlSocketState {
 pub struct UnixSeqpacketControlSocket<R: Runner> {
     _job_id: Uuid,
     task_handle: JoinHandle<Result<()>>,
-    task_cmd_chan: tokio::sync::mpsc::Sender<ControlSocketTaskCommand>,
+    // Unbounded, such that sending an event never waits for the task, which
+    // may itself be waiting for the runner to serve a request:
+    task_cmd_chan: tokio::sync::mpsc::UnboundedSender<ControlSocketTaskCommand>,
     state: Arc<RwLock<ControlSocketState>>,
     _runner: Arc<R>,
 }
@@ -39,7 +42,32 @@ impl<R: Runner> UnixSeqpacketControlSocket<R> {
             PuppetReq::Ping => RunnerResp::PingResp,
 
             PuppetReq::SSHKeys => RunnerResp::SSHKeysResp {
-                ssh_keys: runner.ssh_keys(job_id).await.unwrap_or_else(|| vec![]),
+                ssh_keys: runner
+                    .ssh_keys(job_id)
+                    .await
+                    .unwrap_or_else(std::vec::Vec::new),
+            },
+
+            PuppetReq::SSHHostKeys => RunnerResp::SSHHostKeysResp {
+                host_keys: runner
+                    .ssh_host_keys(job_id)
+                    .await
+                    .unwrap_or_else(std::vec::Vec::new),
+            },
+
+            PuppetReq::SSHUserCA => {
+                if let Some(ca) = runner.ssh_user_ca(job_id).await {
+                    RunnerResp::SSHUserCA(ca)
+                } else {
+                    RunnerResp::JobNotFound
+                }
+            }
+
+            PuppetReq::UserAccounts => RunnerResp::UserAccountsResp {
+                user_accounts: runner
+                    .user_accounts(job_id)
+                    .await
+                    .unwrap_or_else(std::vec::Vec::new),
             },
 
             PuppetReq::NetworkConfig => {
@@ -65,29 +93,35 @@ impl<R: Runner> UnixSeqpacketControlSocket<R> {
 
         let state = Arc::new(RwLock::new(ControlSocketState { client: None }));
 
-        let (task_cmd_chan_tx, mut task_cmd_chan_rx) = tokio::sync::mpsc::channel(1);
+        let (task_cmd_chan_tx, mut task_cmd_chan_rx) = tokio::sync::mpsc::unbounded_channel();
 
         let task_state = state.clone();
         let task_runner = runner.clone();
 
         let task_handle = tokio::spawn(async move {
-            const RECV_RSV: usize = 1 * 1024 * 1024;
+            const RECV_RSV: usize = 1024 * 1024;
 
             let state = task_state;
             let runner = task_runner;
 
             let mut shutdown_requested = false;
             let mut recv_buf = vec![0; RECV_RSV];
+            let mut runner_event_cnt: u64 = 0;
 
             while !shutdown_requested {
                 // Accept new connections. We only handle one
                 // connection at any point in time:
                 #[rustfmt::skip]
                 let socket_res = tokio::select! {
-                    accept_res = server_socket.accept() => Ok(
-                        accept_res
-                            .context("Accepting new control socket connection.").unwrap()
-                    ),
+                    accept_res = server_socket.accept() => match accept_res {
+                        Ok(socket) => Ok(socket),
+                        Err(e) => {
+                            // Accepting may fail transiently (e.g. when
+                            // running out of file descriptors):
+                            warn!("Error accepting new control socket connection: {:?}", e);
+                            continue;
+                        },
+                    },
 
                     cmd_res = task_cmd_chan_rx.recv() => match cmd_res {
                         Some(cmd) => Err(cmd),
@@ -99,6 +133,10 @@ impl<R: Runner> UnixSeqpacketControlSocket<R> {
 
                 let socket = match socket_res {
                     Ok(socket) => socket,
+                    Err(ControlSocketTaskCommand::SendEvent(event)) => {
+                        debug!("No puppet connected, dropping event {:?}", event);
+                        continue;
+                    }
                     Err(ControlSocketTaskCommand::Shutdown) => {
                         shutdown_requested = true;
                         continue;
@@ -138,7 +176,6 @@ impl<R: Runner> UnixSeqpacketControlSocket<R> {
                             }
 
                             cmd_res = task_cmd_chan_rx.recv() => {
-                                error!("Received task command inner!");
                                 match cmd_res {
                                     Some(cmd) => Err(cmd),
                                     None => {
@@ -215,6 +252,27 @@ impl<R: Runner> UnixSeqpacketControlSocket<R> {
                                 }
                             }
                         }
+                        Err(ControlSocketTaskCommand::SendEvent(event)) => {
+                            let sock_state = state.read().await;
+                            let socket = sock_state
+                                .client
+                                .as_ref()
+                                .expect("Invariant violated: client socket removed while reading!");
+                            let msg = RunnerMsg::Event {
+                                runner_event_id: runner_event_cnt,
+                                event,
+                            };
+                            runner_event_cnt += 1;
+                            if let Err(e) = socket
+                                .send(
+                                    &serde_json::to_vec(&msg)
+                                        .expect("Failed to encode control socket event as JSON"),
+                                )
+                                .await
+                            {
+                                warn!("Error while sending event to puppet, ignoring: {:?}", e);
+                            }
+                        }
                         Err(ControlSocketTaskCommand::Shutdown) => {
                             shutdown_requested = true;
                             break;
@@ -240,13 +298,14 @@ impl<R: Runner> UnixSeqpacketControlSocket<R> {
         // First, request shutdown of the task:
         self.task_cmd_chan
             .send(ControlSocketTaskCommand::Shutdown)
-            .await
             .with_context(|| {
-                format!("Requesting shutdown of the control socket request handler")
+                "Requesting shutdown of the control socket request handler".to_string()
             })?;
 
         // Then, try to join it:
-        self.task_handle.await.unwrap().unwrap();
+        self.task_handle
+            .await
+            .context("Joining the control socket request handler")??;
 
         // Finally, so some cleanup.
         let mut state = self.state.write().await;
@@ -266,3 +325,18 @@ impl<R: Runner> UnixSeqpacketControlSocket<R> {
         Ok(())
     }
 }
+
+#[async_trait::async_trait]
+impl<R: Runner> ControlSocket for UnixSeqpacketControlSocket<R> {
+    type Error = anyhow::Error;
+
+    fn send_event(&self, event: RunnerEvent) -> Result<()> {
+        self.task_cmd_chan
+            .send(ControlSocketTaskCommand::SendEvent(event))
+            .context("Requesting the control socket request handler to send an event")
+    }
+
+    async fn shutdown(self) -> Result<()> {
+        UnixSeqpacketControlSocket::shutdown(self).await
+    }
+}